@@ -0,0 +1,252 @@
+//! The client's side: sealing requests and opening the responses to them.
+
+use std::io::Read;
+
+use chacha20poly1305::aead::OsRng;
+use rust_hpke::kdf::HkdfSha256;
+use rust_hpke::kem::X25519HkdfSha256;
+use rust_hpke::{Deserializable, OpModeS, Serializable};
+
+use crate::*;
+
+const N_PK: usize = 32;
+
+/// Seal `bhttp` in a chunked request to the first suite of `config`, the gateway's preference.
+pub fn encapsulate(
+    config: &ohttp::KeyConfig,
+    bhttp: &[u8],
+) -> Result<(Vec<u8>, ClientResponse), Error> {
+    let encoded = config.encode().map_err(|_| Error::Unsupported)?;
+    let suite = parse_key_config(&encoded).ok_or(Error::Unsupported)?;
+    let header = [
+        &[suite.key_id][..],
+        &KEM_X25519_HKDF_SHA256.to_be_bytes(),
+        &KDF_HKDF_SHA256.to_be_bytes(),
+        &(suite.aead as u16).to_be_bytes(),
+    ]
+    .concat();
+    match suite.aead {
+        AeadId::Aes128Gcm =>
+            seal_request::<rust_hpke::aead::AesGcm128>(header, suite.public_key, bhttp, suite.aead),
+        AeadId::ChaCha20Poly1305 => seal_request::<rust_hpke::aead::ChaCha20Poly1305>(
+            header,
+            suite.public_key,
+            bhttp,
+            suite.aead,
+        ),
+    }
+}
+
+struct Suite<'a> {
+    key_id: u8,
+    public_key: &'a [u8],
+    aead: AeadId,
+}
+
+/// Read the key id, public key and first supported suite out of an encoded key configuration
+fn parse_key_config(encoded: &[u8]) -> Option<Suite<'_>> {
+    let kem = u16::from_be_bytes([*encoded.get(1)?, *encoded.get(2)?]);
+    if kem != KEM_X25519_HKDF_SHA256 {
+        return None;
+    }
+    let public_key = encoded.get(3..3 + N_PK)?;
+    let suites = encoded.get(3 + N_PK + 2..)?;
+    let aead = suites.chunks_exact(4).find_map(|suite| {
+        let kdf = u16::from_be_bytes([suite[0], suite[1]]);
+        let aead = AeadId::from_u16(u16::from_be_bytes([suite[2], suite[3]]))?;
+        (kdf == KDF_HKDF_SHA256).then_some(aead)
+    })?;
+    Some(Suite { key_id: encoded[0], public_key, aead })
+}
+
+/// Seal `bhttp` after `header` and the encapsulated key, keeping the secret the response is
+/// keyed by
+fn seal_request<A: rust_hpke::aead::Aead>(
+    header: Vec<u8>,
+    public_key: &[u8],
+    bhttp: &[u8],
+    aead: AeadId,
+) -> Result<(Vec<u8>, ClientResponse), Error> {
+    let public_key = <X25519HkdfSha256 as rust_hpke::Kem>::PublicKey::from_bytes(public_key)?;
+    let info = [REQUEST_INFO, &[0], &header].concat();
+    let (enc, mut context) = rust_hpke::setup_sender::<A, HkdfSha256, X25519HkdfSha256, _>(
+        &OpModeS::Base,
+        &public_key,
+        &info,
+        &mut OsRng,
+    )?;
+    let enc = enc.to_bytes().to_vec();
+    let mut request = [header, enc.clone()].concat();
+    request.reserve(bhttp.len() + bhttp.len() / CHUNK_LEN * 24 + 24);
+    let (body, last) = split_final(bhttp);
+    for chunk in body.chunks(CHUNK_LEN) {
+        let sealed = context.seal(chunk, b"")?;
+        write_varint(sealed.len() as u64, &mut request);
+        request.extend(sealed);
+    }
+    write_varint(0, &mut request);
+    request.extend(context.seal(last, FINAL_AAD)?);
+    let mut secret = vec![0; aead.n_secret()];
+    context.export(RESPONSE_LABEL, &mut secret)?;
+    Ok((request, ClientResponse { aead, enc, secret }))
+}
+
+/// What a client needs to open the chunked response to its request
+pub struct ClientResponse {
+    aead: AeadId,
+    enc: Vec<u8>,
+    secret: Vec<u8>,
+}
+
+impl ClientResponse {
+    /// Read and open a chunked response from `response` chunk by chunk.
+    ///
+    /// Reading stops at the first chunk that fails authentication, and once the opened
+    /// plaintext would exceed `max_len`, so no more than `max_len` bytes of plaintext and a
+    /// single sealed chunk are ever held.
+    pub fn decapsulate(self, response: &mut impl Read, max_len: usize) -> Result<Vec<u8>, Error> {
+        let mut response_nonce = vec![0; self.aead.n_secret()];
+        response.read_exact(&mut response_nonce).map_err(truncated)?;
+        let mut keys = ResponseKeys::new(self.aead, &self.enc, &self.secret, &response_nonce);
+        let mut plaintext = Vec::new();
+        loop {
+            let len = read_varint(response)?.ok_or(Error::Truncated)?;
+            let is_final = len == 0;
+            let sealed = if is_final {
+                let mut sealed = Vec::new();
+                response.take(MAX_SEALED_CHUNK_LEN as u64 + 1).read_to_end(&mut sealed)?;
+                sealed
+            } else {
+                if len > MAX_SEALED_CHUNK_LEN as u64 {
+                    return Err(Error::ChunkTooLong);
+                }
+                let mut sealed = vec![0; len as usize];
+                response.read_exact(&mut sealed).map_err(truncated)?;
+                sealed
+            };
+            if sealed.len() > MAX_SEALED_CHUNK_LEN {
+                return Err(Error::ChunkTooLong);
+            }
+            let chunk = keys.open(&sealed, is_final)?;
+            if plaintext.len() + chunk.len() > max_len {
+                return Err(Error::TooLarge(max_len));
+            }
+            plaintext.extend(chunk);
+            if is_final {
+                return Ok(plaintext);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::io;
+
+    use ohttp::hpke::{Aead, Kdf, Kem};
+
+    use super::*;
+
+    const IKM: [u8; 32] = [7; 32];
+
+    fn key_config(aead: Aead) -> ohttp::KeyConfig {
+        let suites = vec![ohttp::SymmetricSuite::new(Kdf::HkdfSha256, aead)];
+        ohttp::KeyConfig::derive(1, Kem::X25519Sha256, suites, &IKM).unwrap()
+    }
+
+    /// Open `request` as the gateway holding `IKM` does
+    fn open_request(request: &[u8]) -> (Vec<u8>, ServerResponse) {
+        let mut decoder =
+            RequestDecoder::new(BTreeMap::from([(1, PrivateKey::derive(&IKM))]), usize::MAX);
+        decoder.push(request).unwrap();
+        decoder.finish().unwrap()
+    }
+
+    #[test]
+    fn requests_are_sealed_chunk_by_chunk() {
+        let bhttp: Vec<u8> = (0..2 * CHUNK_LEN + 100).map(|i| i as u8).collect();
+        let (request, _) = encapsulate(&key_config(Aead::ChaCha20Poly1305), &bhttp).unwrap();
+        assert_eq!(request[..7], [1, 0x00, 0x20, 0x00, 0x01, 0x00, 0x03]);
+        assert_eq!(open_request(&request).0, bhttp);
+
+        let (request, _) = encapsulate(&key_config(Aead::ChaCha20Poly1305), b"").unwrap();
+        assert_eq!(open_request(&request).0, b"");
+    }
+
+    #[test]
+    fn responses_are_opened_chunk_by_chunk() {
+        let plaintext: Vec<u8> = (0..3 * CHUNK_LEN).map(|i| i as u8).collect();
+        let respond = |aead| {
+            let (request, ctx) = encapsulate(&key_config(aead), b"req").unwrap();
+            (ctx, open_request(&request).1.encapsulate(&plaintext))
+        };
+        for aead in [Aead::ChaCha20Poly1305, Aead::Aes128Gcm] {
+            let (ctx, response) = respond(aead);
+            assert_eq!(
+                ctx.decapsulate(&mut response.as_slice(), plaintext.len()).unwrap(),
+                plaintext
+            );
+        }
+
+        let (ctx, mut tampered) = respond(Aead::ChaCha20Poly1305);
+        tampered[40] ^= 1;
+        assert!(matches!(ctx.decapsulate(&mut tampered.as_slice(), usize::MAX), Err(Error::Aead)));
+        // Dropping the final chunk leaves a response that ends early
+        let (ctx, response) = respond(Aead::ChaCha20Poly1305);
+        let mut truncated = &response[..response.len() - 1 - (CHUNK_LEN + 16)];
+        assert!(matches!(ctx.decapsulate(&mut truncated, usize::MAX), Err(Error::Truncated)));
+        let (ctx, response) = respond(Aead::ChaCha20Poly1305);
+        assert!(matches!(
+            ctx.decapsulate(&mut response.as_slice(), plaintext.len() - 1),
+            Err(Error::TooLarge(_))
+        ));
+    }
+
+    /// An endless response of valid chunks, counting what's read of it
+    struct EndlessResponse {
+        keys: ResponseKeys,
+        pending: Vec<u8>,
+        read: usize,
+    }
+
+    impl Read for EndlessResponse {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pending.is_empty() {
+                let sealed = self.keys.seal(&[0; CHUNK_LEN], false);
+                write_varint(sealed.len() as u64, &mut self.pending);
+                self.pending.extend(sealed);
+            }
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            self.read += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn endless_responses_are_refused_with_bounded_memory() {
+        const MAX_LEN: usize = 10 * CHUNK_LEN;
+
+        let (_, ctx) = encapsulate(&key_config(Aead::ChaCha20Poly1305), b"req").unwrap();
+        let response_nonce = vec![9; ctx.aead.n_secret()];
+        let keys = ResponseKeys::new(ctx.aead, &ctx.enc, &ctx.secret, &response_nonce);
+        let mut response = EndlessResponse { keys, pending: response_nonce, read: 0 };
+        assert!(matches!(ctx.decapsulate(&mut response, MAX_LEN), Err(Error::TooLarge(MAX_LEN))));
+        // Everything read was opened and counted, up to the chunk that crossed the limit
+        assert!(response.read <= 32 + 11 * (4 + CHUNK_LEN + 16));
+
+        // A chunk that claims to be longer than any gateway sends is refused unread
+        let (_, ctx) = encapsulate(&key_config(Aead::ChaCha20Poly1305), b"req").unwrap();
+        let mut response = vec![0; 32];
+        write_varint(1 << 40, &mut response);
+        let mut endless = response.as_slice().chain(io::repeat(0));
+        assert!(matches!(ctx.decapsulate(&mut endless, MAX_LEN), Err(Error::ChunkTooLong)));
+
+        // So is a final chunk that never ends
+        let (_, ctx) = encapsulate(&key_config(Aead::ChaCha20Poly1305), b"req").unwrap();
+        let mut endless = [0; 33].chain(io::repeat(0));
+        assert!(matches!(ctx.decapsulate(&mut endless, MAX_LEN), Err(Error::ChunkTooLong)));
+    }
+}
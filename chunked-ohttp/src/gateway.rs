@@ -0,0 +1,292 @@
+//! The gateway's side: opening requests and sealing the responses to them.
+//!
+//! A [`RequestDecoder`] opens request chunks as the body's frames arrive, so nothing but the
+//! plaintext so far and one sealed chunk is held, and a request that outgrows its limit is
+//! refused before the rest of it is read.
+
+use std::collections::BTreeMap;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use ohttp::KeyId;
+use rust_hpke::aead::{AeadCtxR, AesGcm128};
+use rust_hpke::kdf::HkdfSha256;
+use rust_hpke::kem::X25519HkdfSha256;
+use rust_hpke::{Deserializable, Kem as _, OpModeR};
+
+use crate::*;
+
+/// Key id, KEM, KDF and AEAD ids
+const HEADER_LEN: usize = 7;
+const N_ENC: usize = 32;
+
+/// A gateway's X25519 private key
+#[derive(Clone)]
+pub struct PrivateKey(<X25519HkdfSha256 as rust_hpke::Kem>::PrivateKey);
+
+impl PrivateKey {
+    /// Derive the key [`ohttp::KeyConfig::derive`] derives from the same input keying material,
+    /// so chunked requests are opened with the key ordinary ones are.
+    pub fn derive(ikm: &[u8]) -> Self { Self(X25519HkdfSha256::derive_keypair(ikm).0) }
+}
+
+/// The request's HPKE context, whose AEAD the client chose from the advertised suites
+enum RequestContext {
+    Aes128Gcm(Box<AeadCtxR<AesGcm128, HkdfSha256, X25519HkdfSha256>>),
+    ChaCha20Poly1305(
+        Box<AeadCtxR<rust_hpke::aead::ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256>>,
+    ),
+}
+
+impl RequestContext {
+    fn new(aead: AeadId, sk: &PrivateKey, enc: &[u8], info: &[u8]) -> Result<Self, Error> {
+        let enc = <X25519HkdfSha256 as rust_hpke::Kem>::EncappedKey::from_bytes(enc)?;
+        Ok(match aead {
+            AeadId::Aes128Gcm => Self::Aes128Gcm(Box::new(rust_hpke::setup_receiver(
+                &OpModeR::Base,
+                &sk.0,
+                &enc,
+                info,
+            )?)),
+            AeadId::ChaCha20Poly1305 => Self::ChaCha20Poly1305(Box::new(
+                rust_hpke::setup_receiver(&OpModeR::Base, &sk.0, &enc, info)?,
+            )),
+        })
+    }
+
+    fn open(&mut self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(match self {
+            Self::Aes128Gcm(context) => context.open(sealed, aad)?,
+            Self::ChaCha20Poly1305(context) => context.open(sealed, aad)?,
+        })
+    }
+
+    fn export(&self, secret: &mut [u8]) -> Result<(), Error> {
+        match self {
+            Self::Aes128Gcm(context) => context.export(RESPONSE_LABEL, secret)?,
+            Self::ChaCha20Poly1305(context) => context.export(RESPONSE_LABEL, secret)?,
+        }
+        Ok(())
+    }
+}
+
+enum State {
+    Header,
+    Chunks {
+        aead: AeadId,
+        enc: Vec<u8>,
+        context: RequestContext,
+    },
+    /// The final chunk runs to the end of the request
+    Final {
+        aead: AeadId,
+        enc: Vec<u8>,
+        context: RequestContext,
+    },
+}
+
+/// Opens a chunked request pushed to it piece by piece.
+pub struct RequestDecoder {
+    keys: BTreeMap<KeyId, PrivateKey>,
+    state: State,
+    buf: Vec<u8>,
+    plaintext: Vec<u8>,
+    max_len: usize,
+}
+
+impl RequestDecoder {
+    /// Decode a request encapsulated to one of `keys`, refusing it once its plaintext grows
+    /// past `max_len` bytes.
+    pub fn new(keys: BTreeMap<KeyId, PrivateKey>, max_len: usize) -> Self {
+        Self { keys, state: State::Header, buf: vec![], plaintext: vec![], max_len }
+    }
+
+    /// Open every chunk `data` completes
+    pub fn push(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.buf.extend_from_slice(data);
+        loop {
+            match &mut self.state {
+                State::Header => {
+                    if self.buf.len() < HEADER_LEN + N_ENC {
+                        return Ok(());
+                    }
+                    let (header, enc) = self.buf[..HEADER_LEN + N_ENC].split_at(HEADER_LEN);
+                    let sk = self.keys.get(&header[0]).ok_or(Error::KeyId)?;
+                    let kem = u16::from_be_bytes([header[1], header[2]]);
+                    let kdf = u16::from_be_bytes([header[3], header[4]]);
+                    let aead = AeadId::from_u16(u16::from_be_bytes([header[5], header[6]]))
+                        .filter(|_| kem == KEM_X25519_HKDF_SHA256 && kdf == KDF_HKDF_SHA256)
+                        .ok_or(Error::Unsupported)?;
+                    let info = [REQUEST_INFO, &[0], header].concat();
+                    let context = RequestContext::new(aead, sk, enc, &info)?;
+                    self.state = State::Chunks { aead, enc: enc.to_vec(), context };
+                    self.buf.drain(..HEADER_LEN + N_ENC);
+                }
+                State::Chunks { context, .. } => {
+                    let (len, varint_len) = match parse_varint(&self.buf) {
+                        Some(varint) => varint,
+                        None => return Ok(()),
+                    };
+                    if len == 0 {
+                        self.buf.drain(..varint_len);
+                        if let State::Chunks { aead, enc, context } =
+                            std::mem::replace(&mut self.state, State::Header)
+                        {
+                            self.state = State::Final { aead, enc, context };
+                        }
+                        continue;
+                    }
+                    if len > MAX_SEALED_CHUNK_LEN as u64 {
+                        return Err(Error::ChunkTooLong);
+                    }
+                    let end = varint_len + len as usize;
+                    if self.buf.len() < end {
+                        return Ok(());
+                    }
+                    let chunk = context.open(&self.buf[varint_len..end], b"")?;
+                    if self.plaintext.len() + chunk.len() > self.max_len {
+                        return Err(Error::TooLarge(self.max_len));
+                    }
+                    self.plaintext.extend(chunk);
+                    self.buf.drain(..end);
+                }
+                State::Final { .. } => {
+                    if self.buf.len() > MAX_SEALED_CHUNK_LEN {
+                        return Err(Error::ChunkTooLong);
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Open the final chunk once the request has ended, returning its bhttp message and how to
+    /// seal the response
+    pub fn finish(mut self) -> Result<(Vec<u8>, ServerResponse), Error> {
+        let (aead, enc, mut context) = match self.state {
+            State::Final { aead, enc, context } => (aead, enc, context),
+            _ => return Err(Error::Truncated),
+        };
+        let chunk = context.open(&self.buf, FINAL_AAD)?;
+        if self.plaintext.len() + chunk.len() > self.max_len {
+            return Err(Error::TooLarge(self.max_len));
+        }
+        self.plaintext.extend(chunk);
+        let mut secret = vec![0; aead.n_secret()];
+        context.export(&mut secret)?;
+        Ok((self.plaintext, ServerResponse { aead, enc, secret }))
+    }
+}
+
+/// Seals the chunked response to a chunked request
+pub struct ServerResponse {
+    aead: AeadId,
+    enc: Vec<u8>,
+    secret: Vec<u8>,
+}
+
+impl ServerResponse {
+    pub fn encapsulate(self, bhttp: &[u8]) -> Vec<u8> {
+        let mut response_nonce = vec![0; self.aead.n_secret()];
+        OsRng.fill_bytes(&mut response_nonce);
+        let mut keys = ResponseKeys::new(self.aead, &self.enc, &self.secret, &response_nonce);
+        let mut response = response_nonce;
+        response.reserve(bhttp.len() + bhttp.len() / CHUNK_LEN * 24 + 24);
+        let (body, last) = split_final(bhttp);
+        for chunk in body.chunks(CHUNK_LEN) {
+            let sealed = keys.seal(chunk, false);
+            write_varint(sealed.len() as u64, &mut response);
+            response.extend(sealed);
+        }
+        write_varint(0, &mut response);
+        response.extend(keys.seal(last, true));
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ohttp::hpke::{Aead, Kdf, Kem};
+
+    use super::*;
+
+    const KEY_ID: KeyId = 1;
+    const IKM: [u8; 32] = [7; 32];
+
+    fn decoder(max_len: usize) -> RequestDecoder {
+        RequestDecoder::new(BTreeMap::from([(KEY_ID, PrivateKey::derive(&IKM))]), max_len)
+    }
+
+    /// Seal `bhttp` as a client would
+    fn seal_request(bhttp: &[u8]) -> (Vec<u8>, ClientResponse) {
+        let suites = vec![ohttp::SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305)];
+        let config = ohttp::KeyConfig::derive(KEY_ID, Kem::X25519Sha256, suites, &IKM).unwrap();
+        encapsulate(&config, bhttp).unwrap()
+    }
+
+    #[test]
+    fn requests_are_opened_as_they_stream_in() {
+        let bhttp: Vec<u8> = (0..CHUNK_LEN + 100).map(|i| i as u8).collect();
+        let (request, client) = seal_request(&bhttp);
+        let mut decoder = decoder(bhttp.len());
+        for byte in &request {
+            decoder.push(std::slice::from_ref(byte)).unwrap();
+        }
+        let (opened, response) = decoder.finish().unwrap();
+        assert_eq!(opened, bhttp);
+
+        let body = vec![42; 2 * CHUNK_LEN + 1];
+        let response = response.encapsulate(&body);
+        assert_eq!(client.decapsulate(&mut response.as_slice(), body.len()).unwrap(), body);
+    }
+
+    #[test]
+    fn bad_requests_are_refused() {
+        let final_len = 3;
+        let (request, _) = seal_request(&[1; CHUNK_LEN + 3]);
+
+        let mut unknown_key = request.clone();
+        unknown_key[0] = KEY_ID + 1;
+        assert!(matches!(decoder(usize::MAX).push(&unknown_key), Err(Error::KeyId)));
+
+        let mut tampered = request.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let mut d = decoder(usize::MAX);
+        d.push(&tampered).unwrap();
+        assert!(matches!(d.finish(), Err(Error::Hpke(_))));
+
+        // cut before the final chunk's marker
+        let mut d = decoder(usize::MAX);
+        d.push(&request[..request.len() - (final_len + 16) - 1]).unwrap();
+        assert!(matches!(d.finish(), Err(Error::Truncated)));
+
+        // refused as soon as a chunk outgrows the limit, and again once the final one does
+        let mut d = decoder(CHUNK_LEN - 1);
+        assert!(matches!(d.push(&request), Err(Error::TooLarge(_))));
+        let mut d = decoder(CHUNK_LEN);
+        d.push(&request).unwrap();
+        assert!(matches!(d.finish(), Err(Error::TooLarge(_))));
+    }
+
+    #[test]
+    fn endless_requests_are_refused_with_bounded_memory() {
+        let (request, _) = seal_request(b"");
+        let prefix = &request[..HEADER_LEN + N_ENC];
+
+        // a chunk claiming a length that would never arrive
+        let mut d = decoder(usize::MAX);
+        d.push(prefix).unwrap();
+        let mut claim = vec![];
+        write_varint(u32::MAX as u64, &mut claim);
+        assert!(matches!(d.push(&claim), Err(Error::ChunkTooLong)));
+
+        // a final chunk that never ends
+        let mut d = decoder(usize::MAX);
+        d.push(prefix).unwrap();
+        d.push(&[0]).unwrap();
+        let frame = [0u8; 1024];
+        let refused = (0..1024).find_map(|_| d.push(&frame).err());
+        assert!(matches!(refused, Some(Error::ChunkTooLong)));
+        assert!(d.buf.len() <= MAX_SEALED_CHUNK_LEN + frame.len());
+    }
+}
@@ -0,0 +1,246 @@
+//! Chunked Oblivious HTTP messages as in [draft-ietf-ohai-chunked-ohttp].
+//!
+//! A chunked request is sealed to the gateway's HPKE key a chunk at a time, and the gateway
+//! answers with a chunked response keyed by a secret exported from the request's HPKE context.
+//! Each chunk is authenticated on its own, so it's opened as soon as it's read and neither side
+//! has to buffer a whole sealed message. Only the final chunk is sealed with the `final` AAD, so
+//! a truncated message is detected too.
+//!
+//! Clients [`encapsulate`] requests and open responses with a [`ClientResponse`]. Gateways open
+//! requests with a [`RequestDecoder`] and seal responses with a [`ServerResponse`].
+//!
+//! [draft-ietf-ohai-chunked-ohttp]: https://datatracker.ietf.org/doc/draft-ietf-ohai-chunked-ohttp/
+
+use std::io::Read;
+use std::{error, fmt, io};
+
+use chacha20poly1305::aead::{Aead as _, KeyInit, Payload};
+use chacha20poly1305::Nonce;
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+mod client;
+mod gateway;
+
+pub use client::{encapsulate, ClientResponse};
+pub use gateway::{PrivateKey, RequestDecoder, ServerResponse};
+
+pub const CHUNKED_REQ_CONTENT_TYPE: &str = "message/ohttp-chunked-req";
+pub const CHUNKED_RES_CONTENT_TYPE: &str = "message/ohttp-chunked-res";
+
+const REQUEST_INFO: &[u8] = b"message/bhttp chunked request";
+const RESPONSE_LABEL: &[u8] = b"message/bhttp chunked response";
+const FINAL_AAD: &[u8] = b"final";
+
+const KEM_X25519_HKDF_SHA256: u16 = 0x0020;
+const KDF_HKDF_SHA256: u16 = 0x0001;
+const N_N: usize = 12;
+
+/// Plaintext bytes sealed per chunk
+pub const CHUNK_LEN: usize = 16 * 1024;
+
+/// Sealed chunks longer than this are refused before they're read, which bounds what a peer
+/// can make either side buffer beyond its plaintext
+pub const MAX_SEALED_CHUNK_LEN: usize = 64 * 1024;
+
+/// The AEADs chunked messages may be sealed with, by their HPKE ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AeadId {
+    Aes128Gcm = 0x0001,
+    ChaCha20Poly1305 = 0x0003,
+}
+
+impl AeadId {
+    fn from_u16(id: u16) -> Option<Self> {
+        match id {
+            0x0001 => Some(AeadId::Aes128Gcm),
+            0x0003 => Some(AeadId::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    fn n_k(self) -> usize {
+        match self {
+            AeadId::Aes128Gcm => 16,
+            AeadId::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    /// The length of the exported secret and of the response nonce, `max(Nn, Nk)`
+    fn n_secret(self) -> usize { self.n_k().max(N_N) }
+}
+
+/// Split off the last chunk, which may be empty, to be sealed as the final one
+fn split_final(plaintext: &[u8]) -> (&[u8], &[u8]) {
+    let last_len = match plaintext.len() % CHUNK_LEN {
+        0 => plaintext.len().min(CHUNK_LEN),
+        rest => rest,
+    };
+    plaintext.split_at(plaintext.len() - last_len)
+}
+
+/// The AEAD key and nonce response chunks are sealed with, derived by both sides from the
+/// request's encapsulated key, the exported secret and the gateway's response nonce
+struct ResponseKeys {
+    cipher: ResponseCipher,
+    nonce: [u8; N_N],
+    counter: u64,
+}
+
+enum ResponseCipher {
+    Aes128Gcm(Box<aes_gcm::Aes128Gcm>),
+    ChaCha20Poly1305(Box<chacha20poly1305::ChaCha20Poly1305>),
+}
+
+impl ResponseKeys {
+    fn new(aead: AeadId, enc: &[u8], secret: &[u8], response_nonce: &[u8]) -> Self {
+        let salt = [enc, response_nonce].concat();
+        let prk = Hkdf::<Sha256>::new(Some(&salt), secret);
+        let mut key = [0u8; 32];
+        let mut nonce = [0u8; N_N];
+        prk.expand(b"key", &mut key[..aead.n_k()]).expect("shorter than one block");
+        prk.expand(b"nonce", &mut nonce).expect("shorter than one block");
+        let cipher = match aead {
+            AeadId::Aes128Gcm => ResponseCipher::Aes128Gcm(Box::new(
+                aes_gcm::Aes128Gcm::new_from_slice(&key[..aead.n_k()]).expect("key length"),
+            )),
+            AeadId::ChaCha20Poly1305 => ResponseCipher::ChaCha20Poly1305(Box::new(
+                chacha20poly1305::ChaCha20Poly1305::new_from_slice(&key[..aead.n_k()])
+                    .expect("key length"),
+            )),
+        };
+        Self { cipher, nonce, counter: 0 }
+    }
+
+    fn seal(&mut self, chunk: &[u8], is_final: bool) -> Vec<u8> {
+        let aad = if is_final { FINAL_AAD } else { b"" };
+        let nonce = self.next_nonce();
+        let payload = Payload { msg: chunk, aad };
+        match &self.cipher {
+            ResponseCipher::Aes128Gcm(cipher) => cipher.encrypt(Nonce::from_slice(&nonce), payload),
+            ResponseCipher::ChaCha20Poly1305(cipher) =>
+                cipher.encrypt(Nonce::from_slice(&nonce), payload),
+        }
+        .expect("in memory")
+    }
+
+    fn open(&mut self, sealed: &[u8], is_final: bool) -> Result<Vec<u8>, Error> {
+        let aad = if is_final { FINAL_AAD } else { b"" };
+        let nonce = self.next_nonce();
+        let payload = Payload { msg: sealed, aad };
+        match &self.cipher {
+            ResponseCipher::Aes128Gcm(cipher) => cipher.decrypt(Nonce::from_slice(&nonce), payload),
+            ResponseCipher::ChaCha20Poly1305(cipher) =>
+                cipher.decrypt(Nonce::from_slice(&nonce), payload),
+        }
+        .map_err(|_| Error::Aead)
+    }
+
+    /// The response nonce XOR the chunk counter, which is never reused under one key
+    fn next_nonce(&mut self) -> [u8; N_N] {
+        let mut nonce = self.nonce;
+        for (n, c) in nonce[N_N - 8..].iter_mut().zip(self.counter.to_be_bytes()) {
+            *n ^= c;
+        }
+        self.counter += 1;
+        nonce
+    }
+}
+
+/// Append a QUIC variable-length integer, as chunk lengths are encoded
+fn write_varint(n: u64, buf: &mut Vec<u8>) {
+    match n {
+        0..=0x3f => buf.push(n as u8),
+        0x40..=0x3fff => buf.extend((n as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend((n as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend((n | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// Parse a QUIC variable-length integer and its length, or `None` if `buf` doesn't hold it yet
+fn parse_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let len = 1 << (buf.first()? >> 6);
+    let bytes = buf.get(..len)?;
+    let mut value = [0u8; 8];
+    value[8 - len..].copy_from_slice(bytes);
+    value[8 - len] &= 0x3f;
+    Some((u64::from_be_bytes(value), len))
+}
+
+/// Read a QUIC variable-length integer, or `None` if `r` has already ended
+fn read_varint(r: &mut impl Read) -> Result<Option<u64>, Error> {
+    let mut first = [0u8; 1];
+    if r.read(&mut first)? == 0 {
+        return Ok(None);
+    }
+    let len = 1 << (first[0] >> 6);
+    let mut bytes = [0u8; 8];
+    bytes[8 - len] = first[0] & 0x3f;
+    r.read_exact(&mut bytes[8 - len + 1..]).map_err(truncated)?;
+    Ok(Some(u64::from_be_bytes(bytes)))
+}
+
+fn truncated(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::Truncated,
+        _ => Error::Io(e),
+    }
+}
+
+/// Error from sealing or opening a chunked message.
+#[derive(Debug)]
+pub enum Error {
+    /// The request is encapsulated to a key the gateway doesn't hold
+    KeyId,
+    /// The key configuration or request uses no suite chunked messages can be sealed with
+    Unsupported,
+    Hpke(rust_hpke::HpkeError),
+    Io(io::Error),
+    /// A response chunk failed authentication
+    Aead,
+    /// The message ended before its final chunk
+    Truncated,
+    /// A sealed chunk is longer than [`MAX_SEALED_CHUNK_LEN`]
+    ChunkTooLong,
+    /// The message's plaintext is longer than the given limit
+    TooLarge(usize),
+}
+
+impl From<rust_hpke::HpkeError> for Error {
+    fn from(value: rust_hpke::HpkeError) -> Self { Self::Hpke(value) }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self { Self::Io(value) }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+
+        match &self {
+            KeyId => write!(f, "the key ID was invalid"),
+            Unsupported => write!(f, "no OHTTP key suite supports chunked messages"),
+            Hpke(e) => write!(f, "a problem occurred with HPKE: {}", e),
+            Io(e) => e.fmt(f),
+            Aead => write!(f, "a chunked OHTTP response failed authentication"),
+            Truncated => write!(f, "the chunked OHTTP message ended before its final chunk"),
+            ChunkTooLong =>
+                write!(f, "a chunked OHTTP chunk is longer than {} bytes", MAX_SEALED_CHUNK_LEN),
+            TooLarge(max_len) =>
+                write!(f, "the chunked OHTTP message is longer than {} bytes", max_len),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use Error::*;
+
+        match &self {
+            Hpke(e) => Some(e),
+            Io(e) => Some(e),
+            KeyId | Unsupported | Aead | Truncated | ChunkTooLong | TooLarge(_) => None,
+        }
+    }
+}
@@ -0,0 +1,202 @@
+#!/usr/bin/env python3
+"""Generate the HPKE test vectors in payjoin/src/hpke/test_vectors.json.
+
+Implements RFC 9180 HPKE with HKDF-SHA256 and ChaCha20Poly1305 independently of the Rust
+code, using the `cryptography` package for the curves and the AEAD.
+
+The secp256k1 vectors use DHKEM(secp256k1, HKDF-SHA256) as specified by
+draft-wahby-cfrg-hpke-kem-secp256k1. The X25519 vectors reproduce RFC 9180 Appendix A.2 and
+check the KEM-independent key schedule against the published values.
+
+Usage: contrib/hpke_test_vectors.py > payjoin/src/hpke/test_vectors.json
+"""
+
+import hashlib
+import hmac
+import json
+import sys
+
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric import ec, x25519
+from cryptography.hazmat.primitives.ciphers.aead import ChaCha20Poly1305
+
+MODE_BASE = 0x00
+MODE_AUTH = 0x02
+KDF_HKDF_SHA256 = 0x0001
+AEAD_CHACHA20POLY1305 = 0x0003
+KEM_SECP256K1 = 0x0016
+KEM_X25519 = 0x0020
+SECP256K1_ORDER = 0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141
+
+INFO = bytes.fromhex("4f6465206f6e2061204772656369616e2055726e")  # "Ode on a Grecian Urn"
+PT = bytes.fromhex("4265617574792069732074727574682c20747275746820626561757479")
+SEQS = [0, 1, 2, 255, 256]
+
+
+def i2osp(n, length):
+    return n.to_bytes(length, "big")
+
+
+def extract(salt, ikm):
+    return hmac.new(salt, ikm, hashlib.sha256).digest()
+
+
+def expand(prk, info, length):
+    out, t, i = b"", b"", 1
+    while len(out) < length:
+        t = hmac.new(prk, t + info + bytes([i]), hashlib.sha256).digest()
+        out += t
+        i += 1
+    return out[:length]
+
+
+def labeled_extract(suite_id, salt, label, ikm):
+    return extract(salt, b"HPKE-v1" + suite_id + label + ikm)
+
+
+def labeled_expand(suite_id, prk, label, info, length):
+    return expand(prk, i2osp(length, 2) + b"HPKE-v1" + suite_id + label + info, length)
+
+
+class Secp256k1:
+    kem_id = KEM_SECP256K1
+
+    def derive_key_pair(self, ikm):
+        suite_id = b"KEM" + i2osp(self.kem_id, 2)
+        dkp_prk = labeled_extract(suite_id, b"", b"dkp_prk", ikm)
+        for counter in range(256):
+            candidate = labeled_expand(suite_id, dkp_prk, b"candidate", bytes([counter]), 32)
+            sk = int.from_bytes(candidate, "big")
+            if 0 < sk < SECP256K1_ORDER:
+                return ec.derive_private_key(sk, ec.SECP256K1())
+        raise ValueError("DeriveKeyPairError")
+
+    def serialize_private_key(self, sk):
+        return i2osp(sk.private_numbers().private_value, 32)
+
+    def serialize_public_key(self, pk):
+        return pk.public_bytes(
+            serialization.Encoding.X962, serialization.PublicFormat.UncompressedPoint
+        )
+
+    def dh(self, sk, pk):
+        return sk.exchange(ec.ECDH(), pk)
+
+
+class X25519:
+    kem_id = KEM_X25519
+
+    def derive_key_pair(self, ikm):
+        suite_id = b"KEM" + i2osp(self.kem_id, 2)
+        dkp_prk = labeled_extract(suite_id, b"", b"dkp_prk", ikm)
+        sk = labeled_expand(suite_id, dkp_prk, b"sk", b"", 32)
+        return x25519.X25519PrivateKey.from_private_bytes(sk)
+
+    def serialize_private_key(self, sk):
+        return sk.private_bytes(
+            serialization.Encoding.Raw,
+            serialization.PrivateFormat.Raw,
+            serialization.NoEncryption(),
+        )
+
+    def serialize_public_key(self, pk):
+        return pk.public_bytes(serialization.Encoding.Raw, serialization.PublicFormat.Raw)
+
+    def dh(self, sk, pk):
+        return sk.exchange(pk)
+
+
+def vector(kem, mode, ikm_e, ikm_r, ikm_s):
+    kem_suite_id = b"KEM" + i2osp(kem.kem_id, 2)
+    sk_e, sk_r = kem.derive_key_pair(ikm_e), kem.derive_key_pair(ikm_r)
+    pk_e, pk_r = sk_e.public_key(), sk_r.public_key()
+    enc, pk_rm = kem.serialize_public_key(pk_e), kem.serialize_public_key(pk_r)
+    dh, kem_context = kem.dh(sk_e, pk_r), enc + pk_rm
+    if mode == MODE_AUTH:
+        sk_s = kem.derive_key_pair(ikm_s)
+        pk_sm = kem.serialize_public_key(sk_s.public_key())
+        dh, kem_context = dh + kem.dh(sk_s, pk_r), kem_context + pk_sm
+    eae_prk = labeled_extract(kem_suite_id, b"", b"eae_prk", dh)
+    shared_secret = labeled_expand(kem_suite_id, eae_prk, b"shared_secret", kem_context, 32)
+
+    suite_id = (
+        b"HPKE" + i2osp(kem.kem_id, 2) + i2osp(KDF_HKDF_SHA256, 2) + i2osp(AEAD_CHACHA20POLY1305, 2)
+    )
+    psk_id_hash = labeled_extract(suite_id, b"", b"psk_id_hash", b"")
+    info_hash = labeled_extract(suite_id, b"", b"info_hash", INFO)
+    key_schedule_context = bytes([mode]) + psk_id_hash + info_hash
+    secret = labeled_extract(suite_id, shared_secret, b"secret", b"")
+    key = labeled_expand(suite_id, secret, b"key", key_schedule_context, 32)
+    base_nonce = labeled_expand(suite_id, secret, b"base_nonce", key_schedule_context, 12)
+    exporter_secret = labeled_expand(suite_id, secret, b"exp", key_schedule_context, 32)
+
+    encryptions = []
+    for seq in SEQS:
+        nonce = bytes(a ^ b for a, b in zip(base_nonce, i2osp(seq, 12)))
+        aad = b"Count-%d" % seq
+        ct = ChaCha20Poly1305(key).encrypt(nonce, PT, aad)
+        encryptions.append(
+            {"seq": seq, "aad": aad.hex(), "ct": ct.hex(), "nonce": nonce.hex(), "pt": PT.hex()}
+        )
+
+    v = {
+        "mode": mode,
+        "kem_id": kem.kem_id,
+        "kdf_id": KDF_HKDF_SHA256,
+        "aead_id": AEAD_CHACHA20POLY1305,
+        "info": INFO.hex(),
+        "ikmE": ikm_e.hex(),
+        "ikmR": ikm_r.hex(),
+        "skEm": kem.serialize_private_key(sk_e).hex(),
+        "skRm": kem.serialize_private_key(sk_r).hex(),
+        "pkEm": enc.hex(),
+        "pkRm": pk_rm.hex(),
+        "enc": enc.hex(),
+    }
+    if mode == MODE_AUTH:
+        v["ikmS"] = ikm_s.hex()
+        v["skSm"] = kem.serialize_private_key(sk_s).hex()
+        v["pkSm"] = pk_sm.hex()
+    v.update(
+        {
+            "shared_secret": shared_secret.hex(),
+            "key_schedule_context": key_schedule_context.hex(),
+            "secret": secret.hex(),
+            "key": key.hex(),
+            "base_nonce": base_nonce.hex(),
+            "exporter_secret": exporter_secret.hex(),
+            "encryptions": encryptions,
+        }
+    )
+    return v
+
+
+def main():
+    # RFC 9180 A.2.1 and A.2.3
+    x25519_base = (
+        "909a9b35d3dc4713a5e72a4da274b55d3d3821a37e5d099e74a647db583a904b",
+        "1ac01f181fdf9f352797655161c58b75c656a6cc2716dcb66372da835542e1df",
+        "",
+    )
+    x25519_auth = (
+        "938d3daa5a8904540bc24f48ae90eed3f4f7f11839560597b55e7c9598c996c0",
+        "64835d5ee64aa7aad57c6f2e4f758f7696617f8829e70bc9ac7a5ef95d1c756c",
+        "9d8f94537d5a3ddef71234c0baedfad4ca6861634d0b94c3007fed557ad17df6",
+    )
+    vectors = []
+    for mode, (ikm_e, ikm_r, ikm_s) in [(MODE_BASE, x25519_base), (MODE_AUTH, x25519_auth)]:
+        vectors.append(
+            vector(X25519(), mode, bytes.fromhex(ikm_e), bytes.fromhex(ikm_r), bytes.fromhex(ikm_s))
+        )
+    for mode in (MODE_BASE, MODE_AUTH):
+        ikm_e, ikm_r, ikm_s = (
+            hashlib.sha256(b"payjoin ikm%s mode %d" % (role, mode)).digest()
+            for role in (b"E", b"R", b"S")
+        )
+        vectors.append(vector(Secp256k1(), mode, ikm_e, ikm_r, ikm_s))
+    json.dump(vectors, sys.stdout, indent=2)
+    sys.stdout.write("\n")
+
+
+if __name__ == "__main__":
+    main()
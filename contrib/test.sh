@@ -4,6 +4,9 @@ set -e
 # Run tests for the Rust project
 echo "Running Rust tests..."
 cargo test --package payjoin --verbose --all-features --lib
+cargo test --package chunked-ohttp --verbose
+cargo test --package mailbox-auth --verbose
+cargo test --package payjoin-directory --verbose --all-features
 cargo test --package payjoin --verbose --features=send,receive --test integration
 cargo test --package payjoin --verbose --features=send,receive,danger-local-https,v2 --test integration
 cargo test --package payjoin-cli --verbose --features=danger-local-https,v2 --test e2e
@@ -0,0 +1,148 @@
+//! Signed requests to a Payjoin V2 mailbox.
+//!
+//! Only the receiver holding a mailbox's session key may write replies to it, drop requests
+//! from it, or delete it. It proves so with a body of the form `sig || timestamp || payload`,
+//! where `sig` is a compact ECDSA signature by the session key over
+//! `tag || pubkey || timestamp || bound || payload`:
+//!
+//! - `tag` names the kind of request, so a signature for one can't be used as another.
+//! - `pubkey` is the mailbox's, so it can't be used against a different mailbox.
+//! - `timestamp` is when the request was signed, as big-endian unix seconds, so a directory can
+//!   refuse a captured request once it's stale.
+//! - `bound` is whatever the request addresses that isn't in its body, such as the reply slot
+//!   it writes to.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{error, fmt};
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{ecdsa, Keypair, Message, PublicKey, Secp256k1};
+
+/// Domain separation tag for mailbox deletion requests.
+pub const DELETE_MAILBOX_TAG: &[u8] = b"payjoin-directory/delete";
+/// Domain separation tag for a receiver's payjoin proposal written to its mailbox.
+pub const POST_PAYJOIN_TAG: &[u8] = b"payjoin-directory/payjoin";
+/// Domain separation tag for a receiver's reply written to one sender's reply slot.
+pub const POST_REPLY_TAG: &[u8] = b"payjoin-directory/reply";
+/// Domain separation tag for a receiver dropping a request it won't reply to.
+pub const DROP_REQUEST_TAG: &[u8] = b"payjoin-directory/drop";
+
+const SIGNATURE_LEN: usize = 64;
+const TIMESTAMP_LEN: usize = 8;
+
+/// Sign `payload` for the mailbox identified by `s` as of now, returning the request body.
+pub fn sign(tag: &[u8], s: &Keypair, bound: &[u8], payload: &[u8]) -> Vec<u8> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    sign_at(tag, s, now, bound, payload)
+}
+
+/// Sign `payload` for the mailbox identified by `s` as of `timestamp` unix seconds.
+pub fn sign_at(tag: &[u8], s: &Keypair, timestamp: u64, bound: &[u8], payload: &[u8]) -> Vec<u8> {
+    let timestamp = timestamp.to_be_bytes();
+    let msg = message(tag, &s.public_key(), &timestamp, bound, payload);
+    let sig = Secp256k1::signing_only().sign_ecdsa(&msg, &s.secret_key()).serialize_compact();
+    [&sig[..], &timestamp, payload].concat()
+}
+
+/// Verify a body signed by the key identifying a mailbox, returning when it was signed and the
+/// payload it carries.
+///
+/// Whether the timestamp is recent enough is up to the caller.
+pub fn verify<'a>(
+    tag: &[u8],
+    pubkey: &PublicKey,
+    bound: &[u8],
+    body: &'a [u8],
+) -> Result<(u64, &'a [u8]), Error> {
+    if body.len() < SIGNATURE_LEN + TIMESTAMP_LEN {
+        return Err(Error::Truncated);
+    }
+    let (sig, rest) = body.split_at(SIGNATURE_LEN);
+    let (timestamp, payload) = rest.split_at(TIMESTAMP_LEN);
+    let sig = ecdsa::Signature::from_compact(sig)?;
+    let msg = message(tag, pubkey, timestamp, bound, payload);
+    Secp256k1::verification_only().verify_ecdsa(&msg, &sig, pubkey)?;
+    let timestamp = u64::from_be_bytes(timestamp.try_into().expect("split at its length"));
+    Ok((timestamp, payload))
+}
+
+fn message(
+    tag: &[u8],
+    pubkey: &PublicKey,
+    timestamp: &[u8],
+    bound: &[u8],
+    payload: &[u8],
+) -> Message {
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag);
+    engine.input(&pubkey.serialize());
+    engine.input(timestamp);
+    engine.input(bound);
+    engine.input(payload);
+    Message::from_digest(sha256::Hash::from_engine(engine).to_byte_array())
+}
+
+/// Error from verifying a signed mailbox request.
+#[derive(Debug)]
+pub enum Error {
+    /// The body is shorter than a signature and timestamp
+    Truncated,
+    Secp256k1(bitcoin::secp256k1::Error),
+}
+
+impl From<bitcoin::secp256k1::Error> for Error {
+    fn from(value: bitcoin::secp256k1::Error) -> Self { Self::Secp256k1(value) }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "signed body is shorter than a signature and timestamp"),
+            Error::Secp256k1(e) => write!(f, "invalid signature: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Truncated => None,
+            Error::Secp256k1(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn signatures_commit_to_everything_they_authorize() {
+        let secp = Secp256k1::new();
+        let s = Keypair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+        let not_s = Keypair::from_seckey_slice(&secp, &[2; 32]).unwrap();
+        let body = sign_at(POST_REPLY_TAG, &s, 42, b"slot", b"proposal");
+        assert_eq!(
+            verify(POST_REPLY_TAG, &s.public_key(), b"slot", &body).unwrap(),
+            (42, &b"proposal"[..])
+        );
+
+        let mut later = body.clone();
+        later[SIGNATURE_LEN + TIMESTAMP_LEN - 1] ^= 1;
+        let mut tampered = body.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        for (tag, pubkey, bound, body) in [
+            (POST_PAYJOIN_TAG, s.public_key(), &b"slot"[..], &body[..]),
+            (POST_REPLY_TAG, not_s.public_key(), b"slot", &body),
+            (POST_REPLY_TAG, s.public_key(), b"other slot", &body),
+            (POST_REPLY_TAG, s.public_key(), b"slot", &later),
+            (POST_REPLY_TAG, s.public_key(), b"slot", &tampered),
+        ] {
+            assert!(matches!(verify(tag, &pubkey, bound, body), Err(Error::Secp256k1(_))));
+        }
+        assert!(matches!(
+            verify(POST_REPLY_TAG, &s.public_key(), b"slot", &body[..70]),
+            Err(Error::Truncated)
+        ));
+    }
+}
@@ -225,10 +225,17 @@ impl App {
                 .await
                 .map_err(map_reqwest_err)?;
 
-            println!("Sent fallback transaction");
-            match ctx.process_response(&mut response.bytes().await?.to_vec().as_slice()) {
+            let was_delivered = req_ctx.is_original_delivered();
+            match req_ctx.process_v2_response(ctx, &mut response.bytes().await?.to_vec().as_slice())
+            {
                 Ok(Some(psbt)) => return Ok(psbt),
                 Ok(None) => {
+                    if !was_delivered && req_ctx.is_original_delivered() {
+                        println!("Sent fallback transaction");
+                        // Resume by polling without sending the fallback transaction again
+                        let endpoint = req_ctx.endpoint().clone();
+                        self.db.insert_send_session(req_ctx, &endpoint)?;
+                    }
                     println!("No response yet.");
                     tokio::time::sleep(std::time::Duration::from_secs(5)).await;
                 }
@@ -140,15 +140,13 @@ mod e2e {
     #[cfg(feature = "v2")]
     #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
     async fn send_receive_payjoin() {
-        use std::str::FromStr;
+        use std::net::{Ipv6Addr, SocketAddr};
         use std::sync::Arc;
         use std::time::Duration;
 
         use http::StatusCode;
         use once_cell::sync::{Lazy, OnceCell};
         use reqwest::{Client, ClientBuilder};
-        use testcontainers::clients::Cli;
-        use testcontainers_modules::redis::Redis;
         use tokio::process::Child;
         use url::Url;
 
@@ -165,9 +163,8 @@ mod e2e {
         let ohttp_relay = Url::parse(&format!("http://localhost:{}", ohttp_relay_port)).unwrap();
         let directory_port = find_free_port();
         let directory = Url::parse(&format!("https://localhost:{}", directory_port)).unwrap();
-        let gateway_origin = http::Uri::from_str(directory.as_str()).unwrap();
         tokio::select!(
-        _ = ohttp_relay::listen_tcp(ohttp_relay_port, gateway_origin) => assert!(false, "Ohttp relay is long running"),
+        _ = init_relay(ohttp_relay_port, directory.clone(), cert.clone()) => assert!(false, "Ohttp relay is long running"),
         _ = init_directory(directory_port, (cert.clone(), key)) => assert!(false, "Directory server is long running"),
         res = send_receive_cli_async(ohttp_relay, directory, cert) => assert!(res.is_ok(), "send_receive failed: {:?}", res),
         );
@@ -211,8 +208,7 @@ mod e2e {
             wait_for_service_ready(ohttp_relay.clone(), agent.clone()).await?;
             wait_for_service_ready(directory.clone(), agent).await?;
 
-            // fetch for setup here since ohttp_relay doesn't know the certificate for the directory
-            // so payjoin-cli is set up with the mock_ohttp_relay which is the directory
+            // fetch the keys through the relay's CONNECT tunnel for setup
             let ohttp_keys =
                 payjoin::io::fetch_ohttp_keys(ohttp_relay.clone(), directory.clone(), cert.clone())
                     .await?;
@@ -227,7 +223,7 @@ mod e2e {
             let payjoin_cli = env!("CARGO_BIN_EXE_payjoin-cli");
 
             let directory = directory.as_str();
-            // Mock ohttp_relay since the ohttp_relay's http client doesn't have the certificate for the directory
+            // Mock ohttp_relay by passing requests straight through to the directory
             let mock_ohttp_relay = directory;
 
             let cli_receive_initiator = Command::new(payjoin_cli)
@@ -452,12 +448,29 @@ mod e2e {
         }
 
         async fn init_directory(port: u16, local_cert_key: (Vec<u8>, Vec<u8>)) -> Result<()> {
-            let docker: Cli = Cli::default();
-            let timeout = Duration::from_secs(2);
-            let db = docker.run(Redis::default());
-            let db_host = format!("127.0.0.1:{}", db.get_host_port_ipv4(6379));
-            println!("Database running on {}", db.get_host_port_ipv4(6379));
-            payjoin_directory::listen_tcp_with_tls(port, db_host, timeout, local_cert_key).await
+            let (cert, key) = local_cert_key;
+            let config = payjoin_directory::Config {
+                bind_addr: SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+                timeout: Duration::from_secs(2),
+                db_backend: payjoin_directory::DbBackend::Memory,
+                ..Default::default()
+            };
+            payjoin_directory::listen_tcp_with_tls(
+                config,
+                payjoin_directory::GatewayKeys::ephemeral()?,
+                (vec![cert], key),
+                payjoin_directory::ShutdownHandle::new(),
+            )
+            .await
+        }
+
+        async fn init_relay(port: u16, gateway: Url, gateway_cert: Vec<u8>) -> Result<()> {
+            let config = payjoin_directory::RelayConfig {
+                bind_addr: SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+                gateway_origin: gateway.as_str().parse()?,
+                gateway_roots: vec![gateway_cert],
+            };
+            payjoin_directory::listen_relay(config, payjoin_directory::ShutdownHandle::new()).await
         }
 
         // generates or gets a DER encoded localhost cert and key.
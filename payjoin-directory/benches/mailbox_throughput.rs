@@ -0,0 +1,72 @@
+//! Measures how many mailbox round trips a store sustains under concurrent load.
+//!
+//! Each session does what a long-polling receiver and its sender do: subscribe to a key,
+//! write it from another task, wait for the notification, then read the entry back.
+//!
+//! ```console
+//! cargo bench -p payjoin-directory --bench mailbox_throughput
+//! PJ_BENCH_REDIS=localhost:6379 cargo bench -p payjoin-directory --bench mailbox_throughput
+//! ```
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use payjoin_directory::db::{FileStore, MailboxStore, MemoryStore, RedisStore};
+
+const SESSIONS: usize = 256;
+const ROUND_TRIPS_PER_SESSION: usize = 40;
+const ENTRY: &[u8] = &[0; 7168];
+const TTL: Duration = Duration::from_secs(60);
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    bench("memory", Arc::new(MemoryStore::new())).await?;
+
+    let dir = std::env::temp_dir().join(format!("payjoin-directory-bench-{}", std::process::id()));
+    bench("file", Arc::new(FileStore::open(&dir).await?)).await?;
+    std::fs::remove_dir_all(dir)?;
+
+    match std::env::var("PJ_BENCH_REDIS") {
+        Ok(db_host) => bench("redis", Arc::new(RedisStore::connect(&db_host).await?)).await?,
+        Err(_) => println!("redis: skipped, set PJ_BENCH_REDIS=host:port to include it"),
+    }
+    Ok(())
+}
+
+async fn bench(name: &str, store: Arc<dyn MailboxStore>) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let sessions: Vec<_> =
+        (0..SESSIONS).map(|session| tokio::spawn(round_trips(store.clone(), session))).collect();
+    for session in sessions {
+        session.await?.map_err(|e| e.to_string())?;
+    }
+    let elapsed = started.elapsed();
+    let round_trips = SESSIONS * ROUND_TRIPS_PER_SESSION;
+    println!(
+        "{}: {} round trips by {} sessions in {:.2?}, {:.0} round trips/s",
+        name,
+        round_trips,
+        SESSIONS,
+        elapsed,
+        round_trips as f64 / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+async fn round_trips(
+    store: Arc<dyn MailboxStore>,
+    session: usize,
+) -> Result<(), payjoin_directory::db::Error> {
+    let key = format!("bench{}-{}:res", std::process::id(), session);
+    for _ in 0..ROUND_TRIPS_PER_SESSION {
+        let mut subscription = store.subscribe(&key).await?;
+        let writer = tokio::spawn({
+            let (store, key) = (store.clone(), key.clone());
+            async move { store.set(&key, ENTRY.to_vec(), TTL).await }
+        });
+        subscription.changed().await?;
+        writer.await.expect("writer panicked")?;
+        assert_eq!(store.get(&key).await?.as_deref(), Some(ENTRY));
+    }
+    store.delete(&[key]).await
+}
@@ -0,0 +1,301 @@
+//! An API for operators, served on its own port that only accepts local connections.
+//!
+//! Every request must carry the configured token as `Authorization: Bearer <token>`.
+//!
+//! - `GET /mailboxes` counts mailboxes by the time since their last write
+//! - `POST /mailboxes/purge` removes expired entries the store still holds
+//! - `POST /ohttp-keys/rotate` makes a new OHTTP key current
+//! - `GET /storage` reports whether the mailbox store responds, and how quickly
+//! - `GET /metrics` serves the directory's metrics in the Prometheus text format
+
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::service::TowerToHyperService;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::{server, Directory, ShutdownHandle};
+
+/// Upper bounds of the age buckets mailboxes are counted in, the last bucket is unbounded
+const AGE_BUCKETS: &[Duration] = &[
+    Duration::from_secs(60),
+    Duration::from_secs(60 * 10),
+    Duration::from_secs(60 * 60),
+    Duration::from_secs(60 * 60 * 6),
+    Duration::from_secs(60 * 60 * 24),
+];
+
+/// Settings for the admin API
+#[derive(Debug, Clone)]
+pub struct AdminConfig {
+    /// A loopback address to accept connections on
+    pub bind_addr: SocketAddr,
+    pub token: AdminToken,
+}
+
+/// The secret admin requests are authenticated with. Never printed.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct AdminToken(String);
+
+impl AdminToken {
+    pub fn new(token: impl Into<String>) -> Self { Self(token.into()) }
+
+    pub fn len(&self) -> usize { self.0.len() }
+
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+
+    fn authorizes(&self, authorization: Option<&HeaderValue>) -> bool {
+        let presented = authorization.and_then(|value| value.as_bytes().strip_prefix(b"Bearer "));
+        let Some(presented) = presented else { return false };
+        let expected = self.0.as_bytes();
+        // Compare every byte so timing doesn't reveal how much of a guess was right
+        presented.len() == expected.len()
+            && presented.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("AdminToken(..)") }
+}
+
+/// Bind the admin API and return the future serving it until `shutdown` is triggered.
+pub(crate) async fn listen(
+    config: AdminConfig,
+    directory: Directory,
+    shutdown: ShutdownHandle,
+) -> Result<impl Future<Output = ()>, Box<dyn std::error::Error>> {
+    if !config.bind_addr.ip().is_loopback() {
+        return Err(
+            format!("Admin API must bind to a loopback address, not {}", config.bind_addr).into()
+        );
+    }
+    let listener = TcpListener::bind(config.bind_addr).await?;
+    info!("Payjoin Directory admin API awaiting HTTP connection at {}", config.bind_addr);
+    let token = Arc::new(config.token);
+    let new_service = move |_| {
+        let (directory, token) = (directory.clone(), token.clone());
+        let handler = move |req| handle_admin(req, directory.clone(), token.clone());
+        TowerToHyperService::new(tower::service_fn(handler))
+    };
+    Ok(server::serve(listener, |stream| async { Ok(stream) }, new_service, shutdown))
+}
+
+async fn handle_admin<B>(
+    req: Request<B>,
+    directory: Directory,
+    token: Arc<AdminToken>,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    if !token.authorizes(req.headers().get(AUTHORIZATION)) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, "Bearer")
+            .body(Full::default());
+    }
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/mailboxes") => mailbox_ages(&directory).await,
+        (&Method::POST, "/mailboxes/purge") => purge_expired(&directory).await,
+        (&Method::POST, "/ohttp-keys/rotate") => rotate_ohttp_keys(&directory).await,
+        (&Method::GET, "/storage") => storage_health(&directory).await,
+        (&Method::GET, "/metrics") => get_metrics(&directory),
+        _ => Response::builder().status(StatusCode::NOT_FOUND).body(Full::default()),
+    }
+}
+
+#[derive(Serialize)]
+struct MailboxAges {
+    mailboxes: usize,
+    by_age: Vec<AgeBucket>,
+}
+
+#[derive(Serialize)]
+struct AgeBucket {
+    /// `None` for the bucket of mailboxes older than every bound
+    max_age_secs: Option<u64>,
+    mailboxes: usize,
+}
+
+async fn mailbox_ages(directory: &Directory) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    let ages = match directory.pool.mailbox_ages().await {
+        Ok(ages) => ages,
+        Err(e) => return internal_error(e),
+    };
+    let bounds = AGE_BUCKETS.iter().map(|bound| Some(*bound)).chain([None]);
+    let mut by_age: Vec<AgeBucket> = bounds
+        .clone()
+        .map(|bound| AgeBucket { max_age_secs: bound.map(|b| b.as_secs()), mailboxes: 0 })
+        .collect();
+    for age in &ages {
+        let bucket = bounds.clone().position(|bound| bound.is_none_or(|b| *age < b));
+        by_age[bucket.expect("the last bucket is unbounded")].mailboxes += 1;
+    }
+    json(StatusCode::OK, &MailboxAges { mailboxes: ages.len(), by_age })
+}
+
+#[derive(Serialize)]
+struct Purged {
+    purged: usize,
+}
+
+async fn purge_expired(directory: &Directory) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    match directory.pool.purge_expired().await {
+        Ok(purged) => {
+            info!("Admin purged {} expired mailbox entries", purged);
+            json(StatusCode::OK, &Purged { purged })
+        }
+        Err(e) => internal_error(e),
+    }
+}
+
+#[derive(Serialize)]
+struct Rotated {
+    key_id: u8,
+}
+
+async fn rotate_ohttp_keys(
+    directory: &Directory,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    match directory.ohttp.lock().await.rotate() {
+        Ok(key_id) => json(StatusCode::OK, &Rotated { key_id }),
+        Err(e) => internal_error(e),
+    }
+}
+
+#[derive(Serialize)]
+struct StorageHealth {
+    healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn storage_health(
+    directory: &Directory,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    match directory.pool.ping().await {
+        Ok(latency) => {
+            let health =
+                StorageHealth { healthy: true, latency_ms: Some(latency.as_millis()), error: None };
+            json(StatusCode::OK, &health)
+        }
+        Err(e) => {
+            error!("Mailbox store is unhealthy: {}", e);
+            let health =
+                StorageHealth { healthy: false, latency_ms: None, error: Some(e.to_string()) };
+            json(StatusCode::SERVICE_UNAVAILABLE, &health)
+        }
+    }
+}
+
+fn get_metrics(directory: &Directory) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    match directory.metrics.encode() {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, prometheus::TEXT_FORMAT)
+            .body(Full::from(body)),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn json(
+    status: StatusCode,
+    body: &impl Serialize,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    let body = serde_json::to_vec(body).expect("admin responses serialize");
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::from(body))
+}
+
+fn internal_error(e: impl fmt::Display) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    error!("Admin request failed: {}", e);
+    Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Full::default())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use http_body_util::BodyExt;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+
+    use super::*;
+    use crate::{Config, DbBackend, GatewayKeys};
+
+    #[test]
+    fn only_the_exact_token_authorizes() {
+        let token = AdminToken::new("correct horse battery staple");
+        let bearer = |value: &'static str| Some(HeaderValue::from_static(value));
+        assert!(token.authorizes(bearer("Bearer correct horse battery staple").as_ref()));
+        assert!(!token.authorizes(bearer("Bearer correct horse battery stapl").as_ref()));
+        assert!(!token.authorizes(bearer("Bearer correct horse battery staplf").as_ref()));
+        assert!(!token.authorizes(bearer("correct horse battery staple").as_ref()));
+        assert!(!token.authorizes(None));
+        assert_eq!(format!("{:?}", token), "AdminToken(..)");
+    }
+
+    #[tokio::test]
+    async fn admin_api_requires_the_token_and_a_loopback_address() {
+        let free_port =
+            || std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = Config { db_backend: DbBackend::Memory, ..Config::default() };
+        let shutdown = ShutdownHandle::new();
+        let ohttp_keys = GatewayKeys::ephemeral().unwrap();
+        let directory = Directory::new(config, ohttp_keys, shutdown.clone()).await.unwrap();
+        let token = AdminToken::new("secret");
+
+        let public = SocketAddr::from((Ipv4Addr::UNSPECIFIED, free_port().port()));
+        let admin_config = AdminConfig { bind_addr: public, token: token.clone() };
+        assert!(listen(admin_config, directory.clone(), shutdown.clone()).await.is_err());
+
+        let bind_addr = free_port();
+        let admin_config = AdminConfig { bind_addr, token };
+        let admin = listen(admin_config, directory, shutdown.clone()).await.unwrap();
+        let admin = tokio::spawn(admin);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+        let request = |method: Method, path: &str, token: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(format!("http://{}{}", bind_addr, path))
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .body(Full::default())
+                .unwrap();
+            client.request(req)
+        };
+        let res = request(Method::GET, "/mailboxes", "wrong").await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = request(Method::GET, "/mailboxes", "secret").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let ages: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(ages["mailboxes"], 0);
+        assert_eq!(ages["by_age"].as_array().unwrap().len(), AGE_BUCKETS.len() + 1);
+
+        for (method, path) in [
+            (Method::POST, "/mailboxes/purge"),
+            (Method::POST, "/ohttp-keys/rotate"),
+            (Method::GET, "/storage"),
+            (Method::GET, "/metrics"),
+        ] {
+            let res = request(method, path, "secret").await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK, "{}", path);
+        }
+
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), admin).await.unwrap().unwrap();
+    }
+}
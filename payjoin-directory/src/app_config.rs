@@ -0,0 +1,283 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::ArgMatches;
+use config::{Config, ConfigError, File, FileFormat};
+use payjoin_directory::*;
+use serde::Deserialize;
+
+pub(crate) const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const MIN_ADMIN_TOKEN_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Redis,
+    Memory,
+    File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Directory settings merged from defaults, the config file, environment and flags, in
+/// increasing order of precedence
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfig {
+    pub bind_addr: IpAddr,
+    pub port: u16,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub timeout_secs: u64,
+    pub mailbox_ttl_secs: u64,
+    pub max_payload_size: usize,
+    pub max_queued_requests: usize,
+    pub relay_requests_per_sec: u32,
+    pub mailbox_writes_per_min: u32,
+    pub max_long_polls_per_mailbox: usize,
+    pub max_buffered_bytes: usize,
+    pub db_backend: Backend,
+    pub db_host: String,
+    pub db_path: PathBuf,
+    pub ohttp_keys_path: PathBuf,
+    pub ohttp_key_grace_secs: u64,
+    pub rotate_ohttp_keys: bool,
+    pub log_format: LogFormat,
+    /// Run as an OHTTP relay forwarding to this gateway origin instead of as a directory
+    pub relay_gateway: Option<String>,
+    /// A PEM certificate to trust for the relay's gateway besides the system roots
+    pub relay_gateway_cert: Option<PathBuf>,
+    /// Serve the admin API on this port of the IPv4 loopback address
+    pub admin_port: Option<u16>,
+    pub admin_token: Option<AdminToken>,
+}
+
+impl AppConfig {
+    pub(crate) fn new(matches: &ArgMatches) -> Result<Self, ConfigError> {
+        // An explicitly given config file must exist, the default one is optional
+        let (config_path, config_required) = match matches.get_one::<String>("config") {
+            Some(path) => (path.as_str(), true),
+            None => (DEFAULT_CONFIG_PATH, false),
+        };
+        let string_arg = |id: &str| matches.get_one::<String>(id).map(|s| s.as_str());
+        let rotate_ohttp_keys = matches.get_flag("rotate_ohttp_keys").then_some(true);
+        let rate_limits = RateLimits::default();
+
+        let config = Config::builder()
+            .set_default("bind_addr", "::")?
+            .set_default("port", DEFAULT_DIR_PORT)?
+            .set_default("tls_cert", None::<String>)?
+            .set_default("tls_key", None::<String>)?
+            .set_default("timeout_secs", DEFAULT_TIMEOUT_SECS)?
+            .set_default("mailbox_ttl_secs", DEFAULT_MAILBOX_TTL_SECS)?
+            .set_default("max_payload_size", DEFAULT_MAX_PAYLOAD_SIZE as u64)?
+            .set_default("max_queued_requests", DEFAULT_MAX_QUEUED_REQUESTS as u64)?
+            .set_default("relay_requests_per_sec", rate_limits.relay_requests_per_sec)?
+            .set_default("mailbox_writes_per_min", rate_limits.mailbox_writes_per_min)?
+            .set_default(
+                "max_long_polls_per_mailbox",
+                rate_limits.max_long_polls_per_mailbox as u64,
+            )?
+            .set_default("max_buffered_bytes", rate_limits.max_buffered_bytes as u64)?
+            .set_default("db_backend", "redis")?
+            .set_default("db_host", DEFAULT_DB_HOST)?
+            .set_default("db_path", DEFAULT_DB_PATH)?
+            .set_default("ohttp_keys_path", DEFAULT_OHTTP_KEYS_PATH)?
+            .set_default("ohttp_key_grace_secs", DEFAULT_OHTTP_KEY_GRACE_SECS)?
+            .set_default("rotate_ohttp_keys", false)?
+            .set_default("log_format", "text")?
+            .set_default("relay_gateway", None::<String>)?
+            .set_default("relay_gateway_cert", None::<String>)?
+            .set_default("admin_port", None::<String>)?
+            .set_default("admin_token", None::<String>)?
+            .add_source(File::new(config_path, FileFormat::Toml).required(config_required))
+            .set_override_option("bind_addr", string_arg("bind_addr"))?
+            .set_override_option("port", string_arg("port"))?
+            .set_override_option("tls_cert", string_arg("tls_cert"))?
+            .set_override_option("tls_key", string_arg("tls_key"))?
+            .set_override_option("timeout_secs", string_arg("timeout_secs"))?
+            .set_override_option("mailbox_ttl_secs", string_arg("mailbox_ttl_secs"))?
+            .set_override_option("max_payload_size", string_arg("max_payload_size"))?
+            .set_override_option("max_queued_requests", string_arg("max_queued_requests"))?
+            .set_override_option("relay_requests_per_sec", string_arg("relay_requests_per_sec"))?
+            .set_override_option("mailbox_writes_per_min", string_arg("mailbox_writes_per_min"))?
+            .set_override_option(
+                "max_long_polls_per_mailbox",
+                string_arg("max_long_polls_per_mailbox"),
+            )?
+            .set_override_option("max_buffered_bytes", string_arg("max_buffered_bytes"))?
+            .set_override_option("db_backend", string_arg("db_backend"))?
+            .set_override_option("db_host", string_arg("db_host"))?
+            .set_override_option("db_path", string_arg("db_path"))?
+            .set_override_option("ohttp_keys_path", string_arg("ohttp_keys_path"))?
+            .set_override_option("ohttp_key_grace_secs", string_arg("ohttp_key_grace_secs"))?
+            .set_override_option("rotate_ohttp_keys", rotate_ohttp_keys)?
+            .set_override_option("log_format", string_arg("log_format"))?
+            .set_override_option("relay_gateway", string_arg("relay_gateway"))?
+            .set_override_option("relay_gateway_cert", string_arg("relay_gateway_cert"))?
+            .set_override_option("admin_port", string_arg("admin_port"))?
+            .set_override_option("admin_token", string_arg("admin_token"))?
+            .build()?;
+        let app_config: AppConfig = config.try_deserialize()?;
+        app_config.validate()?;
+        Ok(app_config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.timeout_secs == 0 {
+            return Err(invalid("timeout_secs", "must be at least 1"));
+        }
+        if self.mailbox_ttl_secs == 0 {
+            return Err(invalid("mailbox_ttl_secs", "must be at least 1"));
+        }
+        if self.max_payload_size == 0 {
+            return Err(invalid("max_payload_size", "must be at least 1"));
+        }
+        if self.max_queued_requests == 0 {
+            return Err(invalid("max_queued_requests", "must be at least 1"));
+        }
+        if self.relay_requests_per_sec == 0 {
+            return Err(invalid("relay_requests_per_sec", "must be at least 1"));
+        }
+        if self.mailbox_writes_per_min == 0 {
+            return Err(invalid("mailbox_writes_per_min", "must be at least 1"));
+        }
+        if self.max_long_polls_per_mailbox == 0 {
+            return Err(invalid("max_long_polls_per_mailbox", "must be at least 1"));
+        }
+        if self.max_buffered_bytes < self.max_payload_size {
+            return Err(invalid("max_buffered_bytes", "must be at least max_payload_size"));
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), None) => return Err(invalid("tls_key", "is required with tls_cert")),
+            (None, Some(_)) => return Err(invalid("tls_cert", "is required with tls_key")),
+            (Some(_), Some(_)) if !cfg!(feature = "tls") =>
+                return Err(invalid("tls_cert", "requires building with the `tls` feature")),
+            _ => (),
+        }
+        match &self.relay_gateway {
+            Some(_) if !cfg!(feature = "tls") =>
+                return Err(invalid("relay_gateway", "requires building with the `tls` feature")),
+            Some(gateway) if gateway.parse::<hyper::Uri>().is_err() =>
+                return Err(invalid("relay_gateway", "must be a URL like https://host:port")),
+            None if self.relay_gateway_cert.is_some() =>
+                return Err(invalid("relay_gateway", "is required with relay_gateway_cert")),
+            _ => (),
+        }
+        match (&self.admin_port, &self.admin_token) {
+            (Some(_), None) => return Err(invalid("admin_token", "is required with admin_port")),
+            (_, Some(token)) if token.len() < MIN_ADMIN_TOKEN_LEN =>
+                return Err(invalid("admin_token", "must be at least 16 characters")),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    pub fn directory_config(&self) -> payjoin_directory::Config {
+        let db_backend = match self.db_backend {
+            Backend::Redis => DbBackend::Redis(self.db_host.clone()),
+            Backend::Memory => DbBackend::Memory,
+            Backend::File => DbBackend::File(self.db_path.clone()),
+        };
+        payjoin_directory::Config {
+            bind_addr: SocketAddr::new(self.bind_addr, self.port),
+            timeout: Duration::from_secs(self.timeout_secs),
+            mailbox_ttl: Duration::from_secs(self.mailbox_ttl_secs),
+            max_payload_size: self.max_payload_size,
+            max_queued_requests: self.max_queued_requests,
+            db_backend,
+            rate_limits: RateLimits {
+                relay_requests_per_sec: self.relay_requests_per_sec,
+                mailbox_writes_per_min: self.mailbox_writes_per_min,
+                max_long_polls_per_mailbox: self.max_long_polls_per_mailbox,
+                max_buffered_bytes: self.max_buffered_bytes,
+            },
+            admin: self.admin_port.zip(self.admin_token.clone()).map(|(port, token)| AdminConfig {
+                bind_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+                token,
+            }),
+        }
+    }
+}
+
+fn invalid(key: &str, reason: &str) -> ConfigError {
+    ConfigError::Message(format!("\"{}\" {}", key, reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config_file(test_name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "payjoin-directory-{}-{}.toml",
+            test_name,
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn load(config_path: &str, args: &[&str]) -> Result<AppConfig, ConfigError> {
+        let mut argv = vec!["payjoin-directory", "--config", config_path];
+        argv.extend(args);
+        AppConfig::new(&crate::cli().try_get_matches_from(argv).unwrap())
+    }
+
+    #[test]
+    fn missing_explicit_config_file_is_an_error() {
+        assert!(load("payjoin-directory-missing.toml", &[]).is_err());
+    }
+
+    #[test]
+    fn flags_override_config_file() {
+        let path = config_file(
+            "flags_override_config_file",
+            "port = 9000\ndb_backend = \"memory\"\nlog_format = \"json\"\n",
+        );
+        let app_config = load(&path, &["--port", "9001"]).unwrap();
+        assert_eq!(app_config.port, 9001);
+        assert_eq!(app_config.db_backend, Backend::Memory);
+        assert_eq!(app_config.log_format, LogFormat::Json);
+        assert_eq!(app_config.max_payload_size, DEFAULT_MAX_PAYLOAD_SIZE);
+    }
+
+    #[test]
+    fn admin_api_binds_to_loopback_and_hides_its_token() {
+        let path = config_file(
+            "admin_api_binds_to_loopback_and_hides_its_token",
+            "admin_port = 8081\nadmin_token = \"0123456789abcdef\"\n",
+        );
+        let app_config = load(&path, &[]).unwrap();
+        let admin = app_config.directory_config().admin.unwrap();
+        assert!(admin.bind_addr.ip().is_loopback());
+        assert_eq!(admin.token, AdminToken::new("0123456789abcdef"));
+        assert!(!format!("{:?}", app_config).contains("0123456789abcdef"));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let path = config_file("invalid_values_are_rejected", "");
+        for args in [
+            &["--port", "not-a-port"][..],
+            &["--bind-addr", "localhost"],
+            &["--db-backend", "postgres"],
+            &["--max-payload-size", "0"],
+            &["--max-queued-requests", "0"],
+            &["--max-long-polls-per-mailbox", "0"],
+            &["--max-buffered-bytes", "1024"],
+            &["--tls-cert", "cert.pem"],
+            &["--relay-gateway", "not a url"],
+            &["--relay-gateway-cert", "cert.pem"],
+            &["--admin-port", "8081"],
+            &["--admin-port", "8081", "--admin-token", "short"],
+        ] {
+            assert!(load(&path, args).is_err(), "{:?} should be rejected", args);
+        }
+    }
+}
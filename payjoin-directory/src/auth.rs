@@ -0,0 +1,31 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::secp256k1::PublicKey;
+
+/// How far a signed timestamp may be from the directory's clock
+pub(crate) const MAX_TIMESTAMP_SKEW: Duration = Duration::from_secs(120);
+
+/// Verify a body signed by the key identifying a mailbox as [`mailbox_auth`] describes, and
+/// that it was signed recently, returning the payload it carries.
+///
+/// The timestamp bounds how long a captured request can be replayed.
+pub(crate) fn verify_fresh<'a>(
+    tag: &[u8],
+    pubkey: &PublicKey,
+    bound: &[u8],
+    body: &'a [u8],
+) -> anyhow::Result<&'a [u8]> {
+    let (timestamp, payload) = mailbox_auth::verify(tag, pubkey, bound, body)?;
+    check_fresh(timestamp)?;
+    Ok(payload)
+}
+
+fn check_fresh(timestamp: u64) -> anyhow::Result<()> {
+    let signed_at = UNIX_EPOCH + Duration::from_secs(timestamp);
+    let now = SystemTime::now();
+    let skew = now.duration_since(signed_at).or_else(|_| signed_at.duration_since(now))?;
+    if skew > MAX_TIMESTAMP_SKEW {
+        anyhow::bail!("Signed timestamp is {}s away from now", skew.as_secs());
+    }
+    Ok(())
+}
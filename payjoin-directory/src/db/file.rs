@@ -0,0 +1,268 @@
+use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+
+use super::notifier::Notifier;
+use super::{Error, MailboxStore, Subscription};
+
+const EXPIRY_LEN: usize = 8;
+const LENGTH_PREFIX_LEN: usize = 4;
+
+/// Keeps each mailbox entry, or queue of entries, in its own file under a directory.
+///
+/// Files hold the expiry as big-endian unix seconds followed by each entry's big-endian
+/// `u32` length and data, oldest first. Writes go through a temporary file and a rename so
+/// readers never see a partial entry. Wake-ups are delivered in-process, so only one
+/// directory instance may use a given path.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    notifier: Notifier,
+    tmp_counter: AtomicU64,
+    /// Serializes the read-modify-write of appends, and writes with removals of expired
+    /// files so a fresh write is never removed in its place
+    append_lock: Mutex<()>,
+}
+
+impl FileStore {
+    /// Open a store at `path`, creating it if needed and removing entries that expired
+    /// while the directory was down.
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        fs::create_dir_all(&path).await?;
+        let store = Self {
+            path,
+            notifier: Notifier::new(),
+            tmp_counter: AtomicU64::new(0),
+            append_lock: Mutex::new(()),
+        };
+        store.remove_expired().await?;
+        Ok(store)
+    }
+
+    /// Remove expired and leftover temporary files, returning how many expired entries
+    /// there were
+    async fn remove_expired(&self) -> Result<usize, Error> {
+        let mut removed = 0;
+        let mut dir = fs::read_dir(&self.path).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let path = file.path();
+            if is_tmp(&path) {
+                remove_if_exists(&path).await?;
+            } else if read_entries(&path).await?.is_none() {
+                remove_if_exists(&path).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        // ':' separates id and column in keys but isn't portable in file names
+        self.path.join(key.replace(':', "."))
+    }
+
+    async fn write_entries(
+        &self,
+        key: &str,
+        entries: &[Vec<u8>],
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let expires_at = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?
+            .as_secs();
+        let mut contents = expires_at.to_be_bytes().to_vec();
+        for entry in entries {
+            let len = u32::try_from(entry.len())
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+            contents.extend(len.to_be_bytes());
+            contents.extend(entry);
+        }
+
+        let path = self.entry_path(key);
+        let tmp_id = self.tmp_counter.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = path.with_extension(format!("{}.tmp", tmp_id));
+        fs::write(&tmp_path, contents).await?;
+        fs::rename(&tmp_path, &path).await?;
+        self.notifier.notify(key);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MailboxStore for FileStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let path = self.entry_path(key);
+        if let Some(entries) = read_entries(&path).await? {
+            return Ok(entries.into_iter().next());
+        }
+        // A write may have replaced the file since it was read, so check again before
+        // removing it
+        let _appending = self.append_lock.lock().await;
+        if read_expiry(&path).await?.is_some_and(|expires_at| expires_at <= unix_now()) {
+            remove_if_exists(&path).await?;
+        }
+        Ok(None)
+    }
+
+    async fn set(&self, key: &str, data: Vec<u8>, ttl: Duration) -> Result<(), Error> {
+        let _appending = self.append_lock.lock().await;
+        self.write_entries(key, &[data], ttl).await
+    }
+
+    async fn append(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        ttl: Duration,
+        max_len: usize,
+    ) -> Result<(), Error> {
+        let _appending = self.append_lock.lock().await;
+        let mut entries = read_entries(&self.entry_path(key)).await?.unwrap_or_default();
+        if entries.contains(&data) {
+            return Ok(());
+        }
+        if entries.len() >= max_len {
+            return Err(Error::MailboxFull);
+        }
+        entries.push(data);
+        self.write_entries(key, &entries, ttl).await
+    }
+
+    async fn dequeue(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        let _appending = self.append_lock.lock().await;
+        let path = self.entry_path(key);
+        let Some(mut entries) = read_entries(&path).await? else { return Ok(()) };
+        if entries.first().map(Vec::as_slice) != Some(data) {
+            return Ok(());
+        }
+        entries.remove(0);
+        if entries.is_empty() {
+            remove_if_exists(&path).await?;
+            self.notifier.notify(key);
+            return Ok(());
+        }
+        let expires_at = read_expiry(&path).await?.unwrap_or_default();
+        let ttl = Duration::from_secs(expires_at.saturating_sub(unix_now()));
+        self.write_entries(key, &entries, ttl).await
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<(), Error> {
+        for key in keys {
+            remove_if_exists(&self.entry_path(key)).await?;
+        }
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let prefix = prefix.replace(':', ".");
+        let mut keys = vec![];
+        let mut dir = fs::read_dir(&self.path).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let path = file.path();
+            let Some(name) = path.file_name().and_then(OsStr::to_str) else { continue };
+            if name.starts_with(&prefix) && !is_tmp(&path) {
+                keys.push(name.replace('.', ":"));
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn subscribe(&self, key: &str) -> Result<Box<dyn Subscription>, Error> {
+        Ok(Box::new(self.notifier.subscribe(key)))
+    }
+
+    async fn expirations(&self) -> Result<Vec<(String, Duration)>, Error> {
+        let now = unix_now();
+        let mut expirations = vec![];
+        let mut dir = fs::read_dir(&self.path).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let path = file.path();
+            let Some(name) = path.file_name().and_then(OsStr::to_str) else { continue };
+            if is_tmp(&path) {
+                continue;
+            }
+            if let Some(expires_at) = read_expiry(&path).await?.filter(|&at| at > now) {
+                let key = name.replace('.', ":");
+                expirations.push((key, Duration::from_secs(expires_at - now)));
+            }
+        }
+        Ok(expirations)
+    }
+
+    /// Temporary files of interrupted writes are removed too
+    async fn purge_expired(&self) -> Result<usize, Error> {
+        // Appends rewrite files in place, which must not race with their removal
+        let _appending = self.append_lock.lock().await;
+        self.remove_expired().await
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        if fs::metadata(&self.path).await?.is_dir() {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(ErrorKind::NotFound, "store directory is missing").into())
+        }
+    }
+}
+
+/// Read a file's entries, or `None` if it is missing, expired or corrupt.
+async fn read_entries(path: &Path) -> Result<Option<Vec<Vec<u8>>>, Error> {
+    let contents = match fs::read(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let Some((expires_at, mut rest)) = split(&contents, EXPIRY_LEN) else { return Ok(None) };
+    let expires_at = u64::from_be_bytes(expires_at.try_into().expect("split at EXPIRY_LEN"));
+    if expires_at <= unix_now() {
+        return Ok(None);
+    }
+    let mut entries = vec![];
+    while !rest.is_empty() {
+        let Some((len, after_len)) = split(rest, LENGTH_PREFIX_LEN) else { return Ok(None) };
+        let len = u32::from_be_bytes(len.try_into().expect("split at LENGTH_PREFIX_LEN"));
+        let Some((entry, after_entry)) = split(after_len, len as usize) else { return Ok(None) };
+        entries.push(entry.to_vec());
+        rest = after_entry;
+    }
+    Ok(Some(entries))
+}
+
+/// Read just the expiry of a file, or `None` if it is missing or corrupt.
+async fn read_expiry(path: &Path) -> Result<Option<u64>, Error> {
+    let mut file = match fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut expires_at = [0; EXPIRY_LEN];
+    match file.read_exact(&mut expires_at).await {
+        Ok(_) => Ok(Some(u64::from_be_bytes(expires_at))),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn unix_now() -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() }
+
+fn split(bytes: &[u8], mid: usize) -> Option<(&[u8], &[u8])> {
+    (bytes.len() >= mid).then(|| bytes.split_at(mid))
+}
+
+async fn remove_if_exists(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn is_tmp(path: &Path) -> bool { path.extension() == Some(OsStr::new("tmp")) }
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use super::notifier::Notifier;
+use super::{Error, MailboxStore, Subscription};
+
+/// How often writes sweep out entries that expired without being read
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps mailboxes in process memory.
+#[derive(Debug)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, Entry>>,
+    next_sweep: Mutex<Instant>,
+    notifier: Notifier,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// A single entry, or queued entries oldest first
+    data: Vec<Vec<u8>>,
+    expires_at: Instant,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            next_sweep: Mutex::new(Instant::now() + SWEEP_INTERVAL),
+            notifier: Notifier::new(),
+        }
+    }
+
+    /// Drop expired entries at most once per [`SWEEP_INTERVAL`], so a write only pays for a
+    /// pass over every mailbox once in a while
+    fn sweep_expired(&self, entries: &mut HashMap<String, Entry>, now: Instant) {
+        let mut next_sweep = self.next_sweep.lock().expect("poisoned");
+        if now < *next_sweep {
+            return;
+        }
+        entries.retain(|_, entry| entry.expires_at > now);
+        *next_sweep = now + SWEEP_INTERVAL;
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self { Self::new() }
+}
+
+#[async_trait]
+impl MailboxStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let mut entries = self.entries.lock().expect("poisoned");
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Ok(entry.data.first().cloned()),
+            Some(_) => {
+                entries.remove(key);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, data: Vec<u8>, ttl: Duration) -> Result<(), Error> {
+        let now = Instant::now();
+        {
+            let mut entries = self.entries.lock().expect("poisoned");
+            self.sweep_expired(&mut entries, now);
+            entries.insert(key.to_owned(), Entry { data: vec![data], expires_at: now + ttl });
+        }
+        self.notifier.notify(key);
+        Ok(())
+    }
+
+    async fn append(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        ttl: Duration,
+        max_len: usize,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        {
+            let mut entries = self.entries.lock().expect("poisoned");
+            self.sweep_expired(&mut entries, now);
+            let entry = entries
+                .entry(key.to_owned())
+                .or_insert_with(|| Entry { data: vec![], expires_at: now + ttl });
+            // Not swept yet, but no longer queued
+            if entry.expires_at <= now {
+                entry.data.clear();
+            }
+            if entry.data.contains(&data) {
+                return Ok(());
+            }
+            if entry.data.len() >= max_len {
+                return Err(Error::MailboxFull);
+            }
+            entry.data.push(data);
+            entry.expires_at = now + ttl;
+        }
+        self.notifier.notify(key);
+        Ok(())
+    }
+
+    async fn dequeue(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        {
+            let mut entries = self.entries.lock().expect("poisoned");
+            let Some(entry) = entries.get_mut(key) else { return Ok(()) };
+            if entry.data.first().map(Vec::as_slice) != Some(data) {
+                return Ok(());
+            }
+            entry.data.remove(0);
+            if entry.data.is_empty() {
+                entries.remove(key);
+            }
+        }
+        self.notifier.notify(key);
+        Ok(())
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<(), Error> {
+        let mut entries = self.entries.lock().expect("poisoned");
+        for key in keys {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let now = Instant::now();
+        let entries = self.entries.lock().expect("poisoned");
+        Ok(entries
+            .iter()
+            .filter(|(key, entry)| key.starts_with(prefix) && entry.expires_at > now)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    async fn subscribe(&self, key: &str) -> Result<Box<dyn Subscription>, Error> {
+        Ok(Box::new(self.notifier.subscribe(key)))
+    }
+
+    async fn expirations(&self) -> Result<Vec<(String, Duration)>, Error> {
+        let now = Instant::now();
+        let entries = self.entries.lock().expect("poisoned");
+        Ok(entries
+            .iter()
+            .filter(|(_, entry)| entry.expires_at > now)
+            .map(|(key, entry)| (key.clone(), entry.expires_at - now))
+            .collect())
+    }
+
+    async fn purge_expired(&self) -> Result<usize, Error> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("poisoned");
+        let before = entries.len();
+        entries.retain(|_, entry| entry.expires_at > now);
+        Ok(before - entries.len())
+    }
+}
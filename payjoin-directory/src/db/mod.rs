@@ -0,0 +1,634 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tracing::debug;
+
+mod file;
+mod memory;
+mod notifier;
+mod redis;
+
+pub use self::file::FileStore;
+pub use self::memory::MemoryStore;
+pub use self::redis::RedisStore;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
+use crate::shutdown::ShutdownHandle;
+
+const RES_COLUMN: &str = "res";
+const REQ_COLUMN: &str = "req";
+/// A key no mailbox uses, read to check that a store responds
+const HEALTH_PROBE_KEY: &str = "health-probe";
+
+/// Storage for mailbox entries, keyed by subdirectory id and column.
+///
+/// Implementations must wake every [`Subscription`] to a key when that key is written so
+/// long-polling clients are answered as soon as their counterparty posts.
+#[async_trait]
+pub trait MailboxStore: Send + Sync {
+    /// Fetch the entry under `key`, or the oldest entry queued under it, or `None` if there
+    /// is none or it expired.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Store `data` under `key`, replacing any previous entry or queue, and notify
+    /// subscribers.
+    async fn set(&self, key: &str, data: Vec<u8>, ttl: Duration) -> Result<(), Error>;
+
+    /// Queue `data` behind the entries under `key` and notify subscribers, unless an
+    /// identical entry is already queued. Refuses with [`Error::MailboxFull`] once `max_len`
+    /// entries are queued. The whole queue expires `ttl` after its last write.
+    async fn append(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        ttl: Duration,
+        max_len: usize,
+    ) -> Result<(), Error>;
+
+    /// Remove the oldest entry queued under `key` if it equals `data`, and notify subscribers
+    /// so polls see the entry queued behind it.
+    async fn dequeue(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        let _ = (key, data);
+        Err(Error::Unsupported)
+    }
+
+    /// Remove the entries under `keys`. Missing keys are ignored.
+    async fn delete(&self, keys: &[String]) -> Result<(), Error>;
+
+    /// List the live keys starting with `prefix`, such as every reply slot of a mailbox.
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let keys = self.expirations().await?.into_iter().map(|(key, _)| key);
+        Ok(keys.filter(|key| key.starts_with(prefix)).collect())
+    }
+
+    /// Listen for writes to `key`.
+    async fn subscribe(&self, key: &str) -> Result<Box<dyn Subscription>, Error>;
+
+    /// List every live key with the time left until it expires, for the admin API.
+    async fn expirations(&self) -> Result<Vec<(String, Duration)>, Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Remove expired entries that are still stored and return how many there were.
+    /// Stores that drop entries as soon as they expire have nothing to purge.
+    async fn purge_expired(&self) -> Result<usize, Error> { Ok(0) }
+
+    /// Check that the store can be reached.
+    async fn ping(&self) -> Result<(), Error> { self.get(HEALTH_PROBE_KEY).await.map(drop) }
+}
+
+/// A handle on notifications of writes to a single key.
+#[async_trait]
+pub trait Subscription: Send {
+    /// Wait for the next write to the subscribed key.
+    async fn changed(&mut self) -> Result<(), Error>;
+}
+
+/// Where the directory keeps its mailboxes
+#[derive(Clone)]
+pub enum DbBackend {
+    /// A redis server at `host:port`. Supports several directory instances sharing one store.
+    Redis(String),
+    /// Process memory, for tests and small deployments. Mailboxes are lost on restart.
+    Memory,
+    /// One file per entry in the given directory, for single-node deployments.
+    File(PathBuf),
+    /// A caller-provided store.
+    Custom(Arc<dyn MailboxStore>),
+}
+
+impl fmt::Debug for DbBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbBackend::Redis(host) => f.debug_tuple("Redis").field(host).finish(),
+            DbBackend::Memory => write!(f, "Memory"),
+            DbBackend::File(path) => f.debug_tuple("File").field(path).finish(),
+            DbBackend::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct DbPool {
+    store: Arc<dyn MailboxStore>,
+    timeout: Duration,
+    /// How long a mailbox entry lives after its last write
+    ttl: Duration,
+    /// How many distinct requests a mailbox holds for its receiver
+    max_queued_requests: usize,
+    metrics: Metrics,
+    limiter: RateLimiter,
+    shutdown: ShutdownHandle,
+}
+
+impl DbPool {
+    pub async fn new(
+        timeout: Duration,
+        ttl: Duration,
+        max_queued_requests: usize,
+        backend: DbBackend,
+        metrics: Metrics,
+        limiter: RateLimiter,
+        shutdown: ShutdownHandle,
+    ) -> Result<Self, Error> {
+        let store: Arc<dyn MailboxStore> = match backend {
+            DbBackend::Redis(db_host) => Arc::new(RedisStore::connect(&db_host).await?),
+            DbBackend::Memory => Arc::new(MemoryStore::new()),
+            DbBackend::File(path) => Arc::new(FileStore::open(path).await?),
+            DbBackend::Custom(store) => store,
+        };
+        Ok(Self { store, timeout, ttl, max_queued_requests, metrics, limiter, shutdown })
+    }
+
+    pub async fn peek_req(&self, pubkey_id: &str) -> Option<Result<Vec<u8>, Error>> {
+        self.peek_with_timeout(pubkey_id, REQ_COLUMN).await
+    }
+
+    pub async fn peek_res(&self, pubkey_id: &str) -> Option<Result<Vec<u8>, Error>> {
+        self.peek_with_timeout(pubkey_id, RES_COLUMN).await
+    }
+
+    /// Queue a sender's request for the receiver. The receiver is served the oldest one, so
+    /// a later competing request can't replace it, and resending a request is harmless.
+    pub async fn push_req(&self, pubkey_id: &str, data: Vec<u8>) -> Result<(), Error> {
+        self.push(pubkey_id, REQ_COLUMN, data).await
+    }
+
+    /// Answer the oldest request in the shared response column, and serve the next request
+    pub async fn push_res(&self, pubkey_id: &str, data: Vec<u8>) -> Result<(), Error> {
+        self.push(pubkey_id, RES_COLUMN, data).await?;
+        let Some(answered) = self.get(&channel_name(pubkey_id, REQ_COLUMN)).await? else {
+            return Ok(());
+        };
+        self.drop_req(pubkey_id, &reply_id(&answered)).await
+    }
+
+    /// Wait for the reply to the request whose digest is `reply_id`
+    pub async fn peek_reply(
+        &self,
+        pubkey_id: &str,
+        reply_id: &str,
+    ) -> Option<Result<Vec<u8>, Error>> {
+        self.peek_with_timeout(pubkey_id, &reply_column(reply_id)).await
+    }
+
+    /// The reply to the request whose digest is `reply_id`, without waiting for one
+    pub async fn get_reply(
+        &self,
+        pubkey_id: &str,
+        reply_id: &str,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.get(&channel_name(pubkey_id, &reply_column(reply_id))).await
+    }
+
+    /// Answer the request whose digest is `reply_id` in its own reply slot, so the senders
+    /// of a reusable mailbox each get their own reply, and serve the next request.
+    pub async fn push_reply(
+        &self,
+        pubkey_id: &str,
+        reply_id: &str,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.push(pubkey_id, &reply_column(reply_id), data).await?;
+        self.drop_req(pubkey_id, reply_id).await
+    }
+
+    /// Stop serving the oldest request if its digest is `reply_id`, so the receiver is
+    /// served the request queued behind it
+    pub async fn drop_req(&self, pubkey_id: &str, reply_id: &str) -> Result<(), Error> {
+        let key = channel_name(pubkey_id, REQ_COLUMN);
+        let Some(oldest) = self.get(&key).await? else { return Ok(()) };
+        if reply_id != self::reply_id(&oldest) {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let result = self.store.dequeue(&key, &oldest).await;
+        self.metrics.store_op("dequeue", started);
+        result
+    }
+
+    /// Remove every entry of a mailbox: its requests, its response and each reply slot.
+    pub async fn delete(&self, pubkey_id: &str) -> Result<(), Error> {
+        let started = Instant::now();
+        let mut keys = self.store.keys(&channel_name(pubkey_id, &reply_column(""))).await?;
+        keys.extend([channel_name(pubkey_id, REQ_COLUMN), channel_name(pubkey_id, RES_COLUMN)]);
+        let result = self.store.delete(&keys).await;
+        self.metrics.store_op("delete", started);
+        result
+    }
+
+    /// The time since each mailbox was last written, in no particular order
+    pub async fn mailbox_ages(&self) -> Result<Vec<Duration>, Error> {
+        let mut remaining: HashMap<String, Duration> = HashMap::new();
+        for (key, left) in self.store.expirations().await? {
+            // Either column's write keeps the mailbox alive
+            let pubkey_id = key.split_once(':').map_or(key.as_str(), |(id, _)| id);
+            let longest = remaining.entry(pubkey_id.to_owned()).or_default();
+            *longest = (*longest).max(left);
+        }
+        Ok(remaining.into_values().map(|left| self.ttl.saturating_sub(left)).collect())
+    }
+
+    pub async fn purge_expired(&self) -> Result<usize, Error> {
+        let started = Instant::now();
+        let result = self.store.purge_expired().await;
+        self.metrics.store_op("purge", started);
+        result
+    }
+
+    /// Check that the store responds and return how long it took
+    pub async fn ping(&self) -> Result<Duration, Error> {
+        let started = Instant::now();
+        self.store.ping().await?;
+        Ok(started.elapsed())
+    }
+
+    async fn push(&self, pubkey_id: &str, channel_type: &str, data: Vec<u8>) -> Result<(), Error> {
+        self.limiter
+            .check_mailbox_write(&mailbox_side(pubkey_id, channel_type))
+            .map_err(|_| Error::RateLimited)?;
+        let key = channel_name(pubkey_id, channel_type);
+        self.metrics.mailbox_entry(column_kind(channel_type), data.len());
+        // Expire every entry so abandoned sessions don't linger in the store
+        let started = Instant::now();
+        match channel_type {
+            REQ_COLUMN => {
+                let result =
+                    self.store.append(&key, data, self.ttl, self.max_queued_requests).await;
+                self.metrics.store_op("append", started);
+                result
+            }
+            _ => {
+                let result = self.store.set(&key, data, self.ttl).await;
+                self.metrics.store_op("set", started);
+                result
+            }
+        }
+    }
+
+    async fn peek_with_timeout(
+        &self,
+        pubkey_id: &str,
+        channel_type: &str,
+    ) -> Option<Result<Vec<u8>, Error>> {
+        let _long_poll = match self.limiter.start_long_poll(&mailbox_side(pubkey_id, channel_type))
+        {
+            Ok(guard) => guard,
+            Err(_) => return Some(Err(Error::RateLimited)),
+        };
+        let peeked = tokio::select! {
+            peeked = tokio::time::timeout(self.timeout, self.peek(pubkey_id, channel_type)) => peeked,
+            // Answer as if the poll timed out so the client polls again once we're back
+            _ = self.shutdown.wait() => return None,
+        };
+        if peeked.is_err() {
+            self.metrics.long_poll_timeout(column_kind(channel_type));
+        }
+        peeked.ok()
+    }
+
+    async fn peek(&self, pubkey_id: &str, channel_type: &str) -> Result<Vec<u8>, Error> {
+        let key = channel_name(pubkey_id, channel_type);
+
+        // Listen for changes before looking so a write landing in between isn't missed
+        let started = Instant::now();
+        let mut subscription = self.store.subscribe(&key).await?;
+        self.metrics.store_op("subscribe", started);
+        loop {
+            if let Some(data) = self.get(&key).await? {
+                if !data.is_empty() {
+                    return Ok(data);
+                }
+            }
+            debug!("Waiting for the mailbox to be written");
+            subscription.changed().await?;
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let started = Instant::now();
+        let result = self.store.get(key).await;
+        self.metrics.store_op("get", started);
+        result
+    }
+}
+
+fn channel_name(pubkey_id: &str, channel_type: &str) -> String {
+    format!("{}:{}", pubkey_id, channel_type)
+}
+
+/// The base64url-encoded SHA256 digest of a request, which names the slot its reply goes in
+pub(crate) fn reply_id(request: &[u8]) -> String {
+    use bitcoin::base64::prelude::BASE64_URL_SAFE_NO_PAD;
+    use bitcoin::base64::Engine;
+    use bitcoin::hashes::{sha256, Hash};
+
+    BASE64_URL_SAFE_NO_PAD.encode(sha256::Hash::hash(request).to_byte_array())
+}
+
+fn reply_column(reply_id: &str) -> String { format!("{}-{}", RES_COLUMN, reply_id) }
+
+/// The key mailbox limits are counted under. Senders write requests and wait on replies,
+/// while only the receiver, whose writes are signed, writes replies and waits on requests,
+/// so limiting each column separately keeps senders from using up the receiver's share.
+fn mailbox_side(pubkey_id: &str, channel_type: &str) -> String {
+    channel_name(pubkey_id, column_kind(channel_type))
+}
+
+/// The column a reply slot belongs to, so metrics don't label anything per request
+fn column_kind(channel_type: &str) -> &str {
+    channel_type.split_once('-').map_or(channel_type, |(kind, _)| kind)
+}
+
+/// Error from reading or writing a [`MailboxStore`].
+#[derive(Debug)]
+pub enum Error {
+    Redis(::redis::RedisError),
+    Io(std::io::Error),
+    /// The store stopped delivering write notifications
+    SubscriptionClosed,
+    /// The mailbox is being written to or polled too often
+    RateLimited,
+    /// The mailbox already holds as many requests as it may queue
+    MailboxFull,
+    /// The store doesn't implement an optional operation
+    Unsupported,
+}
+
+impl From<::redis::RedisError> for Error {
+    fn from(value: ::redis::RedisError) -> Self { Self::Redis(value) }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self { Self::Io(value) }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+
+        match &self {
+            Redis(e) => e.fmt(f),
+            Io(e) => e.fmt(f),
+            SubscriptionClosed => write!(f, "Mailbox subscription closed"),
+            RateLimited => write!(f, "Mailbox rate limit exceeded"),
+            MailboxFull => write!(f, "Mailbox request queue is full"),
+            Unsupported => write!(f, "Not supported by this mailbox store"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+
+        match &self {
+            Redis(e) => Some(e),
+            Io(e) => Some(e),
+            SubscriptionClosed | RateLimited | MailboxFull | Unsupported => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::OnceLock;
+
+    use testcontainers::clients::Cli;
+    use testcontainers::Container;
+    use testcontainers_modules::redis::Redis;
+
+    use super::*;
+
+    const ID: &str = "subdirectory";
+
+    /// Start a redis server of the test's own, removed again once dropped, and return its
+    /// `host:port`
+    pub(crate) fn redis_server() -> (Container<'static, Redis>, String) {
+        static DOCKER: OnceLock<Cli> = OnceLock::new();
+        let server = DOCKER.get_or_init(Cli::default).run(Redis);
+        let host = format!("127.0.0.1:{}", server.get_host_port_ipv4(6379));
+        (server, host)
+    }
+
+    /// A pool for every backend, along with the redis server the redis pool uses
+    async fn pools(test_name: &str) -> (Vec<DbPool>, Container<'static, Redis>) {
+        let (redis, redis_host) = redis_server();
+        let timeout = Duration::from_millis(200);
+        let ttl = Duration::from_secs(60);
+        let dir = std::env::temp_dir().join(format!(
+            "payjoin-directory-{}-{}",
+            test_name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut pools = vec![];
+        for backend in [DbBackend::Memory, DbBackend::File(dir), DbBackend::Redis(redis_host)] {
+            let metrics = Metrics::new();
+            let limiter = RateLimiter::new(&Default::default(), metrics.clone());
+            let shutdown = ShutdownHandle::new();
+            pools.push(
+                DbPool::new(timeout, ttl, 2, backend, metrics, limiter, shutdown).await.unwrap(),
+            );
+        }
+        (pools, redis)
+    }
+
+    #[tokio::test]
+    async fn peek_returns_pushed_entry() {
+        let (pools, _redis) = pools("peek_returns_pushed_entry").await;
+        for pool in pools {
+            pool.push_req(ID, b"original".to_vec()).await.unwrap();
+            assert_eq!(pool.peek_req(ID).await.unwrap().unwrap(), b"original");
+            assert!(pool.peek_res(ID).await.is_none(), "response slot should be empty");
+            pool.delete(ID).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn peek_wakes_on_push() {
+        let (pools, _redis) = pools("peek_wakes_on_push").await;
+        for pool in pools {
+            let waiting = tokio::spawn({
+                let pool = pool.clone();
+                async move { pool.peek_res(ID).await }
+            });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            pool.push_res(ID, b"proposal".to_vec()).await.unwrap();
+            assert_eq!(waiting.await.unwrap().unwrap().unwrap(), b"proposal");
+            pool.delete(ID).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn peek_gives_up_on_shutdown() {
+        let timeout = Duration::from_secs(60);
+        let metrics = Metrics::new();
+        let limiter = RateLimiter::new(&Default::default(), metrics.clone());
+        let shutdown = ShutdownHandle::new();
+        let backend = DbBackend::Memory;
+        let pool = DbPool::new(timeout, timeout, 2, backend, metrics, limiter, shutdown.clone());
+        let pool = pool.await.unwrap();
+
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.peek_req(ID).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.shutdown();
+        let peeked = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap();
+        assert!(peeked.unwrap().is_none(), "answered like a timeout");
+        // and polls that start once shutting down don't wait at all
+        let peeked = tokio::time::timeout(Duration::from_secs(5), pool.peek_res(ID)).await;
+        assert!(peeked.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn requests_queue_instead_of_replacing_each_other() {
+        let (pools, _redis) = pools("requests_queue_instead_of_replacing_each_other").await;
+        for pool in pools {
+            pool.push_req(ID, b"first".to_vec()).await.unwrap();
+            pool.push_req(ID, b"second".to_vec()).await.unwrap();
+            pool.push_req(ID, b"first".to_vec()).await.expect("resending is harmless");
+            assert!(matches!(pool.push_req(ID, b"third".to_vec()).await, Err(Error::MailboxFull)));
+            assert_eq!(pool.peek_req(ID).await.unwrap().unwrap(), b"first");
+
+            pool.push_res(ID, b"proposal".to_vec()).await.unwrap();
+            pool.push_res(ID, b"replaced".to_vec()).await.unwrap();
+            assert_eq!(pool.peek_res(ID).await.unwrap().unwrap(), b"replaced");
+            pool.delete(ID).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn each_request_is_replied_to_in_its_own_slot() {
+        let (pools, _redis) = pools("each_request_is_replied_to_in_its_own_slot").await;
+        for pool in pools {
+            let (first, second) = (reply_id(b"first"), reply_id(b"second"));
+            pool.push_req(ID, b"first".to_vec()).await.unwrap();
+            pool.push_req(ID, b"second".to_vec()).await.unwrap();
+
+            pool.drop_req(ID, &second).await.expect("only the oldest request is dropped");
+            assert_eq!(pool.peek_req(ID).await.unwrap().unwrap(), b"first");
+            pool.push_reply(ID, &first, b"first proposal".to_vec()).await.unwrap();
+            assert_eq!(pool.peek_req(ID).await.unwrap().unwrap(), b"second");
+            assert!(pool.peek_reply(ID, &second).await.is_none());
+            assert_eq!(pool.get_reply(ID, &first).await.unwrap().unwrap(), b"first proposal");
+
+            pool.drop_req(ID, &second).await.unwrap();
+            assert!(pool.peek_req(ID).await.is_none(), "the queue should be empty");
+            pool.delete(ID).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_sees_mailbox_ages_and_purges_expired_entries() {
+        let (pools, _redis) = pools("admin_sees_mailbox_ages_and_purges_expired_entries").await;
+        // Redis drops expired entries itself, leaving nothing to purge
+        for (pool, purged) in pools.into_iter().zip([1, 1, 0]) {
+            pool.push_req(ID, b"original".to_vec()).await.unwrap();
+            pool.push_res(ID, b"proposal".to_vec()).await.unwrap();
+            let expired = channel_name("abandoned", REQ_COLUMN);
+            pool.store.set(&expired, b"original".to_vec(), Duration::ZERO).await.unwrap();
+
+            let ages = pool.mailbox_ages().await.unwrap();
+            assert_eq!(ages.len(), 1, "both columns belong to one mailbox");
+            assert!(ages[0] < Duration::from_secs(2));
+            assert_eq!(pool.purge_expired().await.unwrap(), purged);
+            assert_eq!(pool.purge_expired().await.unwrap(), 0);
+            assert!(pool.ping().await.is_ok());
+            pool.delete(ID).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn senders_cannot_use_up_the_receivers_limits() {
+        use crate::rate_limit::RateLimits;
+
+        let limits = RateLimits {
+            mailbox_writes_per_min: 1,
+            max_long_polls_per_mailbox: 1,
+            ..Default::default()
+        };
+        let metrics = Metrics::new();
+        let limiter = RateLimiter::new(&limits, metrics.clone());
+        let timeout = Duration::from_secs(5);
+        let ttl = Duration::from_secs(60);
+        let pool = DbPool::new(
+            timeout,
+            ttl,
+            2,
+            DbBackend::Memory,
+            metrics,
+            limiter,
+            ShutdownHandle::new(),
+        )
+        .await
+        .unwrap();
+
+        pool.push_req(ID, b"first".to_vec()).await.unwrap();
+        assert!(matches!(pool.push_req(ID, b"second".to_vec()).await, Err(Error::RateLimited)));
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.peek_res(ID).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let first = reply_id(b"first");
+        assert!(matches!(pool.peek_reply(ID, &first).await, Some(Err(Error::RateLimited))));
+
+        // the receiver still polls for and answers the request
+        assert_eq!(pool.peek_req(ID).await.unwrap().unwrap(), b"first");
+        pool.push_res(ID, b"proposal".to_vec()).await.unwrap();
+        assert_eq!(waiting.await.unwrap().unwrap().unwrap(), b"proposal");
+    }
+
+    #[tokio::test]
+    async fn reading_an_expired_entry_never_loses_a_concurrent_write() {
+        let (pools, _redis) =
+            pools("reading_an_expired_entry_never_loses_a_concurrent_write").await;
+        for pool in pools {
+            for i in 0..50 {
+                let key = channel_name(&format!("{}{}", ID, i), REQ_COLUMN);
+                pool.store.set(&key, b"stale".to_vec(), Duration::ZERO).await.unwrap();
+                let ttl = Duration::from_secs(60);
+                let (read, appended) = tokio::join!(
+                    pool.store.get(&key),
+                    pool.store.append(&key, b"fresh".to_vec(), ttl, 1)
+                );
+                assert_ne!(read.unwrap().as_deref(), Some(&b"stale"[..]));
+                appended.unwrap();
+                assert_eq!(pool.store.get(&key).await.unwrap().unwrap(), b"fresh");
+                pool.store.delete(&[key]).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn deleted_and_expired_entries_are_gone() {
+        let (pools, _redis) = pools("deleted_and_expired_entries_are_gone").await;
+        for pool in pools {
+            pool.push_req(ID, b"original".to_vec()).await.unwrap();
+            pool.push_reply(ID, &reply_id(b"original"), b"reply".to_vec()).await.unwrap();
+            pool.push_reply("other", &reply_id(b"original"), b"kept".to_vec()).await.unwrap();
+            pool.delete(ID).await.unwrap();
+            assert!(pool.peek_req(ID).await.is_none());
+            assert_eq!(pool.get_reply(ID, &reply_id(b"original")).await.unwrap(), None);
+            assert!(pool.get_reply("other", &reply_id(b"original")).await.unwrap().is_some());
+            pool.delete("other").await.unwrap();
+
+            let key = channel_name(ID, REQ_COLUMN);
+            pool.store.set(&key, b"original".to_vec(), Duration::ZERO).await.unwrap();
+            assert_eq!(pool.store.get(&key).await.unwrap(), None);
+
+            // an expired queue that wasn't swept yet holds nothing back
+            pool.store.append(&key, b"stale".to_vec(), Duration::ZERO, 1).await.unwrap();
+            pool.store.append(&key, b"fresh".to_vec(), Duration::from_secs(60), 1).await.unwrap();
+            assert_eq!(pool.store.get(&key).await.unwrap().unwrap(), b"fresh");
+            pool.delete(ID).await.unwrap();
+        }
+    }
+}
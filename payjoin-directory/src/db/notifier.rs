@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+use super::{Error, Subscription};
+
+/// Fans write notifications out to the tasks waiting on each key.
+///
+/// Each key with waiters has its own channel, so a write only wakes the tasks waiting on
+/// that key. Notifications are coalesced: a waiter that misses some still wakes once and
+/// re-checks its key. Keys are forgotten when their last waiter goes away.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Notifier(Arc<Mutex<HashMap<String, watch::Sender<()>>>>);
+
+impl Notifier {
+    pub(super) fn new() -> Self { Self::default() }
+
+    pub(super) fn notify(&self, key: &str) {
+        if let Some(waiters) = self.0.lock().expect("poisoned").get(key) {
+            waiters.send_replace(());
+        }
+    }
+
+    /// Wake every waiter, e.g. after notifications may have been lost
+    pub(super) fn notify_all(&self) {
+        for waiters in self.0.lock().expect("poisoned").values() {
+            waiters.send_replace(());
+        }
+    }
+
+    pub(super) fn subscribe(&self, key: &str) -> LocalSubscription {
+        let updates = self
+            .0
+            .lock()
+            .expect("poisoned")
+            .entry(key.to_owned())
+            .or_insert_with(|| watch::channel(()).0)
+            .subscribe();
+        LocalSubscription { key: key.to_owned(), updates, notifier: self.clone() }
+    }
+
+    #[cfg(test)]
+    fn keys(&self) -> usize { self.0.lock().expect("poisoned").len() }
+}
+
+pub(super) struct LocalSubscription {
+    key: String,
+    updates: watch::Receiver<()>,
+    notifier: Notifier,
+}
+
+#[async_trait]
+impl Subscription for LocalSubscription {
+    async fn changed(&mut self) -> Result<(), Error> {
+        self.updates.changed().await.map_err(|_| Error::SubscriptionClosed)
+    }
+}
+
+impl Drop for LocalSubscription {
+    fn drop(&mut self) {
+        let mut channels = self.notifier.0.lock().expect("poisoned");
+        // Our own receiver is still alive until this returns
+        if channels.get(&self.key).is_some_and(|waiters| waiters.receiver_count() <= 1) {
+            channels.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn only_waiters_on_the_written_key_wake() {
+        let notifier = Notifier::new();
+        let mut a = notifier.subscribe("a");
+        let mut b = notifier.subscribe("b");
+        notifier.notify("a");
+        a.changed().await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(20), b.changed()).await.is_err());
+
+        drop((a, b));
+        assert_eq!(notifier.keys(), 0, "keys without waiters are forgotten");
+    }
+}
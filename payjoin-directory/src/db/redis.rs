@@ -0,0 +1,339 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use redis::aio::{ConnectionManager, PubSub};
+use redis::{AsyncCommands, Client, Script};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::notifier::Notifier;
+use super::{Error, MailboxStore, Subscription};
+
+const LEGACY_ID_LEN: usize = 8;
+
+/// Every mailbox key ends in its column, `req`, `res` or a `res-` reply slot
+const MAILBOX_CHANNELS: &str = "*:re[qs]*";
+
+/// How long to wait before reconnecting a dropped pubsub connection
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Read a single entry, or the oldest of a queue
+const GET_SCRIPT: &str = r"
+if redis.call('TYPE', KEYS[1]).ok == 'list' then
+    return redis.call('LINDEX', KEYS[1], 0)
+end
+return redis.call('GET', KEYS[1])
+";
+
+/// Queue ARGV[1] under KEYS[1] unless it is already queued, expiring the queue ARGV[2]
+/// milliseconds later. Returns 0 without queueing if ARGV[3] entries are already queued.
+const APPEND_SCRIPT: &str = r"
+if redis.call('TYPE', KEYS[1]).ok == 'string' then
+    -- Requests written before they were queued were single entries
+    local entry = redis.call('GET', KEYS[1])
+    redis.call('DEL', KEYS[1])
+    redis.call('RPUSH', KEYS[1], entry)
+end
+local queued = redis.call('LRANGE', KEYS[1], 0, -1)
+for _, entry in ipairs(queued) do
+    if entry == ARGV[1] then
+        return 1
+    end
+end
+if #queued >= tonumber(ARGV[3]) then
+    return 0
+end
+redis.call('RPUSH', KEYS[1], ARGV[1])
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+redis.call('PUBLISH', KEYS[1], 'updated')
+return 1
+";
+
+/// Remove the oldest entry queued under KEYS[1] if it equals ARGV[1]
+const DEQUEUE_SCRIPT: &str = r"
+if redis.call('TYPE', KEYS[1]).ok == 'list' and redis.call('LINDEX', KEYS[1], 0) == ARGV[1] then
+    redis.call('LPOP', KEYS[1])
+    redis.call('PUBLISH', KEYS[1], 'updated')
+end
+return 1
+";
+
+/// Keeps mailboxes in redis and wakes long-polls through redis pubsub.
+///
+/// Commands from every request share one multiplexed connection that reconnects on its
+/// own. A single pubsub connection listens for writes to any mailbox, including writes by
+/// other directories sharing the redis server, and wakes the local tasks waiting on the
+/// written key.
+pub struct RedisStore {
+    conn: ConnectionManager,
+    notifier: Notifier,
+    listener: JoinHandle<()>,
+    get_script: Script,
+    append_script: Script,
+    dequeue_script: Script,
+}
+
+impl RedisStore {
+    /// Connect to the redis server at `db_host`, failing if it can't be reached
+    pub async fn connect(db_host: &str) -> Result<Self, Error> {
+        let client = Client::open(format!("redis://{}", db_host))?;
+        let mut conn = client.get_tokio_connection_manager().await?;
+        Self::drop_legacy_entries(&mut conn).await?;
+        // Subscribe before serving so no write made after startup goes unnoticed
+        let pubsub = subscribe_to_mailboxes(&client).await?;
+        let notifier = Notifier::new();
+        let listener = tokio::spawn(forward_notifications(client, pubsub, notifier.clone()));
+        Ok(Self {
+            conn,
+            notifier,
+            listener,
+            get_script: Script::new(GET_SCRIPT),
+            append_script: Script::new(APPEND_SCRIPT),
+            dequeue_script: Script::new(DEQUEUE_SCRIPT),
+        })
+    }
+
+    /// Drop every entry stored under a legacy 8-character shortened id.
+    ///
+    /// Directories used to key mailboxes by the first 8 characters of the subdirectory id,
+    /// so any subdirectory sharing those characters could read the entry. Nothing proves
+    /// which full id such an entry was written for, so it is dropped rather than adopted.
+    /// Nothing writes these keys anymore, so one sweep at startup clears them for good.
+    // TODO remove once directories have been running with full ids for longer than a session
+    async fn drop_legacy_entries(conn: &mut ConnectionManager) -> Result<(), Error> {
+        let mut legacy_keys = vec![];
+        {
+            let mut scan = conn.scan_match::<_, String>(MAILBOX_CHANNELS).await?;
+            while let Some(key) = scan.next_item().await {
+                if key.split_once(':').is_some_and(|(id, _)| id.len() == LEGACY_ID_LEN) {
+                    legacy_keys.push(key);
+                }
+            }
+        }
+        if !legacy_keys.is_empty() {
+            conn.del::<_, ()>(&legacy_keys).await?;
+            debug!("Dropped {} legacy mailbox entries", legacy_keys.len());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MailboxStore for RedisStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.get_script.key(key).invoke_async(&mut self.conn.clone()).await?)
+    }
+
+    async fn set(&self, key: &str, data: Vec<u8>, ttl: Duration) -> Result<(), Error> {
+        redis::pipe()
+            .atomic()
+            .set(key, data)
+            .pexpire(key, ttl_millis(ttl))
+            .publish(key, "updated")
+            .query_async::<_, ()>(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn append(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        ttl: Duration,
+        max_len: usize,
+    ) -> Result<(), Error> {
+        let queued: bool = self
+            .append_script
+            .key(key)
+            .arg(data)
+            .arg(ttl_millis(ttl))
+            .arg(max_len)
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        queued.then_some(()).ok_or(Error::MailboxFull)
+    }
+
+    async fn dequeue(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        self.dequeue_script
+            .key(key)
+            .arg(data)
+            .invoke_async::<_, ()>(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<(), Error> {
+        let mut conn = self.conn.clone();
+        conn.del::<_, ()>(keys).await?;
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut conn = self.conn.clone();
+        let mut keys = vec![];
+        // Mailbox ids and reply ids are base64url, so the prefix holds no glob characters
+        let mut scan = conn.scan_match::<_, String>(format!("{}*", prefix)).await?;
+        while let Some(key) = scan.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    async fn subscribe(&self, key: &str) -> Result<Box<dyn Subscription>, Error> {
+        Ok(Box::new(self.notifier.subscribe(key)))
+    }
+
+    async fn expirations(&self) -> Result<Vec<(String, Duration)>, Error> {
+        let mut conn = self.conn.clone();
+        let mut keys = vec![];
+        {
+            let mut scan = conn.scan_match::<_, String>(MAILBOX_CHANNELS).await?;
+            while let Some(key) = scan.next_item().await {
+                keys.push(key);
+            }
+        }
+        let mut pttls = redis::pipe();
+        for key in &keys {
+            pttls.pttl(key);
+        }
+        let pttls: Vec<i64> = pttls.query_async(&mut conn).await?;
+        // Negative for keys that expired since the scan, or legacy ones that never expire
+        Ok(keys
+            .into_iter()
+            .zip(pttls)
+            .filter_map(|(key, pttl)| Some((key, Duration::from_millis(pttl.try_into().ok()?))))
+            .collect())
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        Ok(redis::cmd("PING").query_async(&mut self.conn.clone()).await?)
+    }
+}
+
+impl Drop for RedisStore {
+    fn drop(&mut self) { self.listener.abort() }
+}
+
+impl std::fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RedisStore").finish_non_exhaustive()
+    }
+}
+
+async fn subscribe_to_mailboxes(client: &Client) -> Result<PubSub, Error> {
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    pubsub.psubscribe(MAILBOX_CHANNELS).await?;
+    Ok(pubsub)
+}
+
+/// Wake local waiters on every mailbox write published to redis, reconnecting for as long
+/// as the store lives
+async fn forward_notifications(client: Client, mut pubsub: PubSub, notifier: Notifier) {
+    loop {
+        let mut messages = pubsub.into_on_message();
+        while let Some(msg) = messages.next().await {
+            notifier.notify(msg.get_channel_name());
+        }
+        warn!("Lost the redis pubsub connection, resubscribing");
+        pubsub = loop {
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            match subscribe_to_mailboxes(&client).await {
+                Ok(pubsub) => break pubsub,
+                Err(e) => warn!("Failed to resubscribe to redis: {}", e),
+            }
+        };
+        // Writes made while disconnected went unannounced, so have every waiter re-check
+        notifier.notify_all();
+    }
+}
+
+/// Redis expiries in milliseconds. An expiry of zero deletes the key at once, like an entry
+/// that expired before it could be read.
+fn ttl_millis(ttl: Duration) -> usize { ttl.as_millis().try_into().unwrap_or(usize::MAX) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::test::redis_server;
+
+    const WAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn connection(host: &str) -> ConnectionManager {
+        Client::open(format!("redis://{}", host))
+            .unwrap()
+            .get_tokio_connection_manager()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn redis_writes_by_other_directories_wake_subscribers() {
+        let (_redis, host) = redis_server();
+        let store = RedisStore::connect(&host).await.unwrap();
+        let other = RedisStore::connect(&host).await.unwrap();
+        let ttl = Duration::from_secs(60);
+
+        let mut req = store.subscribe("mailbox:req").await.unwrap();
+        let mut res = store.subscribe("mailbox:res").await.unwrap();
+        let mut reply = store.subscribe("mailbox:res-digest").await.unwrap();
+        other.append("mailbox:req", b"request".to_vec(), ttl, 2).await.unwrap();
+        tokio::time::timeout(WAKE_TIMEOUT, req.changed()).await.unwrap().unwrap();
+        other.set("mailbox:res", b"proposal".to_vec(), ttl).await.unwrap();
+        tokio::time::timeout(WAKE_TIMEOUT, res.changed()).await.unwrap().unwrap();
+        other.set("mailbox:res-digest", b"reply".to_vec(), ttl).await.unwrap();
+        tokio::time::timeout(WAKE_TIMEOUT, reply.changed()).await.unwrap().unwrap();
+        other.dequeue("mailbox:req", b"request").await.unwrap();
+        tokio::time::timeout(WAKE_TIMEOUT, req.changed()).await.unwrap().unwrap();
+
+        let mut unrelated = store.subscribe("other:req").await.unwrap();
+        other.append("mailbox:req", b"request".to_vec(), ttl, 2).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(200), unrelated.changed())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn redis_subscribers_wake_after_the_pubsub_connection_drops() {
+        let (_redis, host) = redis_server();
+        let store = RedisStore::connect(&host).await.unwrap();
+        let ttl = Duration::from_secs(60);
+
+        let mut sub = store.subscribe("mailbox:res").await.unwrap();
+        redis::cmd("CLIENT")
+            .arg("KILL")
+            .arg("TYPE")
+            .arg("pubsub")
+            .query_async::<_, ()>(&mut connection(&host).await)
+            .await
+            .unwrap();
+        // Written while nobody listens, so only the re-check after resubscribing finds it
+        store.set("mailbox:res", b"proposal".to_vec(), ttl).await.unwrap();
+        tokio::time::timeout(WAKE_TIMEOUT, sub.changed()).await.unwrap().unwrap();
+        assert_eq!(store.get("mailbox:res").await.unwrap().unwrap(), b"proposal");
+
+        // and writes after resubscribing are announced again
+        store.set("mailbox:res", b"another".to_vec(), ttl).await.unwrap();
+        tokio::time::timeout(WAKE_TIMEOUT, sub.changed()).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn redis_legacy_entries_are_dropped_on_connect() {
+        let (_redis, host) = redis_server();
+        let mut conn = connection(&host).await;
+        let legacy = ["abcdefgh:req", "abcdefgh:res", "abcdefgh:res-digest"];
+        let current = ["abcdefghijklmnop:req", "abcdefghijklmnop:res-digest"];
+        for key in legacy.iter().chain(&current) {
+            conn.set::<_, _, ()>(key, "entry").await.unwrap();
+        }
+        conn.set::<_, _, ()>("unrelated", "entry").await.unwrap();
+
+        let _store = RedisStore::connect(&host).await.unwrap();
+        for key in legacy {
+            assert!(!conn.exists::<_, bool>(key).await.unwrap(), "{} should be dropped", key);
+        }
+        for key in current.iter().chain(&["unrelated"]) {
+            assert!(conn.exists::<_, bool>(key).await.unwrap(), "{} should be kept", key);
+        }
+    }
+}
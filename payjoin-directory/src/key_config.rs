@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use bitcoin::base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use bitcoin::base64::Engine;
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use chunked_ohttp::{PrivateKey, RequestDecoder};
+use ohttp::hpke::{Aead, Kdf, Kem};
+use ohttp::{KeyId, SymmetricSuite};
+use tracing::info;
+
+const KEM: Kem = Kem::X25519Sha256;
+/// Advertised in order of preference. ChaCha20Poly1305 stays first because older clients
+/// always encapsulate to the first suite of a configuration.
+const SYMMETRIC: &[SymmetricSuite] = &[
+    SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305),
+    SymmetricSuite::new(Kdf::HkdfSha256, Aead::Aes128Gcm),
+];
+
+const IKM_LEN: usize = 32;
+/// key id, creation time as big-endian unix seconds, input keying material
+const RECORD_LEN: usize = 1 + 8 + IKM_LEN;
+
+/// The OHTTP gateway keys a directory decapsulates requests with.
+///
+/// The newest key is the one advertised at `/ohttp-keys`. When it is rotated out, older keys
+/// keep decapsulating for a grace period so `pj=` URIs that embed them keep working, and are
+/// listed after it so senders still recognize them.
+///
+/// Only input keying material is persisted. Each key is re-derived from it on load, which
+/// lets a restarted directory accept the same keys it handed out before.
+pub struct GatewayKeys {
+    path: Option<PathBuf>,
+    grace: Duration,
+    /// Oldest first, so the last key is the current one
+    keys: Vec<StoredKey>,
+    servers: BTreeMap<KeyId, ohttp::Server>,
+    /// The same keys again, for opening chunked requests
+    private_keys: BTreeMap<KeyId, PrivateKey>,
+}
+
+#[derive(Clone)]
+struct StoredKey {
+    key_id: KeyId,
+    created_at: u64,
+    ikm: [u8; IKM_LEN],
+}
+
+impl GatewayKeys {
+    /// Generate a single key that only lives as long as the process.
+    pub fn ephemeral() -> Result<Self> {
+        let mut keys = Self {
+            path: None,
+            grace: Duration::ZERO,
+            keys: vec![],
+            servers: BTreeMap::new(),
+            private_keys: BTreeMap::new(),
+        };
+        keys.rotate()?;
+        Ok(keys)
+    }
+
+    /// Load keys from `path`, creating a first key if the file doesn't exist yet.
+    ///
+    /// Keys that were rotated out longer than `grace` ago are dropped.
+    pub fn load_or_create(path: impl Into<PathBuf>, grace: Duration) -> Result<Self> {
+        let path = path.into();
+        let keys = match std::fs::read(&path) {
+            Ok(bytes) => decode_records(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        let mut gateway_keys = Self {
+            path: Some(path),
+            grace,
+            keys: vec![],
+            servers: BTreeMap::new(),
+            private_keys: BTreeMap::new(),
+        };
+        for key in keys {
+            gateway_keys.insert(key)?;
+        }
+        if gateway_keys.keys.is_empty() {
+            gateway_keys.rotate()?;
+        } else {
+            gateway_keys.prune_and_save()?;
+            gateway_keys.log_current();
+        }
+        Ok(gateway_keys)
+    }
+
+    /// Make a new key current, keeping the previous ones for the grace period.
+    pub fn rotate(&mut self) -> Result<KeyId> {
+        let key_id = self.next_key_id()?;
+        let mut ikm = [0u8; IKM_LEN];
+        thread_rng().fill_bytes(&mut ikm);
+        self.insert(StoredKey { key_id, created_at: unix_now(), ikm })?;
+        self.prune_and_save()?;
+        self.log_current();
+        Ok(key_id)
+    }
+
+    /// The configuration of the current key, advertised to clients.
+    pub fn current_config(&self) -> &ohttp::KeyConfig {
+        let current = self.keys.last().expect("at least one key");
+        self.servers[&current.key_id].config()
+    }
+
+    /// The `application/ohttp-keys` body served at `/ohttp-keys`: the current configuration
+    /// alone, as clients that decode it with `KeyConfig::decode` expect.
+    pub fn encode(&self) -> Result<Vec<u8>> { Ok(self.current_config().encode()?) }
+
+    /// The `application/ohttp-keys` body served at `/ohttp-keys?format=list`: an RFC 9458
+    /// list of every active configuration, the current one first and then newest to oldest.
+    pub fn encode_list(&self) -> Result<Vec<u8>> {
+        let now = unix_now();
+        let active: Vec<&ohttp::KeyConfig> = (0..self.keys.len())
+            .rev()
+            .filter(|&i| !self.is_expired(i, now))
+            .map(|i| self.servers[&self.keys[i].key_id].config())
+            .collect();
+        Ok(ohttp::KeyConfig::encode_list(&active)?)
+    }
+
+    /// Decapsulate a request with whichever active key it was encapsulated to.
+    pub fn decapsulate(&self, enc_request: &[u8]) -> Result<(Vec<u8>, ohttp::ServerResponse)> {
+        let key_id = *enc_request.first().ok_or(ohttp::Error::Truncated)?;
+        let position = self.keys.iter().position(|key| key.key_id == key_id);
+        let active = position.is_some_and(|i| !self.is_expired(i, unix_now()));
+        if !active {
+            return Err(ohttp::Error::KeyId.into());
+        }
+        Ok(self.servers[&key_id].decapsulate(enc_request)?)
+    }
+
+    /// Start opening a chunked request encapsulated to any key that is active now, refusing it
+    /// once its plaintext grows past `max_len` bytes.
+    pub(crate) fn chunked_request_decoder(&self, max_len: usize) -> RequestDecoder {
+        let now = unix_now();
+        let active = self
+            .keys
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| !self.is_expired(i, now))
+            .map(|(_, key)| (key.key_id, self.private_keys[&key.key_id].clone()))
+            .collect();
+        RequestDecoder::new(active, max_len)
+    }
+
+    fn insert(&mut self, key: StoredKey) -> Result<()> {
+        let config = ohttp::KeyConfig::derive(key.key_id, KEM, Vec::from(SYMMETRIC), &key.ikm)?;
+        self.servers.insert(key.key_id, ohttp::Server::new(config)?);
+        self.private_keys.insert(key.key_id, PrivateKey::derive(&key.ikm));
+        self.keys.push(key);
+        Ok(())
+    }
+
+    fn next_key_id(&self) -> Result<KeyId> {
+        let start = self.keys.last().map_or(1, |key| key.key_id.wrapping_add(1));
+        (0..=KeyId::MAX)
+            .map(|offset| start.wrapping_add(offset))
+            .find(|id| !self.servers.contains_key(id))
+            .ok_or_else(|| anyhow!("Every OHTTP key id is in use"))
+    }
+
+    /// A key expires `grace` after the key that replaced it was created
+    fn is_expired(&self, index: usize, now: u64) -> bool {
+        match self.keys.get(index + 1) {
+            Some(successor) => successor.created_at.saturating_add(self.grace.as_secs()) <= now,
+            None => false,
+        }
+    }
+
+    fn prune_and_save(&mut self) -> Result<()> {
+        let now = unix_now();
+        let expired: Vec<usize> =
+            (0..self.keys.len()).filter(|&i| self.is_expired(i, now)).collect();
+        for i in expired.into_iter().rev() {
+            let key = self.keys.remove(i);
+            self.servers.remove(&key.key_id);
+            self.private_keys.remove(&key.key_id);
+            info!("Retired OHTTP key id {}", key.key_id);
+        }
+        match &self.path {
+            Some(path) => write_records(path, &self.keys),
+            None => Ok(()),
+        }
+    }
+
+    fn log_current(&self) {
+        match self.current_config().encode() {
+            Ok(encoded) => info!(
+                "ohttp-keys server config base64 UrlSafe: {:?}",
+                BASE64_URL_SAFE_NO_PAD.encode(encoded)
+            ),
+            Err(e) => tracing::error!("Failed to encode ohttp-keys: {}", e),
+        }
+    }
+}
+
+fn decode_records(bytes: &[u8]) -> Result<Vec<StoredKey>> {
+    let records = bytes.chunks_exact(RECORD_LEN);
+    if !records.remainder().is_empty() {
+        return Err(anyhow!("OHTTP key file is corrupt"));
+    }
+    Ok(records
+        .map(|record| StoredKey {
+            key_id: record[0],
+            created_at: u64::from_be_bytes(record[1..9].try_into().expect("8 bytes")),
+            ikm: record[9..].try_into().expect("IKM_LEN bytes"),
+        })
+        .collect())
+}
+
+/// Replace the key file atomically so a crash never leaves it half written
+fn write_records(path: &Path, keys: &[StoredKey]) -> Result<()> {
+    use std::io::Write;
+
+    let mut bytes = Vec::with_capacity(keys.len() * RECORD_LEN);
+    for key in keys {
+        bytes.push(key.key_id);
+        bytes.extend(key.created_at.to_be_bytes());
+        bytes.extend(key.ikm);
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn unix_now() -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key_file(test_name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "payjoin-directory-{}-{}.keys",
+            test_name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Encapsulate a request the way a client that fetched `config` would
+    fn encapsulate(config: &ohttp::KeyConfig) -> Vec<u8> {
+        let mut public = ohttp::KeyConfig::decode(&config.encode().unwrap()).unwrap();
+        let (enc_request, _) =
+            ohttp::ClientRequest::from_config(&mut public).unwrap().encapsulate(b"req").unwrap();
+        enc_request
+    }
+
+    #[test]
+    fn keys_survive_restart() {
+        let path = key_file("keys_survive_restart");
+        let keys = GatewayKeys::load_or_create(&path, Duration::ZERO).unwrap();
+        let enc_request = encapsulate(keys.current_config());
+
+        let reloaded = GatewayKeys::load_or_create(&path, Duration::ZERO).unwrap();
+        assert_eq!(
+            keys.current_config().encode().unwrap(),
+            reloaded.current_config().encode().unwrap()
+        );
+        assert_eq!(reloaded.decapsulate(&enc_request).unwrap().0, b"req");
+    }
+
+    #[test]
+    fn rotated_keys_decapsulate_during_grace_period() {
+        let path = key_file("rotated_keys_decapsulate_during_grace_period");
+        let mut keys = GatewayKeys::load_or_create(&path, Duration::from_secs(3600)).unwrap();
+        let old_request = encapsulate(keys.current_config());
+        let old_id = keys.current_config().encode().unwrap()[0];
+
+        let new_id = keys.rotate().unwrap();
+        assert_ne!(old_id, new_id);
+        assert_eq!(keys.current_config().encode().unwrap()[0], new_id);
+        assert_eq!(keys.decapsulate(&old_request).unwrap().0, b"req");
+        assert_eq!(keys.decapsulate(&encapsulate(keys.current_config())).unwrap().0, b"req");
+        // both are listed, the current one first
+        let listed: Vec<u8> = ohttp::KeyConfig::decode_list(&keys.encode_list().unwrap())
+            .unwrap()
+            .iter()
+            .map(|config| config.encode().unwrap()[0])
+            .collect();
+        assert_eq!(listed, [new_id, old_id]);
+
+        let reloaded = GatewayKeys::load_or_create(&path, Duration::from_secs(3600)).unwrap();
+        assert_eq!(reloaded.decapsulate(&old_request).unwrap().0, b"req");
+    }
+
+    #[test]
+    fn keys_are_advertised_as_a_list_offering_every_suite() {
+        let keys = GatewayKeys::ephemeral().unwrap();
+        let configs = ohttp::KeyConfig::decode_list(&keys.encode_list().unwrap()).unwrap();
+        assert_eq!(configs.len(), 1);
+        let encoded = configs[0].encode().unwrap();
+        assert_eq!(encoded, keys.current_config().encode().unwrap());
+        // HKDF-SHA256 with ChaCha20Poly1305, then with AES-128-GCM
+        assert_eq!(encoded[encoded.len() - 10..], [0, 8, 0, 1, 0, 3, 0, 1, 0, 1]);
+
+        // a client that only speaks AES-128-GCM is still served
+        let aes_only = [&encoded[..encoded.len() - 10], &[0, 4, 0, 1, 0, 1]].concat();
+        let mut aes_only = ohttp::KeyConfig::decode(&aes_only).unwrap();
+        let (enc_request, _) =
+            ohttp::ClientRequest::from_config(&mut aes_only).unwrap().encapsulate(b"req").unwrap();
+        assert_eq!(keys.decapsulate(&enc_request).unwrap().0, b"req");
+    }
+
+    #[test]
+    fn rotated_keys_expire_after_grace_period() {
+        let path = key_file("rotated_keys_expire_after_grace_period");
+        let mut keys = GatewayKeys::load_or_create(&path, Duration::ZERO).unwrap();
+        let old_request = encapsulate(keys.current_config());
+        keys.rotate().unwrap();
+        assert!(keys.decapsulate(&old_request).is_err());
+        assert_eq!(keys.keys.len(), 1);
+        assert_eq!(ohttp::KeyConfig::decode_list(&keys.encode_list().unwrap()).unwrap().len(), 1);
+    }
+}
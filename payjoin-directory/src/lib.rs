@@ -1,152 +1,365 @@
+use std::convert::Infallible;
+use std::future::Future;
 use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
 use anyhow::Result;
 use bitcoin::base64::prelude::BASE64_URL_SAFE_NO_PAD;
 use bitcoin::base64::Engine;
-use hyper::header::{HeaderValue, ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_TYPE};
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, Server, StatusCode, Uri};
+use futures::future::{BoxFuture, OptionFuture};
+use futures::FutureExt;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use hyper_util::service::TowerToHyperService;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
+use tower::ServiceBuilder;
+use tower_http::add_extension::AddExtension;
+use tower_http::cors::{Any, CorsLayer};
+use tower_http::trace::TraceLayer;
 use tracing::{debug, error, info, trace};
 
 pub const DEFAULT_DIR_PORT: u16 = 8080;
 pub const DEFAULT_DB_HOST: &str = "localhost:6379";
+pub const DEFAULT_DB_PATH: &str = "payjoin-directory-db";
 pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_MAILBOX_TTL_SECS: u64 = 60 * 60 * 24;
+pub const DEFAULT_OHTTP_KEYS_PATH: &str = "ohttp-keys";
+pub const DEFAULT_OHTTP_KEY_GRACE_SECS: u64 = 60 * 60 * 24 * 7;
 
-const MAX_BUFFER_SIZE: usize = 65536;
+/// Fits the largest v2 message, a 448KiB padding bucket plus its encryption overhead
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 512 * 1024;
+pub const DEFAULT_MAX_QUEUED_REQUESTS: usize = 8;
+
+/// Room for the method, target and headers a bhttp request carries around its payload
+const MAX_BHTTP_OVERHEAD: usize = 4096;
 
 const V1_REJECT_RES_JSON: &str =
     r#"{{"errorCode": "original-psbt-rejected ", "message": "Body is not a string"}}"#;
 const V1_UNAVAILABLE_RES_JSON: &str = r#"{{"errorCode": "unavailable", "message": "V2 receiver offline. V1 sends require synchronous communications."}}"#;
 
-mod db;
+mod admin;
+mod auth;
+pub mod db;
+mod key_config;
+mod metrics;
+mod rate_limit;
+#[cfg(feature = "tls")]
+mod relay;
+mod server;
+mod shutdown;
+use chunked_ohttp::{CHUNKED_REQ_CONTENT_TYPE, CHUNKED_RES_CONTENT_TYPE};
+
+pub use crate::admin::{AdminConfig, AdminToken};
+pub use crate::db::DbBackend;
 use crate::db::DbPool;
+pub use crate::key_config::GatewayKeys;
+use crate::metrics::Metrics;
+pub use crate::rate_limit::RateLimits;
+use crate::rate_limit::{BufferError, RateLimiter};
+#[cfg(feature = "tls")]
+pub use crate::relay::{listen_relay, RelayConfig};
+pub use crate::shutdown::ShutdownHandle;
 
-pub async fn listen_tcp(
-    port: u16,
-    db_host: String,
-    timeout: Duration,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let pool = DbPool::new(timeout, db_host).await?;
-    let ohttp = Arc::new(Mutex::new(init_ohttp()?));
-    let make_svc = make_service_fn(|_| {
-        let pool = pool.clone();
-        let ohttp = ohttp.clone();
-        async move {
-            let handler = move |req| handle_ohttp_gateway(req, pool.clone(), ohttp.clone());
-            Ok::<_, hyper::Error>(service_fn(handler))
+/// Settings for a directory server
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The address to accept connections on
+    pub bind_addr: SocketAddr,
+    /// How long a poll waits for its counterparty before the directory answers
+    pub timeout: Duration,
+    /// How long a mailbox entry lives after its last write
+    pub mailbox_ttl: Duration,
+    /// The largest payload a client may store in a mailbox
+    pub max_payload_size: usize,
+    /// How many distinct sender requests a mailbox holds. The receiver is served the oldest
+    pub max_queued_requests: usize,
+    pub db_backend: DbBackend,
+    pub rate_limits: RateLimits,
+    /// Serve the admin API too, if set
+    pub admin: Option<AdminConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), DEFAULT_DIR_PORT),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            mailbox_ttl: Duration::from_secs(DEFAULT_MAILBOX_TTL_SECS),
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            max_queued_requests: DEFAULT_MAX_QUEUED_REQUESTS,
+            db_backend: DbBackend::Redis(DEFAULT_DB_HOST.to_string()),
+            rate_limits: RateLimits::default(),
+            admin: None,
         }
-    });
+    }
+}
 
-    // Parse the bind address using the provided port
-    let bind_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
-    let server = Server::bind(&bind_addr).serve(make_svc);
-    info!("Payjoin Directory awaiting HTTP connection at {}", bind_addr);
-    Ok(server.await?)
+/// Serve the directory until `shutdown` is triggered and in-flight requests are answered.
+///
+/// Requests are traced and CORS requests are allowed from any origin. Mount a [`Directory`]
+/// in another server to choose different layers.
+pub async fn listen_tcp(
+    config: Config,
+    ohttp_keys: GatewayKeys,
+    shutdown: ShutdownHandle,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (bind_addr, admin) = (config.bind_addr, config.admin.clone());
+    let directory = Directory::new(config, ohttp_keys, shutdown.clone()).await?;
+    let handshake = |stream| async { Ok(stream) };
+    let (_, serving) = bind(directory, bind_addr, admin, handshake, shutdown).await?;
+    serving.await;
+    Ok(())
 }
 
-#[cfg(feature = "danger-local-https")]
+/// Serve over TLS with a DER encoded certificate chain, leaf first, and private key, until
+/// `shutdown` is triggered and in-flight requests are answered
+#[cfg(feature = "tls")]
 pub async fn listen_tcp_with_tls(
-    port: u16,
-    db_host: String,
-    timeout: Duration,
-    tls_config: (Vec<u8>, Vec<u8>),
+    config: Config,
+    ohttp_keys: GatewayKeys,
+    tls_config: (Vec<Vec<u8>>, Vec<u8>),
+    shutdown: ShutdownHandle,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let pool = DbPool::new(timeout, db_host).await?;
-    let ohttp = Arc::new(Mutex::new(init_ohttp()?));
-    let make_svc = make_service_fn(|_| {
-        let pool = pool.clone();
-        let ohttp = ohttp.clone();
-        async move {
-            let handler = move |req| handle_ohttp_gateway(req, pool.clone(), ohttp.clone());
-            Ok::<_, hyper::Error>(service_fn(handler))
-        }
-    });
-
-    // Parse the bind address using the provided port
-    let bind_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
-    let server = init_tls_server(&bind_addr, tls_config)?.serve(make_svc);
-    info!("Payjoin Directory awaiting HTTP connection at {}", bind_addr);
-    Ok(server.await?)
-}
-
-#[cfg(feature = "danger-local-https")]
-fn init_tls_server(
-    bind_addr: &SocketAddr,
-    cert_key: (Vec<u8>, Vec<u8>),
-) -> Result<hyper::server::Builder<hyper_rustls::TlsAcceptor>> {
-    use hyper::server::conn::AddrIncoming;
-
-    let (cert, key) = cert_key;
-    let cert = rustls::Certificate(cert);
-    let key = rustls::PrivateKey(key);
-    let incoming = AddrIncoming::bind(bind_addr)?;
-    let acceptor = hyper_rustls::TlsAcceptor::builder()
-        .with_single_cert(vec![cert], key)
-        .map_err(|e| anyhow::anyhow!("TLS error: {}", e))?
-        .with_all_versions_alpn()
-        .with_incoming(incoming);
-    Ok(Server::builder(acceptor))
-}
-
-fn init_ohttp() -> Result<ohttp::Server> {
-    use ohttp::hpke::{Aead, Kdf, Kem};
-    use ohttp::{KeyId, SymmetricSuite};
-
-    const KEY_ID: KeyId = 1;
-    const KEM: Kem = Kem::X25519Sha256;
-    const SYMMETRIC: &[SymmetricSuite] =
-        &[SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305)];
-
-    // create or read from file
-    let server_config = ohttp::KeyConfig::new(KEY_ID, KEM, Vec::from(SYMMETRIC))?;
-    let encoded_config = server_config.encode()?;
-    let b64_config = BASE64_URL_SAFE_NO_PAD.encode(encoded_config);
-    info!("ohttp-keys server config base64 UrlSafe: {:?}", b64_config);
-    Ok(ohttp::Server::new(server_config)?)
-}
-
-async fn handle_ohttp_gateway(
-    req: Request<Body>,
+    let (bind_addr, admin) = (config.bind_addr, config.admin.clone());
+    let acceptor = init_tls_acceptor(tls_config)?;
+    let directory = Directory::new(config, ohttp_keys, shutdown.clone()).await?;
+    let handshake = move |stream| acceptor.accept(stream);
+    let (_, serving) = bind(directory, bind_addr, admin, handshake, shutdown).await?;
+    serving.await;
+    Ok(())
+}
+
+/// Bind `directory` to `bind_addr`, and the admin API if configured, and return the address
+/// bound along with the future serving both until `shutdown` is triggered.
+///
+/// `handshake` turns each accepted stream into the transport to serve, as in
+/// [`server::serve`].
+async fn bind<H, F, I>(
+    directory: Directory,
+    bind_addr: SocketAddr,
+    admin: Option<AdminConfig>,
+    handshake: H,
+    shutdown: ShutdownHandle,
+) -> Result<(SocketAddr, impl Future<Output = ()>), Box<dyn std::error::Error>>
+where
+    H: Fn(TcpStream) -> F,
+    F: Future<Output = std::io::Result<I>> + Send + 'static,
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let admin = match admin {
+        Some(admin) => Some(admin::listen(admin, directory.clone(), shutdown.clone()).await?),
+        None => None,
+    };
+    let service = ServiceBuilder::new()
+        .layer(TraceLayer::new_for_http())
+        .layer(CorsLayer::new().allow_origin(Any))
+        .service(directory);
+    let new_service = move |peer: SocketAddr| {
+        TowerToHyperService::new(AddExtension::new(service.clone(), RelayAddr(peer.ip())))
+    };
+
+    let listener = TcpListener::bind(bind_addr).await?;
+    let local_addr = listener.local_addr()?;
+    info!("Payjoin Directory awaiting connections at {}", local_addr);
+    let serving = async move {
+        tokio::join!(
+            server::serve(listener, handshake, new_service, shutdown),
+            OptionFuture::from(admin)
+        );
+        info!("Payjoin Directory stopped");
+    };
+    Ok((local_addr, serving))
+}
+
+/// The address of the OHTTP relay a request reached the directory from.
+///
+/// [`listen_tcp`] adds it to the extensions of every request. A server mounting a
+/// [`Directory`] must add it too for [`RateLimits::relay_requests_per_sec`] to apply, since
+/// requests without it aren't limited per relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayAddr(pub IpAddr);
+
+/// A directory's routes as a [`tower::Service`], to mount in another server.
+///
+/// Unlike [`listen_tcp`] it adds no CORS or tracing layers, so wrap it in whichever CORS,
+/// tracing, auth or limit layers that server needs. Errors are answered with an error
+/// response, the service itself never fails. Clones share their state.
+#[derive(Clone)]
+pub struct Directory {
     pool: DbPool,
-    ohttp: Arc<Mutex<ohttp::Server>>,
-) -> Result<Response<Body>> {
+    ohttp: Arc<Mutex<GatewayKeys>>,
+    metrics: Metrics,
+    limiter: RateLimiter,
+    max_payload_size: usize,
+}
+
+impl Directory {
+    /// Connect to the mailbox store. Pending long-polls are answered once `shutdown` is
+    /// triggered
+    pub async fn new(
+        config: Config,
+        ohttp_keys: GatewayKeys,
+        shutdown: ShutdownHandle,
+    ) -> Result<Self, db::Error> {
+        let metrics = Metrics::new();
+        let limiter = RateLimiter::new(&config.rate_limits, metrics.clone());
+        let pool = DbPool::new(
+            config.timeout,
+            config.mailbox_ttl,
+            config.max_queued_requests,
+            config.db_backend,
+            metrics.clone(),
+            limiter.clone(),
+            shutdown,
+        )
+        .await?;
+        Ok(Self {
+            pool,
+            ohttp: Arc::new(Mutex::new(ohttp_keys)),
+            metrics,
+            limiter,
+            max_payload_size: config.max_payload_size,
+        })
+    }
+}
+
+#[cfg(feature = "tls")]
+fn init_tls_acceptor(cert_key: (Vec<Vec<u8>>, Vec<u8>)) -> Result<tokio_rustls::TlsAcceptor> {
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let (cert_chain, key) = cert_key;
+    let cert_chain = cert_chain.into_iter().map(CertificateDer::from).collect();
+    let key = PrivateKeyDer::try_from(key).map_err(|e| anyhow::anyhow!("TLS error: {}", e))?;
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .map_err(|e| anyhow::anyhow!("TLS error: {}", e))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(server_config)))
+}
+
+impl<B> tower::Service<Request<B>> for Directory
+where
+    B: hyper::body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        handle_ohttp_gateway(req, self.clone()).map(Ok).boxed()
+    }
+}
+
+async fn handle_ohttp_gateway<B>(req: Request<B>, directory: Directory) -> Response<Full<Bytes>>
+where
+    B: hyper::body::Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
     let path = req.uri().path().to_string();
     let query = req.uri().query().unwrap_or_default().to_string();
     let (parts, body) = req.into_parts();
 
     let path_segments: Vec<&str> = path.split('/').collect();
     debug!("handle_ohttp_gateway: {:?}", &path_segments);
-    let mut response = match (parts.method, path_segments.as_slice()) {
-        (Method::POST, ["", ""]) => handle_ohttp(body, pool, ohttp).await,
-        (Method::GET, ["", "ohttp-keys"]) => get_ohttp_keys(&ohttp).await,
-        (Method::POST, ["", id]) => post_fallback_v1(id, query, body, pool).await,
-        (Method::GET, ["", "health"]) => health_check().await,
-        _ => Ok(not_found()),
-    }
-    .unwrap_or_else(|e| e.to_response());
-
-    // Allow CORS for third-party access
-    response.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
-
-    Ok(response)
+    let Directory { pool, ohttp, metrics, limiter, max_payload_size } = directory;
+    let response = async {
+        if let Some(RelayAddr(relay)) = parts.extensions.get() {
+            limiter.check_relay(*relay).map_err(|_| HandlerError::TooManyRequests)?;
+        }
+        let is_chunked =
+            parts.headers.get(CONTENT_TYPE).is_some_and(|ct| ct == CHUNKED_REQ_CONTENT_TYPE);
+        if is_chunked && parts.method == Method::POST && path_segments == ["", ""] {
+            return handle_ohttp_chunked(body, pool, ohttp, &limiter, max_payload_size).await;
+        }
+        // Hold the body's share of the memory budget until the response is ready
+        let (body, _buffered) = limiter.buffer(body).await?;
+        match (parts.method, path_segments.as_slice()) {
+            (Method::POST, ["", ""]) => handle_ohttp(body, pool, ohttp, max_payload_size).await,
+            (Method::GET, ["", "ohttp-keys"]) => get_ohttp_keys(&ohttp, &query).await,
+            (Method::POST, ["", id]) => {
+                metrics.v1_fallback_post();
+                post_fallback_v1(id, query, body, pool, max_payload_size).await
+            }
+            (Method::GET, ["", "health"]) => health_check().await,
+            _ => Ok(not_found()),
+        }
+    };
+    response.await.unwrap_or_else(|e| {
+        metrics.error(e.kind());
+        e.to_response()
+    })
 }
 
 async fn handle_ohttp(
-    body: Body,
+    ohttp_body: Bytes,
     pool: DbPool,
-    ohttp: Arc<Mutex<ohttp::Server>>,
-) -> Result<Response<Body>, HandlerError> {
+    ohttp: Arc<Mutex<GatewayKeys>>,
+    max_payload_size: usize,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
     // decapsulate
-    let ohttp_body =
-        hyper::body::to_bytes(body).await.map_err(|e| HandlerError::BadRequest(e.into()))?;
     let ohttp_locked = ohttp.lock().await;
-    let (bhttp_req, res_ctx) = ohttp_locked
-        .decapsulate(&ohttp_body)
-        .map_err(|e| HandlerError::OhttpKeyRejection(e.into()))?;
+    let (bhttp_req, res_ctx) =
+        ohttp_locked.decapsulate(&ohttp_body).map_err(HandlerError::OhttpKeyRejection)?;
     drop(ohttp_locked);
+    let bhttp_res =
+        handle_bhttp(bhttp_req, pool, max_payload_size, bhttp::Mode::KnownLength).await?;
+    let ohttp_res =
+        res_ctx.encapsulate(&bhttp_res).map_err(|e| HandlerError::InternalServerError(e.into()))?;
+    Ok(Response::new(Full::from(ohttp_res)))
+}
+
+/// Decapsulate a chunked request as its body streams in, reserving each frame from the
+/// memory budget, so an oversized request is refused before the rest of it is read.
+async fn handle_ohttp_chunked<B>(
+    body: B,
+    pool: DbPool,
+    ohttp: Arc<Mutex<GatewayKeys>>,
+    limiter: &RateLimiter,
+    max_payload_size: usize,
+) -> Result<Response<Full<Bytes>>, HandlerError>
+where
+    B: hyper::body::Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut decoder =
+        ohttp.lock().await.chunked_request_decoder(max_payload_size + MAX_BHTTP_OVERHEAD);
+    // Hold each frame's share of the memory budget until the response is ready
+    let mut buffered = Vec::new();
+    tokio::pin!(body);
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| BufferError::Body(e.into()))?;
+        // Trailers carry nothing the directory reads
+        let Ok(data) = frame.into_data() else { continue };
+        buffered.push(limiter.reserve(data.len())?);
+        decoder.push(&data)?;
+    }
+    let (bhttp_req, res_ctx) = decoder.finish()?;
+    let bhttp_res =
+        handle_bhttp(bhttp_req, pool, max_payload_size, bhttp::Mode::IndeterminateLength).await?;
+    let mut res = Response::new(Full::from(res_ctx.encapsulate(&bhttp_res)));
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(CHUNKED_RES_CONTENT_TYPE));
+    Ok(res)
+}
+
+/// Serve a decapsulated bhttp request, returning the bhttp response to encapsulate
+async fn handle_bhttp(
+    bhttp_req: Vec<u8>,
+    pool: DbPool,
+    max_payload_size: usize,
+    mode: bhttp::Mode,
+) -> Result<Vec<u8>, HandlerError> {
     let mut cursor = std::io::Cursor::new(bhttp_req);
     let req =
         bhttp::Message::read_bhttp(&mut cursor).map_err(|e| HandlerError::BadRequest(e.into()))?;
@@ -161,27 +374,27 @@ async fn handle_ohttp(
     for header in req.header().fields() {
         http_req = http_req.header(header.name(), header.value())
     }
-    let request = http_req.body(Body::from(body))?;
+    let request = http_req.body(Bytes::from(body))?;
 
-    let response = handle_v2(pool, request).await?;
+    let response = handle_v2(pool, request, max_payload_size).await?;
 
     let (parts, body) = response.into_parts();
     let mut bhttp_res = bhttp::Message::response(parts.status.as_u16());
-    let full_body = hyper::body::to_bytes(body)
-        .await
-        .map_err(|e| HandlerError::InternalServerError(e.into()))?;
+    let full_body =
+        body.collect().await.map_err(|e| HandlerError::InternalServerError(e.into()))?.to_bytes();
     bhttp_res.write_content(&full_body);
     let mut bhttp_bytes = Vec::new();
     bhttp_res
-        .write_bhttp(bhttp::Mode::KnownLength, &mut bhttp_bytes)
-        .map_err(|e| HandlerError::InternalServerError(e.into()))?;
-    let ohttp_res = res_ctx
-        .encapsulate(&bhttp_bytes)
+        .write_bhttp(mode, &mut bhttp_bytes)
         .map_err(|e| HandlerError::InternalServerError(e.into()))?;
-    Ok(Response::new(Body::from(ohttp_res)))
+    Ok(bhttp_bytes)
 }
 
-async fn handle_v2(pool: DbPool, req: Request<Body>) -> Result<Response<Body>, HandlerError> {
+async fn handle_v2(
+    pool: DbPool,
+    req: Request<Bytes>,
+    max_payload_size: usize,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
     let path = req.uri().path().to_string();
     let (parts, body) = req.into_parts();
 
@@ -189,29 +402,56 @@ async fn handle_v2(pool: DbPool, req: Request<Body>) -> Result<Response<Body>, H
     debug!("handle_v2: {:?}", &path_segments);
     match (parts.method, path_segments.as_slice()) {
         (Method::POST, &["", ""]) => post_session(body).await,
-        (Method::POST, &["", id]) => post_fallback_v2(id, body, pool).await,
+        (Method::POST, &["", id]) => post_fallback_v2(id, body, pool, max_payload_size).await,
         (Method::GET, &["", id]) => get_fallback(id, pool).await,
-        (Method::POST, &["", id, "payjoin"]) => post_payjoin(id, body, pool).await,
+        (Method::DELETE, &["", id]) => delete_mailbox(id, body, pool).await,
+        (Method::POST, &["", id, "payjoin"]) =>
+            post_payjoin(id, body, pool, max_payload_size).await,
+        (Method::POST, &["", id, "payjoin", reply_id]) =>
+            post_reply(id, reply_id, body, pool, max_payload_size).await,
+        (Method::POST, &["", id, reply_id]) =>
+            post_request(id, reply_id, body, pool, max_payload_size).await,
+        (Method::GET, &["", id, reply_id]) => get_reply(id, reply_id, pool).await,
+        (Method::DELETE, &["", id, reply_id]) => drop_request(id, reply_id, body, pool).await,
         _ => Ok(not_found()),
     }
 }
 
-async fn health_check() -> Result<Response<Body>, HandlerError> {
-    Ok(Response::builder().status(StatusCode::OK).body(Body::empty())?)
+async fn health_check() -> Result<Response<Full<Bytes>>, HandlerError> {
+    Ok(Response::builder().status(StatusCode::OK).body(Full::default())?)
 }
 
 enum HandlerError {
     PayloadTooLarge,
+    TooManyRequests,
+    /// The mailbox can't take another request
+    Conflict,
     InternalServerError(anyhow::Error),
     OhttpKeyRejection(anyhow::Error),
     BadRequest(anyhow::Error),
+    Forbidden(anyhow::Error),
 }
 
 impl HandlerError {
-    fn to_response(&self) -> Response<Body> {
+    /// A fixed label for the error metric
+    fn kind(&self) -> &'static str {
+        match self {
+            HandlerError::PayloadTooLarge => "payload_too_large",
+            HandlerError::TooManyRequests => "too_many_requests",
+            HandlerError::Conflict => "conflict",
+            HandlerError::InternalServerError(_) => "internal_server_error",
+            HandlerError::OhttpKeyRejection(_) => "ohttp_key_rejection",
+            HandlerError::BadRequest(_) => "bad_request",
+            HandlerError::Forbidden(_) => "forbidden",
+        }
+    }
+
+    fn to_response(&self) -> Response<Full<Bytes>> {
         let mut res = Response::default();
         match self {
             HandlerError::PayloadTooLarge => *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE,
+            HandlerError::TooManyRequests => *res.status_mut() = StatusCode::TOO_MANY_REQUESTS,
+            HandlerError::Conflict => *res.status_mut() = StatusCode::CONFLICT,
             HandlerError::InternalServerError(e) => {
                 error!("Internal server error: {}", e);
                 *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR
@@ -223,12 +463,16 @@ impl HandlerError {
                 *res.status_mut() = StatusCode::BAD_REQUEST;
                 res.headers_mut()
                     .insert(CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
-                *res.body_mut() = Body::from(OHTTP_KEY_REJECTION_RES_JSON);
+                *res.body_mut() = Full::from(OHTTP_KEY_REJECTION_RES_JSON);
             }
             HandlerError::BadRequest(e) => {
                 error!("Bad request: {}", e);
                 *res.status_mut() = StatusCode::BAD_REQUEST
             }
+            HandlerError::Forbidden(e) => {
+                error!("Forbidden: {}", e);
+                *res.status_mut() = StatusCode::FORBIDDEN
+            }
         };
 
         res
@@ -239,127 +483,719 @@ impl From<hyper::http::Error> for HandlerError {
     fn from(e: hyper::http::Error) -> Self { HandlerError::InternalServerError(e.into()) }
 }
 
-async fn post_session(body: Body) -> Result<Response<Body>, HandlerError> {
-    let bytes =
-        hyper::body::to_bytes(body).await.map_err(|e| HandlerError::BadRequest(e.into()))?;
+impl From<db::Error> for HandlerError {
+    fn from(e: db::Error) -> Self {
+        match e {
+            db::Error::RateLimited => HandlerError::TooManyRequests,
+            db::Error::MailboxFull => HandlerError::Conflict,
+            e => HandlerError::BadRequest(e.into()),
+        }
+    }
+}
+
+impl From<chunked_ohttp::Error> for HandlerError {
+    fn from(e: chunked_ohttp::Error) -> Self {
+        match e {
+            chunked_ohttp::Error::KeyId => HandlerError::OhttpKeyRejection(e.into()),
+            chunked_ohttp::Error::ChunkTooLong | chunked_ohttp::Error::TooLarge(_) =>
+                HandlerError::PayloadTooLarge,
+            e => HandlerError::BadRequest(e.into()),
+        }
+    }
+}
+
+impl From<BufferError> for HandlerError {
+    fn from(e: BufferError) -> Self {
+        match e {
+            BufferError::Body(e) => HandlerError::BadRequest(anyhow::anyhow!(e)),
+            BufferError::Limited => HandlerError::TooManyRequests,
+        }
+    }
+}
+
+async fn post_session(bytes: Bytes) -> Result<Response<Full<Bytes>>, HandlerError> {
     let base64_id =
         String::from_utf8(bytes.to_vec()).map_err(|e| HandlerError::BadRequest(e.into()))?;
-    let pubkey_bytes: Vec<u8> =
-        BASE64_URL_SAFE_NO_PAD.decode(base64_id).map_err(|e| HandlerError::BadRequest(e.into()))?;
-    let pubkey = bitcoin::secp256k1::PublicKey::from_slice(&pubkey_bytes)
-        .map_err(|e| HandlerError::BadRequest(e.into()))?;
+    let pubkey = subdirectory_pubkey(&base64_id)?;
     tracing::info!("Initialized session with pubkey: {:?}", pubkey);
-    Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty())?)
+    Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Full::default())?)
 }
 
 async fn post_fallback_v1(
     id: &str,
     query: String,
-    body: Body,
+    body: Bytes,
     pool: DbPool,
-) -> Result<Response<Body>, HandlerError> {
+    max_payload_size: usize,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
     trace!("Post fallback v1");
     let none_response = Response::builder()
         .status(StatusCode::SERVICE_UNAVAILABLE)
-        .body(Body::from(V1_UNAVAILABLE_RES_JSON))?;
+        .body(Full::from(V1_UNAVAILABLE_RES_JSON))?;
     let bad_request_body_res =
-        Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from(V1_REJECT_RES_JSON))?;
+        Response::builder().status(StatusCode::BAD_REQUEST).body(Full::from(V1_REJECT_RES_JSON))?;
 
-    let body_bytes = match hyper::body::to_bytes(body).await {
-        Ok(bytes) => bytes.to_vec(),
-        Err(_) => return Ok(bad_request_body_res),
-    };
-
-    let body_str = match String::from_utf8(body_bytes) {
+    let body_str = match String::from_utf8(body.to_vec()) {
         Ok(body_str) => body_str,
         Err(_) => return Ok(bad_request_body_res),
     };
 
-    let v2_compat_body = Body::from(format!("{}\n{}", body_str, query));
-    post_fallback(id, v2_compat_body, pool, none_response).await
+    let v2_compat_body = Bytes::from(format!("{}\n{}", body_str, query));
+    post_fallback(id, v2_compat_body, pool, none_response, max_payload_size).await
 }
 
 async fn post_fallback_v2(
     id: &str,
-    body: Body,
+    body: Bytes,
     pool: DbPool,
-) -> Result<Response<Body>, HandlerError> {
+    max_payload_size: usize,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
     trace!("Post fallback v2");
-    let none_response = Response::builder().status(StatusCode::ACCEPTED).body(Body::empty())?;
-    post_fallback(id, body, pool, none_response).await
+    let none_response = Response::builder().status(StatusCode::ACCEPTED).body(Full::default())?;
+    post_fallback(id, body, pool, none_response, max_payload_size).await
 }
 
 async fn post_fallback(
     id: &str,
-    body: Body,
+    req: Bytes,
     pool: DbPool,
-    none_response: Response<Body>,
-) -> Result<Response<Body>, HandlerError> {
+    none_response: Response<Full<Bytes>>,
+    max_payload_size: usize,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
     tracing::trace!("Post fallback");
-    let id = shorten_string(id);
-    let req = hyper::body::to_bytes(body)
-        .await
-        .map_err(|e| HandlerError::InternalServerError(e.into()))?;
-    if req.len() > MAX_BUFFER_SIZE {
+    let id = mailbox_id(&subdirectory_pubkey(id)?);
+    if req.len() > max_payload_size {
         return Err(HandlerError::PayloadTooLarge);
     }
 
     match pool.push_req(&id, req.into()).await {
         Ok(_) => (),
-        Err(e) => return Err(HandlerError::BadRequest(e.into())),
+        Err(e) => return Err(e.into()),
     };
 
     match pool.peek_res(&id).await {
         Some(result) => match result {
-            Ok(buffered_res) => Ok(Response::new(Body::from(buffered_res))),
-            Err(e) => Err(HandlerError::BadRequest(e.into())),
+            Ok(buffered_res) => Ok(Response::new(Full::from(buffered_res))),
+            Err(e) => Err(e.into()),
         },
         None => Ok(none_response),
     }
 }
 
-async fn get_fallback(id: &str, pool: DbPool) -> Result<Response<Body>, HandlerError> {
+/// Queue a sender's request and wait for the reply to it in its own reply slot, named by
+/// the request's digest, so many senders can share a reusable mailbox.
+async fn post_request(
+    id: &str,
+    reply_id: &str,
+    req: Bytes,
+    pool: DbPool,
+    max_payload_size: usize,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
+    trace!("POST request");
+    let id = mailbox_id(&subdirectory_pubkey(id)?);
+    if req.len() > max_payload_size {
+        return Err(HandlerError::PayloadTooLarge);
+    }
+    // Only the request's sender can know its digest, and with it poll for its reply
+    if reply_id != db::reply_id(&req) {
+        return Err(HandlerError::BadRequest(anyhow::anyhow!("Reply id isn't the request digest")));
+    }
+
+    // A replied request was dequeued and must not be queued again by a later poll
+    if let Some(reply) = pool.get_reply(&id, reply_id).await? {
+        return Ok(Response::new(Full::from(reply)));
+    }
+    pool.push_req(&id, req.into()).await?;
+    match pool.peek_reply(&id, reply_id).await {
+        Some(result) => Ok(Response::new(Full::from(result?))),
+        None => Ok(Response::builder().status(StatusCode::ACCEPTED).body(Full::default())?),
+    }
+}
+
+/// Wait for the reply to a request posted before, without sending the request again
+async fn get_reply(
+    id: &str,
+    reply_id: &str,
+    pool: DbPool,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
+    trace!("GET reply");
+    let id = mailbox_id(&subdirectory_pubkey(id)?);
+    reply_digest(reply_id)?;
+    match pool.peek_reply(&id, reply_id).await {
+        Some(result) => Ok(Response::new(Full::from(result?))),
+        None => Ok(Response::builder().status(StatusCode::ACCEPTED).body(Full::default())?),
+    }
+}
+
+async fn get_fallback(id: &str, pool: DbPool) -> Result<Response<Full<Bytes>>, HandlerError> {
     trace!("GET fallback");
-    let id = shorten_string(id);
+    let id = mailbox_id(&subdirectory_pubkey(id)?);
     match pool.peek_req(&id).await {
         Some(result) => match result {
-            Ok(buffered_req) => Ok(Response::new(Body::from(buffered_req))),
-            Err(e) => Err(HandlerError::BadRequest(e.into())),
+            Ok(buffered_req) => Ok(Response::new(Full::from(buffered_req))),
+            Err(e) => Err(e.into()),
         },
-        None => Ok(Response::builder().status(StatusCode::ACCEPTED).body(Body::empty())?),
+        None => Ok(Response::builder().status(StatusCode::ACCEPTED).body(Full::default())?),
     }
 }
 
-async fn post_payjoin(id: &str, body: Body, pool: DbPool) -> Result<Response<Body>, HandlerError> {
+async fn post_payjoin(
+    id: &str,
+    signed_res: Bytes,
+    pool: DbPool,
+    max_payload_size: usize,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
     trace!("POST payjoin");
-    let id = shorten_string(id);
-    let res = hyper::body::to_bytes(body)
-        .await
-        .map_err(|e| HandlerError::InternalServerError(e.into()))?;
+    let pubkey = subdirectory_pubkey(id)?;
+    let id = mailbox_id(&pubkey);
+    // Only the receiver holding the subdirectory's session key may write its response
+    let res = auth::verify_fresh(mailbox_auth::POST_PAYJOIN_TAG, &pubkey, &[], &signed_res)
+        .map_err(HandlerError::Forbidden)?;
+    if res.len() > max_payload_size {
+        return Err(HandlerError::PayloadTooLarge);
+    }
 
-    match pool.push_res(&id, res.into()).await {
-        Ok(_) => Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty())?),
-        Err(e) => Err(HandlerError::BadRequest(e.into())),
+    match pool.push_res(&id, res.to_vec()).await {
+        Ok(_) => Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Full::default())?),
+        Err(e) => Err(e.into()),
     }
 }
 
-fn not_found() -> Response<Body> {
+async fn post_reply(
+    id: &str,
+    reply_id: &str,
+    signed_res: Bytes,
+    pool: DbPool,
+    max_payload_size: usize,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
+    trace!("POST reply");
+    let pubkey = subdirectory_pubkey(id)?;
+    let id = mailbox_id(&pubkey);
+    let digest = reply_digest(reply_id)?;
+    let res = auth::verify_fresh(mailbox_auth::POST_REPLY_TAG, &pubkey, &digest, &signed_res)
+        .map_err(HandlerError::Forbidden)?;
+    if res.len() > max_payload_size {
+        return Err(HandlerError::PayloadTooLarge);
+    }
+
+    pool.push_reply(&id, reply_id, res.to_vec()).await?;
+    Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Full::default())?)
+}
+
+async fn drop_request(
+    id: &str,
+    reply_id: &str,
+    signed_timestamp: Bytes,
+    pool: DbPool,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
+    trace!("DELETE request");
+    let pubkey = subdirectory_pubkey(id)?;
+    let digest = reply_digest(reply_id)?;
+    auth::verify_fresh(mailbox_auth::DROP_REQUEST_TAG, &pubkey, &digest, &signed_timestamp)
+        .map_err(HandlerError::Forbidden)?;
+
+    pool.drop_req(&mailbox_id(&pubkey), reply_id).await?;
+    Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Full::default())?)
+}
+
+async fn delete_mailbox(
+    id: &str,
+    signed_timestamp: Bytes,
+    pool: DbPool,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
+    trace!("DELETE mailbox");
+    let pubkey = subdirectory_pubkey(id)?;
+    auth::verify_fresh(mailbox_auth::DELETE_MAILBOX_TAG, &pubkey, &[], &signed_timestamp)
+        .map_err(HandlerError::Forbidden)?;
+
+    pool.delete(&mailbox_id(&pubkey)).await?;
+    Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Full::default())?)
+}
+
+fn not_found() -> Response<Full<Bytes>> {
     let mut res = Response::default();
     *res.status_mut() = StatusCode::NOT_FOUND;
     res
 }
 
-async fn get_ohttp_keys(ohttp: &Arc<Mutex<ohttp::Server>>) -> Result<Response<Body>, HandlerError> {
+/// Serve the current key configuration, or an RFC 9458 list of configurations to clients that
+/// ask for one with `?format=list`. Deployed clients decode the body as a single configuration.
+async fn get_ohttp_keys(
+    ohttp: &Arc<Mutex<GatewayKeys>>,
+    query: &str,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
     let mut res = Response::default();
     res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/ohttp-keys"));
-    let ohttp_keys = ohttp
-        .lock()
-        .await
-        .config()
-        .encode()
-        .map_err(|e| HandlerError::InternalServerError(e.into()))?;
-    *res.body_mut() = Body::from(ohttp_keys);
+    let keys = ohttp.lock().await;
+    let ohttp_keys = if query.split('&').any(|param| param == "format=list") {
+        keys.encode_list()
+    } else {
+        keys.encode()
+    }
+    .map_err(HandlerError::InternalServerError)?;
+    *res.body_mut() = Full::from(ohttp_keys);
     Ok(res)
 }
 
-fn shorten_string(input: &str) -> String { input.chars().take(8).collect() }
+/// Parse the base64url-encoded session pubkey that identifies a subdirectory
+fn subdirectory_pubkey(id: &str) -> Result<bitcoin::secp256k1::PublicKey, HandlerError> {
+    let pubkey_bytes =
+        BASE64_URL_SAFE_NO_PAD.decode(id).map_err(|e| HandlerError::BadRequest(e.into()))?;
+    bitcoin::secp256k1::PublicKey::from_slice(&pubkey_bytes)
+        .map_err(|e| HandlerError::BadRequest(e.into()))
+}
+
+/// Parse the base64url-encoded request digest that names a reply slot
+fn reply_digest(reply_id: &str) -> Result<[u8; 32], HandlerError> {
+    let digest =
+        BASE64_URL_SAFE_NO_PAD.decode(reply_id).map_err(|e| HandlerError::BadRequest(e.into()))?;
+    digest.try_into().map_err(|_| HandlerError::BadRequest(anyhow::anyhow!("Malformed reply id")))
+}
+
+/// The storage key for a subdirectory, derived from its full validated pubkey
+fn mailbox_id(pubkey: &bitcoin::secp256k1::PublicKey) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(pubkey.serialize())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::secp256k1::PublicKey;
+
+    use super::*;
+
+    /// Find two valid pubkeys whose base64url ids share the 8 characters the directory
+    /// used to key mailboxes by
+    fn pubkeys_with_shared_prefix() -> (PublicKey, PublicKey) {
+        let mut keys = (0..=u8::MAX).filter_map(|last| {
+            let mut bytes = [0x02; 33];
+            bytes[32] = last;
+            PublicKey::from_slice(&bytes).ok()
+        });
+        (keys.next().unwrap(), keys.next().unwrap())
+    }
+
+    #[test]
+    fn colliding_prefixes_get_distinct_mailboxes() {
+        let (a, b) = pubkeys_with_shared_prefix();
+        let (id_a, id_b) = (mailbox_id(&a), mailbox_id(&b));
+        assert_eq!(id_a[..8], id_b[..8]);
+        assert_ne!(id_a, id_b);
+        assert_eq!(subdirectory_pubkey(&id_a).ok(), Some(a));
+        assert_eq!(subdirectory_pubkey(&id_b).ok(), Some(b));
+    }
+
+    #[test]
+    fn malformed_ids_are_bad_requests() {
+        let (pubkey, _) = pubkeys_with_shared_prefix();
+        let id = mailbox_id(&pubkey);
+        for bad_id in [&id[..8], "not base64!", "", &format!("{}AA", id)] {
+            assert!(matches!(subdirectory_pubkey(bad_id), Err(HandlerError::BadRequest(_))));
+        }
+        // an x coordinate beyond the field order is not a point
+        let off_curve = BASE64_URL_SAFE_NO_PAD
+            .encode([0x02].iter().chain(&[0xff; 32]).copied().collect::<Vec<u8>>());
+        assert!(matches!(subdirectory_pubkey(&off_curve), Err(HandlerError::BadRequest(_))));
+    }
+
+    async fn test_directory(config: Config) -> Directory {
+        let config =
+            Config { timeout: Duration::from_millis(50), db_backend: DbBackend::Memory, ..config };
+        let ohttp_keys = GatewayKeys::ephemeral().unwrap();
+        Directory::new(config, ohttp_keys, ShutdownHandle::new()).await.unwrap()
+    }
+
+    async fn request(
+        directory: &Directory,
+        method: Method,
+        path: &str,
+        body: Vec<u8>,
+    ) -> Response<Full<Bytes>> {
+        use tower::ServiceExt;
+
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .extension(RelayAddr(IpAddr::V6(Ipv6Addr::LOCALHOST)))
+            .body(Full::from(body))
+            .unwrap();
+        directory.clone().oneshot(req).await.unwrap()
+    }
+
+    /// Serve a request as if it had been decapsulated from OHTTP
+    async fn v2_request(
+        directory: &Directory,
+        method: Method,
+        path: String,
+        body: Vec<u8>,
+    ) -> (StatusCode, Vec<u8>) {
+        let req = Request::builder().method(method).uri(path).body(Bytes::from(body)).unwrap();
+        let res = handle_v2(directory.pool.clone(), req, DEFAULT_MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap_or_else(|e| e.to_response());
+        let status = res.status();
+        (status, res.into_body().collect().await.unwrap().to_bytes().to_vec())
+    }
+
+    #[tokio::test]
+    async fn ohttp_keys_are_one_config_unless_a_list_is_asked_for() {
+        let directory = test_directory(Config::default()).await;
+        let res = request(&directory, Method::GET, "/ohttp-keys", vec![]).await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/ohttp-keys");
+        let config = res.into_body().collect().await.unwrap().to_bytes();
+        assert!(ohttp::KeyConfig::decode(&config).is_ok());
+
+        let res = request(&directory, Method::GET, "/ohttp-keys?format=list", vec![]).await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/ohttp-keys");
+        let list = res.into_body().collect().await.unwrap().to_bytes();
+        let configs = ohttp::KeyConfig::decode_list(&list).unwrap();
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].encode().unwrap(), config);
+    }
+
+    #[tokio::test]
+    async fn mailboxes_are_deleted_only_with_a_fresh_signature() {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        use bitcoin::secp256k1::{Keypair, Secp256k1};
+
+        let directory = test_directory(Config::default()).await;
+        let s = Keypair::from_seckey_slice(&Secp256k1::new(), &[1; 32]).unwrap();
+        let not_s = Keypair::from_seckey_slice(&Secp256k1::new(), &[2; 32]).unwrap();
+        let id = mailbox_id(&s.public_key());
+        let reply_id = db::reply_id(b"original");
+        let (status, _) = v2_request(
+            &directory,
+            Method::POST,
+            format!("/{}/{}", id, reply_id),
+            b"original".to_vec(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let signed_at = |key: &Keypair, timestamp: u64| {
+            mailbox_auth::sign_at(mailbox_auth::DELETE_MAILBOX_TAG, key, timestamp, &[], &[])
+        };
+        let stale = now - 2 * auth::MAX_TIMESTAMP_SKEW.as_secs();
+        for body in [vec![], signed_at(&not_s, now), signed_at(&s, stale)] {
+            let (status, _) =
+                v2_request(&directory, Method::DELETE, format!("/{}", id), body).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+        let (status, body) = v2_request(&directory, Method::GET, format!("/{}", id), vec![]).await;
+        assert_eq!((status, body), (StatusCode::OK, b"original".to_vec()));
+
+        let (status, _) =
+            v2_request(&directory, Method::DELETE, format!("/{}", id), signed_at(&s, now)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = v2_request(&directory, Method::GET, format!("/{}", id), vec![]).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "the mailbox is gone");
+    }
+
+    #[tokio::test]
+    async fn only_the_receiver_may_post_a_payjoin_proposal() {
+        use bitcoin::secp256k1::{Keypair, Secp256k1};
+
+        let directory = test_directory(Config::default()).await;
+        let s = Keypair::from_seckey_slice(&Secp256k1::new(), &[1; 32]).unwrap();
+        let not_s = Keypair::from_seckey_slice(&Secp256k1::new(), &[2; 32]).unwrap();
+        let id = mailbox_id(&s.public_key());
+        let path = format!("/{}/payjoin", id);
+        let signed = |key: &Keypair, proposal: &[u8]| {
+            mailbox_auth::sign(mailbox_auth::POST_PAYJOIN_TAG, key, &[], proposal)
+        };
+
+        let mut tampered = signed(&s, b"proposal");
+        *tampered.last_mut().unwrap() ^= 1;
+        let stale = mailbox_auth::sign_at(mailbox_auth::POST_PAYJOIN_TAG, &s, 0, &[], b"proposal");
+        for body in
+            [b"proposal".to_vec(), [0; 72].to_vec(), signed(&not_s, b"proposal"), tampered, stale]
+        {
+            let (status, _) = v2_request(&directory, Method::POST, path.clone(), body).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+        let (status, _) =
+            v2_request(&directory, Method::POST, format!("/{}", id), b"original".to_vec()).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "no proposal was stored");
+
+        let (status, _) = v2_request(&directory, Method::POST, path, signed(&s, b"proposal")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) =
+            v2_request(&directory, Method::POST, format!("/{}", id), b"original".to_vec()).await;
+        assert_eq!((status, body), (StatusCode::OK, b"proposal".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn payjoin_proposals_serve_the_next_queued_request() {
+        use bitcoin::secp256k1::{Keypair, Secp256k1};
+
+        let directory = test_directory(Config::default()).await;
+        let s = Keypair::from_seckey_slice(&Secp256k1::new(), &[1; 32]).unwrap();
+        let id = mailbox_id(&s.public_key());
+        let v2 = |method, path, body| v2_request(&directory, method, path, body);
+        for req in [&b"first"[..], b"second"] {
+            let (status, _) = v2(Method::POST, format!("/{}", id), req.to_vec()).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+        }
+
+        assert_eq!(v2(Method::GET, format!("/{}", id), vec![]).await.1, b"first");
+        let signed = mailbox_auth::sign(mailbox_auth::POST_PAYJOIN_TAG, &s, &[], b"proposal");
+        let (status, _) = v2(Method::POST, format!("/{}/payjoin", id), signed).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            v2(Method::GET, format!("/{}", id), vec![]).await.1,
+            b"second",
+            "the answered request must not hide the one queued behind it"
+        );
+    }
+
+    #[tokio::test]
+    async fn reusable_mailboxes_reply_to_each_sender() {
+        use bitcoin::secp256k1::{Keypair, Secp256k1};
+
+        let directory = test_directory(Config::default()).await;
+        let s = Keypair::from_seckey_slice(&Secp256k1::new(), &[1; 32]).unwrap();
+        let id = mailbox_id(&s.public_key());
+        let v2 = |method, path, body| v2_request(&directory, method, path, body);
+        let (first, second) = (db::reply_id(b"first"), db::reply_id(b"second"));
+
+        for (reply_id, req) in [(&first, &b"first"[..]), (&second, &b"second"[..])] {
+            let (status, _) = v2(Method::POST, format!("/{}/{}", id, reply_id), req.to_vec()).await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+        }
+        let (status, _) = v2(Method::GET, format!("/{}/{}", id, first), vec![]).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "there is no reply yet");
+        let (status, _) = v2(Method::POST, format!("/{}/{}", id, second), b"third".to_vec()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "a sender can't poll another's reply slot");
+        assert_eq!(v2(Method::GET, format!("/{}", id), vec![]).await.1, b"first");
+
+        let reply = |reply_id: &str, key: &Keypair| {
+            let digest = reply_digest(reply_id).ok().unwrap();
+            mailbox_auth::sign(mailbox_auth::POST_REPLY_TAG, key, &digest, b"proposal")
+        };
+        let not_s = Keypair::from_seckey_slice(&Secp256k1::new(), &[2; 32]).unwrap();
+        let path = format!("/{}/payjoin/{}", id, first);
+        let (status, _) = v2(Method::POST, path.clone(), reply(&first, &not_s)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = v2(Method::POST, path, reply(&first, &s)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(v2(Method::GET, format!("/{}", id), vec![]).await.1, b"second");
+
+        let (status, body) = v2(Method::GET, format!("/{}/{}", id, first), vec![]).await;
+        assert_eq!((status, body), (StatusCode::OK, b"proposal".to_vec()));
+        let (status, body) =
+            v2(Method::POST, format!("/{}/{}", id, first), b"first".to_vec()).await;
+        assert_eq!((status, body), (StatusCode::OK, b"proposal".to_vec()));
+        assert_eq!(
+            v2(Method::GET, format!("/{}", id), vec![]).await.1,
+            b"second",
+            "a replied request must not be queued again"
+        );
+
+        let digest = reply_digest(&second).ok().unwrap();
+        let path = format!("/{}/{}", id, second);
+        let stale = mailbox_auth::sign_at(mailbox_auth::DROP_REQUEST_TAG, &s, 0, &digest, &[]);
+        let (status, _) = v2(Method::DELETE, path.clone(), stale).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "a captured drop can't be replayed later");
+        let sig = mailbox_auth::sign(mailbox_auth::DROP_REQUEST_TAG, &s, &digest, &[]);
+        let (status, _) = v2(Method::DELETE, path, sig).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = v2(Method::GET, format!("/{}", id), vec![]).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+    }
+
+    fn scrape(directory: &Directory) -> String {
+        String::from_utf8(directory.metrics.encode().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn metrics_count_rejections_without_leaking_ids() {
+        let directory = test_directory(Config { max_payload_size: 16, ..Config::default() }).await;
+        let path = format!("/{}", mailbox_id(&pubkeys_with_shared_prefix().0));
+
+        // no key has id 0xff
+        let res = request(&directory, Method::POST, "/", vec![0xff; 32]).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = request(&directory, Method::POST, &path, vec![b'a'; 32]).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res = request(&directory, Method::POST, &path, b"psbt".to_vec()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let scraped = scrape(&directory);
+        // only the admin API serves metrics
+        let res = request(&directory, Method::GET, "/metrics", vec![]).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        for expected in [
+            r#"payjoin_directory_errors_total{kind="ohttp_key_rejection"} 1"#,
+            r#"payjoin_directory_errors_total{kind="payload_too_large"} 1"#,
+            "payjoin_directory_v1_fallback_posts_total 2",
+            r#"payjoin_directory_long_poll_timeouts_total{column="res"} 1"#,
+            r#"payjoin_directory_mailbox_entry_bytes_count{column="req"} 1"#,
+            r#"payjoin_directory_store_latency_seconds_count{op="append"} 1"#,
+        ] {
+            assert!(scraped.contains(expected), "missing {} in\n{}", expected, scraped);
+        }
+        assert!(!scraped.contains(&path[1..]));
+    }
+
+    #[tokio::test]
+    async fn exceeding_a_limit_is_too_many_requests() {
+        let rate_limits = RateLimits {
+            relay_requests_per_sec: 3,
+            mailbox_writes_per_min: 1,
+            ..Default::default()
+        };
+        let directory = test_directory(Config { rate_limits, ..Config::default() }).await;
+        let path = format!("/{}", mailbox_id(&pubkeys_with_shared_prefix().0));
+
+        let res = request(&directory, Method::POST, &path, b"psbt".to_vec()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = request(&directory, Method::POST, &path, b"psbt".to_vec()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        // the relay's burst of 3 is spent
+        let res = request(&directory, Method::GET, "/health", vec![]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request(&directory, Method::GET, "/health", vec![]).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let scraped = directory.metrics.encode().unwrap();
+        let scraped = String::from_utf8(scraped).unwrap();
+        for expected in [
+            r#"payjoin_directory_rate_limited_total{limit="mailbox_writes"} 1"#,
+            r#"payjoin_directory_rate_limited_total{limit="relay"} 1"#,
+        ] {
+            assert!(scraped.contains(expected), "missing {} in\n{}", expected, scraped);
+        }
+    }
+
+    #[tokio::test]
+    async fn mounts_behind_custom_layers() {
+        use hyper::header::{ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN};
+        use tower::ServiceExt;
+
+        let rate_limits = RateLimits { relay_requests_per_sec: 1, ..Default::default() };
+        let directory = test_directory(Config { rate_limits, ..Config::default() }).await;
+        let wallet = HeaderValue::from_static("https://wallet.example");
+        let service = ServiceBuilder::new()
+            .layer(CorsLayer::new().allow_origin(wallet.clone()))
+            .service(directory);
+
+        // without a relay address, requests are left to the embedder's limits
+        for _ in 0..3 {
+            let req = Request::get("/health").header(ORIGIN, wallet.clone());
+            let res = service.clone().oneshot(req.body(Full::<Bytes>::default()).unwrap()).await;
+            let res = res.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], wallet);
+        }
+    }
+
+    /// A directory with in-memory storage serving on a free port
+    struct Served {
+        addr: SocketAddr,
+        directory: Directory,
+        shutdown: ShutdownHandle,
+        serving: tokio::task::JoinHandle<()>,
+    }
+
+    /// Bind a directory with in-memory storage to a free port, so it accepts connections
+    /// once this returns, and serve it in the background
+    async fn serve_directory(timeout: Duration) -> Served {
+        let config = Config { timeout, db_backend: DbBackend::Memory, ..Config::default() };
+        let shutdown = ShutdownHandle::new();
+        let ohttp_keys = GatewayKeys::ephemeral().unwrap();
+        let directory = Directory::new(config, ohttp_keys, shutdown.clone()).await.unwrap();
+        let bind_addr = SocketAddr::from((Ipv6Addr::LOCALHOST, 0));
+        let handshake = |stream| async { Ok(stream) };
+        let (addr, serving) =
+            bind(directory.clone(), bind_addr, None, handshake, shutdown.clone()).await.unwrap();
+        Served { addr, directory, shutdown, serving: tokio::spawn(serving) }
+    }
+
+    /// Wait until a long-poll is waiting on the store, which it subscribes to first
+    async fn until_polling(directory: &Directory) {
+        while !scrape(directory).contains(r#"op="subscribe""#) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_answers_pending_polls_and_stops() {
+        use hyper_util::client::legacy::Client;
+        use hyper_util::rt::TokioExecutor;
+
+        let Served { addr, directory, shutdown, serving } =
+            serve_directory(Duration::from_secs(60)).await;
+
+        let id = mailbox_id(&pubkeys_with_shared_prefix().0);
+        let poll =
+            Request::post(format!("http://{}/{}", addr, id)).body(Full::from("psbt")).unwrap();
+        let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+        let poll = tokio::spawn(client.request(poll));
+        until_polling(&directory).await;
+        shutdown.shutdown();
+
+        let res = tokio::time::timeout(Duration::from_secs(5), poll).await.unwrap().unwrap();
+        // v1 senders are told the receiver is unavailable, v2 clients get 202 Accepted
+        assert_eq!(res.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+        tokio::time::timeout(Duration::from_secs(5), serving).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_answers_pending_v2_polls_with_accepted() {
+        let timeout = Duration::from_secs(60);
+        let config = Config { timeout, db_backend: DbBackend::Memory, ..Config::default() };
+        let shutdown = ShutdownHandle::new();
+        let ohttp_keys = GatewayKeys::ephemeral().unwrap();
+        let directory = Directory::new(config, ohttp_keys, shutdown.clone()).await.unwrap();
+
+        let id = mailbox_id(&pubkeys_with_shared_prefix().0);
+        let polls =
+            [format!("/{}", id), format!("/{}/{}", id, db::reply_id(b"request"))].map(|path| {
+                let directory = directory.clone();
+                tokio::spawn(async move { v2_request(&directory, Method::GET, path, vec![]).await })
+            });
+        until_polling(&directory).await;
+        let started = std::time::Instant::now();
+        shutdown.shutdown();
+
+        for poll in polls {
+            let (status, body) =
+                tokio::time::timeout(Duration::from_secs(5), poll).await.unwrap().unwrap();
+            assert_eq!(status, StatusCode::ACCEPTED, "polled again once the directory is back");
+            assert!(body.is_empty());
+        }
+        assert!(started.elapsed() < timeout / 10, "answered on shutdown rather than timeout");
+    }
+
+    #[tokio::test]
+    async fn long_polls_multiplex_over_one_http2_connection() {
+        use hyper::client::conn::http2;
+        use hyper_util::rt::{TokioExecutor, TokioIo};
+
+        let timeout = Duration::from_millis(500);
+        let Served { addr, shutdown, .. } = serve_directory(timeout).await;
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (sender, conn) =
+            http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(conn);
+        let (a, b) = pubkeys_with_shared_prefix();
+        let polls = [a, b].map(|pubkey| {
+            let mut sender = sender.clone();
+            let uri = format!("http://{}/{}", addr, mailbox_id(&pubkey));
+            let poll = Request::post(uri).body(Full::<Bytes>::from("psbt")).unwrap();
+            async move { sender.send_request(poll).await }
+        });
+
+        let started = std::time::Instant::now();
+        for res in futures::future::join_all(polls).await {
+            let res = res.map_err(|e| e.to_string()).unwrap();
+            assert_eq!(res.version(), hyper::Version::HTTP_2);
+            assert_eq!(res.headers()[hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+        assert!(started.elapsed() < 2 * timeout, "polls on one connection wait concurrently");
+        shutdown.shutdown();
+    }
+}
@@ -1,30 +1,273 @@
-use std::env;
+use std::time::Duration;
 
+use clap::builder::BoolishValueParser;
+use clap::{Arg, ArgAction, Command};
 use payjoin_directory::*;
+use tracing::info;
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
+use crate::app_config::{AppConfig, LogFormat};
+
+mod app_config;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    init_logging();
+    let matches = cli().get_matches();
+    let config = AppConfig::new(&matches).map_err(|e| format!("Invalid configuration: {}", e))?;
+    init_logging(config.log_format);
+    info!("Directory config: {:?}", config);
+
+    let shutdown = ShutdownHandle::new();
+    tokio::spawn(shutdown_on_signal(shutdown.clone()));
+
+    #[cfg(feature = "tls")]
+    if let Some(gateway) = &config.relay_gateway {
+        let gateway_roots = match &config.relay_gateway_cert {
+            Some(path) => load_certs(path)?,
+            None => vec![],
+        };
+        let relay_config = RelayConfig {
+            bind_addr: std::net::SocketAddr::new(config.bind_addr, config.port),
+            gateway_origin: gateway.parse()?,
+            gateway_roots,
+        };
+        return listen_relay(relay_config, shutdown).await;
+    }
+
+    let mut ohttp_keys = GatewayKeys::load_or_create(
+        &config.ohttp_keys_path,
+        Duration::from_secs(config.ohttp_key_grace_secs),
+    )?;
+    if config.rotate_ohttp_keys {
+        ohttp_keys.rotate()?;
+    }
+
+    match (&config.tls_cert, &config.tls_key) {
+        #[cfg(feature = "tls")]
+        (Some(cert_path), Some(key_path)) => {
+            let tls_config = load_tls_config(cert_path, key_path)?;
+            listen_tcp_with_tls(config.directory_config(), ohttp_keys, tls_config, shutdown).await
+        }
+        _ => listen_tcp(config.directory_config(), ohttp_keys, shutdown).await,
+    }
+}
+
+/// Drain connections on SIGTERM, as sent by service managers, or on Ctrl-C
+async fn shutdown_on_signal(shutdown: ShutdownHandle) {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = terminate => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+    info!("Shutting down, finishing pending requests");
+    shutdown.shutdown();
+}
 
-    let dir_port =
-        env::var("PJ_DIR_PORT").map_or(DEFAULT_DIR_PORT, |s| s.parse().expect("Invalid port"));
+fn cli() -> Command {
+    Command::new("payjoin-directory")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Payjoin v2 directory server")
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .short('c')
+                .env("PJ_DIR_CONFIG")
+                .help("Path to a TOML config file [default: config.toml if present]"),
+        )
+        .arg(
+            Arg::new("bind_addr")
+                .long("bind-addr")
+                .env("PJ_DIR_BIND_ADDR")
+                .help("The IP address to listen on [default: ::]"),
+        )
+        .arg(
+            Arg::new("port")
+                .long("port")
+                .short('p')
+                .env("PJ_DIR_PORT")
+                .help("The port to listen on [default: 8080]"),
+        )
+        .arg(
+            Arg::new("tls_cert")
+                .long("tls-cert")
+                .env("PJ_DIR_TLS_CERT")
+                .help("PEM certificate chain to serve HTTPS with, requires --tls-key"),
+        )
+        .arg(
+            Arg::new("tls_key")
+                .long("tls-key")
+                .env("PJ_DIR_TLS_KEY")
+                .help("PEM private key for --tls-cert"),
+        )
+        .arg(
+            Arg::new("timeout_secs")
+                .long("timeout-secs")
+                .env("PJ_DIR_TIMEOUT_SECS")
+                .help("How long a poll waits for its counterparty [default: 30]"),
+        )
+        .arg(
+            Arg::new("mailbox_ttl_secs")
+                .long("mailbox-ttl-secs")
+                .env("PJ_DIR_MAILBOX_TTL_SECS")
+                .help("How long mailbox entries are kept [default: 86400]"),
+        )
+        .arg(
+            Arg::new("max_payload_size")
+                .long("max-payload-size")
+                .env("PJ_DIR_MAX_PAYLOAD_SIZE")
+                .help("The largest payload accepted into a mailbox, in bytes [default: 524288]"),
+        )
+        .arg(
+            Arg::new("max_queued_requests")
+                .long("max-queued-requests")
+                .env("PJ_DIR_MAX_QUEUED_REQUESTS")
+                .help("Distinct sender requests each mailbox may hold [default: 8]"),
+        )
+        .arg(
+            Arg::new("relay_requests_per_sec")
+                .long("relay-requests-per-sec")
+                .env("PJ_DIR_RELAY_REQUESTS_PER_SEC")
+                .help("Requests each OHTTP relay may make per second [default: 100]"),
+        )
+        .arg(
+            Arg::new("mailbox_writes_per_min")
+                .long("mailbox-writes-per-min")
+                .env("PJ_DIR_MAILBOX_WRITES_PER_MIN")
+                .help("Writes each mailbox may receive per minute [default: 30]"),
+        )
+        .arg(
+            Arg::new("max_long_polls_per_mailbox")
+                .long("max-long-polls-per-mailbox")
+                .env("PJ_DIR_MAX_LONG_POLLS_PER_MAILBOX")
+                .help("Long-polls that may wait on one mailbox at once [default: 4]"),
+        )
+        .arg(
+            Arg::new("max_buffered_bytes")
+                .long("max-buffered-bytes")
+                .env("PJ_DIR_MAX_BUFFERED_BYTES")
+                .help("Request bytes held in memory across all requests [default: 67108864]"),
+        )
+        .arg(
+            Arg::new("db_backend")
+                .long("db-backend")
+                .env("PJ_DB_BACKEND")
+                .help("Where to keep mailboxes: redis, memory or file [default: redis]"),
+        )
+        .arg(
+            Arg::new("db_host")
+                .long("db-host")
+                .env("PJ_DB_HOST")
+                .help("The redis host:port [default: localhost:6379]"),
+        )
+        .arg(
+            Arg::new("db_path")
+                .long("db-path")
+                .env("PJ_DB_PATH")
+                .help("The directory of the file backend [default: payjoin-directory-db]"),
+        )
+        .arg(
+            Arg::new("ohttp_keys_path")
+                .long("ohttp-keys-path")
+                .env("PJ_DIR_OHTTP_KEYS_PATH")
+                .help("Where the OHTTP gateway keys are persisted [default: ohttp-keys]"),
+        )
+        .arg(
+            Arg::new("ohttp_key_grace_secs")
+                .long("ohttp-key-grace-secs")
+                .env("PJ_DIR_OHTTP_KEY_GRACE_SECS")
+                .help("How long rotated OHTTP keys keep working [default: 604800]"),
+        )
+        .arg(
+            Arg::new("rotate_ohttp_keys")
+                .long("rotate-ohttp-keys")
+                .env("PJ_DIR_ROTATE_OHTTP_KEYS")
+                .action(ArgAction::SetTrue)
+                .value_parser(BoolishValueParser::new())
+                .help("Rotate to a new OHTTP gateway key on startup"),
+        )
+        .arg(
+            Arg::new("log_format")
+                .long("log-format")
+                .env("PJ_DIR_LOG_FORMAT")
+                .help("Log as human readable text or json [default: text]"),
+        )
+        .arg(
+            Arg::new("relay_gateway")
+                .long("relay-gateway")
+                .env("PJ_RELAY_GATEWAY")
+                .help("Run as an OHTTP relay to this gateway origin instead of a directory"),
+        )
+        .arg(
+            Arg::new("relay_gateway_cert")
+                .long("relay-gateway-cert")
+                .env("PJ_RELAY_GATEWAY_CERT")
+                .help("PEM certificate to trust for --relay-gateway besides the system roots"),
+        )
+        .arg(
+            Arg::new("admin_port")
+                .long("admin-port")
+                .env("PJ_DIR_ADMIN_PORT")
+                .help("Serve the admin API on this port of 127.0.0.1, requires an admin token"),
+        )
+        .arg(
+            Arg::new("admin_token")
+                .long("admin-token")
+                .env("PJ_DIR_ADMIN_TOKEN")
+                .hide_env_values(true)
+                .help("Bearer token for the admin API. Prefer the config file or environment, since flags are visible to other local users"),
+        )
+}
 
-    let timeout_env = env::var("PJ_DIR_TIMEOUT_SECS")
-        .map_or(DEFAULT_TIMEOUT_SECS, |s| s.parse().expect("Invalid timeout"));
-    let timeout = std::time::Duration::from_secs(timeout_env);
+#[cfg(feature = "tls")]
+fn load_tls_config(
+    cert_path: &std::path::Path,
+    key_path: &std::path::Path,
+) -> anyhow::Result<(Vec<Vec<u8>>, Vec<u8>)> {
+    use std::fs::File;
+    use std::io::BufReader;
 
-    let db_host = env::var("PJ_DB_HOST").unwrap_or_else(|_| DEFAULT_DB_HOST.to_string());
+    let cert_chain = load_certs(cert_path)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", key_path.display()))?;
+    Ok((cert_chain, key.secret_der().to_vec()))
+}
 
-    payjoin_directory::listen_tcp(dir_port, db_host, timeout).await
+#[cfg(feature = "tls")]
+fn load_certs(path: &std::path::Path) -> anyhow::Result<Vec<Vec<u8>>> {
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(path)?))
+        .map(|cert| cert.map(|cert| cert.to_vec()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
 }
 
-fn init_logging() {
+fn init_logging(log_format: LogFormat) {
     let env_filter =
         EnvFilter::builder().with_default_directive(LevelFilter::INFO.into()).from_env_lossy();
 
-    tracing_subscriber::fmt().with_target(true).with_level(true).with_env_filter(env_filter).init();
+    let subscriber =
+        tracing_subscriber::fmt().with_target(true).with_level(true).with_env_filter(env_filter);
+    match log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
 
     println!("Logging initialized");
 }
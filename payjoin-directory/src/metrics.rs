@@ -0,0 +1,108 @@
+use std::time::Instant;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use crate::rate_limit::Limit;
+
+const NAMESPACE: &str = "payjoin_directory";
+
+/// Metrics served at `/metrics` in the Prometheus text format.
+///
+/// Labels only ever hold fixed strings like an error kind or a mailbox column. Subdirectory
+/// ids must never become label values, since that would let anyone who can scrape the
+/// directory follow sessions.
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    errors: IntCounterVec,
+    v1_fallback_posts: IntCounter,
+    rate_limited: IntCounterVec,
+    long_poll_timeouts: IntCounterVec,
+    mailbox_entry_bytes: HistogramVec,
+    store_latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None)
+            .expect("namespace is a valid metric name");
+        let errors = IntCounterVec::new(
+            Opts::new("errors_total", "Requests answered with an error, by kind"),
+            &["kind"],
+        )
+        .expect("valid metric");
+        let v1_fallback_posts =
+            IntCounter::new("v1_fallback_posts_total", "Payjoin v1 requests posted to a mailbox")
+                .expect("valid metric");
+        let rate_limited = IntCounterVec::new(
+            Opts::new("rate_limited_total", "Requests refused with 429, by the limit exceeded"),
+            &["limit"],
+        )
+        .expect("valid metric");
+        let long_poll_timeouts = IntCounterVec::new(
+            Opts::new("long_poll_timeouts_total", "Long-polls that timed out with no entry"),
+            &["column"],
+        )
+        .expect("valid metric");
+        let mailbox_entry_bytes = HistogramVec::new(
+            HistogramOpts::new("mailbox_entry_bytes", "Size of entries written to mailboxes")
+                .buckets(prometheus::exponential_buckets(256.0, 2.0, 10).expect("valid buckets")),
+            &["column"],
+        )
+        .expect("valid metric");
+        let store_latency = HistogramVec::new(
+            HistogramOpts::new("store_latency_seconds", "Latency of mailbox store operations"),
+            &["op"],
+        )
+        .expect("valid metric");
+
+        for metric in [
+            Box::new(errors.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(v1_fallback_posts.clone()),
+            Box::new(rate_limited.clone()),
+            Box::new(long_poll_timeouts.clone()),
+            Box::new(mailbox_entry_bytes.clone()),
+            Box::new(store_latency.clone()),
+        ] {
+            registry.register(metric).expect("metric names are unique");
+        }
+        Self {
+            registry,
+            errors,
+            v1_fallback_posts,
+            rate_limited,
+            long_poll_timeouts,
+            mailbox_entry_bytes,
+            store_latency,
+        }
+    }
+
+    pub fn error(&self, kind: &str) { self.errors.with_label_values(&[kind]).inc() }
+
+    pub fn v1_fallback_post(&self) { self.v1_fallback_posts.inc() }
+
+    pub fn rate_limited(&self, limit: Limit) {
+        self.rate_limited.with_label_values(&[limit.as_str()]).inc()
+    }
+
+    pub fn long_poll_timeout(&self, column: &str) {
+        self.long_poll_timeouts.with_label_values(&[column]).inc()
+    }
+
+    pub fn mailbox_entry(&self, column: &str, len: usize) {
+        self.mailbox_entry_bytes.with_label_values(&[column]).observe(len as f64)
+    }
+
+    pub fn store_op(&self, op: &str, started: Instant) {
+        self.store_latency.with_label_values(&[op]).observe(started.elapsed().as_secs_f64())
+    }
+
+    /// Render every metric in the Prometheus text exposition format
+    pub fn encode(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buf = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics::Metrics;
+
+pub const DEFAULT_RELAY_REQUESTS_PER_SEC: u32 = 100;
+pub const DEFAULT_MAILBOX_WRITES_PER_MIN: u32 = 30;
+pub const DEFAULT_MAX_LONG_POLLS_PER_MAILBOX: usize = 4;
+pub const DEFAULT_MAX_BUFFERED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Buckets are forgotten once this many are tracked and they have refilled
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Limits on how much of the directory a single relay or mailbox may use.
+///
+/// The directory only ever sees the OHTTP relays its clients go through, so per-client
+/// limits are enforced per relay address. Mailbox limits apply to each side of a mailbox
+/// separately, so senders can't use up the receiver's share.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// Requests a relay may make per second, with a burst of as many
+    pub relay_requests_per_sec: u32,
+    /// Writes each side of a mailbox may receive per minute, with a burst of as many
+    pub mailbox_writes_per_min: u32,
+    /// Long-polls that may wait on each side of a mailbox at once
+    pub max_long_polls_per_mailbox: usize,
+    /// Bytes of request bodies the directory holds in memory across every request
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            relay_requests_per_sec: DEFAULT_RELAY_REQUESTS_PER_SEC,
+            mailbox_writes_per_min: DEFAULT_MAILBOX_WRITES_PER_MIN,
+            max_long_polls_per_mailbox: DEFAULT_MAX_LONG_POLLS_PER_MAILBOX,
+            max_buffered_bytes: DEFAULT_MAX_BUFFERED_BYTES,
+        }
+    }
+}
+
+/// Which limit a request ran into, used as a metric label
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Limit {
+    Relay,
+    MailboxWrites,
+    LongPolls,
+    Memory,
+}
+
+impl Limit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Limit::Relay => "relay",
+            Limit::MailboxWrites => "mailbox_writes",
+            Limit::LongPolls => "long_polls",
+            Limit::Memory => "memory",
+        }
+    }
+}
+
+/// Enforces [`RateLimits`] across every connection a directory serves, counting every
+/// refusal in the `rate_limited_total` metric
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    metrics: Metrics,
+    relays: Arc<TokenBuckets<IpAddr>>,
+    mailbox_writes: Arc<TokenBuckets<String>>,
+    long_polls: Arc<ConcurrencyLimit>,
+    buffered_bytes: Arc<Semaphore>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &RateLimits, metrics: Metrics) -> Self {
+        Self {
+            metrics,
+            relays: Arc::new(TokenBuckets::new(
+                limits.relay_requests_per_sec,
+                Duration::from_secs(1),
+            )),
+            mailbox_writes: Arc::new(TokenBuckets::new(
+                limits.mailbox_writes_per_min,
+                Duration::from_secs(60),
+            )),
+            long_polls: Arc::new(ConcurrencyLimit::new(limits.max_long_polls_per_mailbox)),
+            buffered_bytes: Arc::new(Semaphore::new(
+                limits.max_buffered_bytes.min(Semaphore::MAX_PERMITS),
+            )),
+        }
+    }
+
+    pub fn check_relay(&self, relay: IpAddr) -> Result<(), Limit> {
+        self.relays.take(relay).then_some(()).ok_or_else(|| self.refuse(Limit::Relay))
+    }
+
+    /// Count a write against the side of a mailbox named by `mailbox_side`
+    pub fn check_mailbox_write(&self, mailbox_side: &str) -> Result<(), Limit> {
+        let allowed = self.mailbox_writes.take(mailbox_side.to_owned());
+        allowed.then_some(()).ok_or_else(|| self.refuse(Limit::MailboxWrites))
+    }
+
+    /// Count a long-poll against the side of a mailbox named by `mailbox_side` until the
+    /// returned guard is dropped
+    pub fn start_long_poll(&self, mailbox_side: &str) -> Result<LongPollGuard, Limit> {
+        self.long_polls.clone().acquire(mailbox_side).ok_or_else(|| self.refuse(Limit::LongPolls))
+    }
+
+    /// Read a request body into memory, reserving its size from the global memory budget
+    /// until the returned permit is dropped.
+    pub async fn buffer<B>(
+        &self,
+        body: B,
+    ) -> Result<(Bytes, Option<OwnedSemaphorePermit>), BufferError>
+    where
+        B: Body<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let mut buf = Vec::new();
+        let mut reserved: Option<OwnedSemaphorePermit> = None;
+        tokio::pin!(body);
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(|e| BufferError::Body(e.into()))?;
+            // Trailers carry nothing the directory reads
+            let Ok(chunk) = frame.into_data() else { continue };
+            let permit = self.reserve(chunk.len())?;
+            match reserved.as_mut() {
+                Some(reserved) => reserved.merge(permit),
+                None => reserved = Some(permit),
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        Ok((buf.into(), reserved))
+    }
+
+    /// Reserve `bytes` from the global memory budget until the returned permit is dropped,
+    /// for bodies that are consumed as they stream in rather than buffered whole.
+    pub fn reserve(&self, bytes: usize) -> Result<OwnedSemaphorePermit, BufferError> {
+        u32::try_from(bytes)
+            .ok()
+            .and_then(|n| self.buffered_bytes.clone().try_acquire_many_owned(n).ok())
+            .ok_or_else(|| {
+                self.refuse(Limit::Memory);
+                BufferError::Limited
+            })
+    }
+
+    fn refuse(&self, limit: Limit) -> Limit {
+        self.metrics.rate_limited(limit);
+        limit
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum BufferError {
+    Body(Box<dyn std::error::Error + Send + Sync>),
+    Limited,
+}
+
+/// Token buckets keyed by client, each holding up to `capacity` tokens and refilling
+/// completely every `period`
+struct TokenBuckets<K> {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<K, (f64, Instant)>>,
+}
+
+impl<K: Hash + Eq> TokenBuckets<K> {
+    fn new(capacity: u32, period: Duration) -> Self {
+        Self {
+            capacity: f64::from(capacity),
+            refill_per_sec: f64::from(capacity) / period.as_secs_f64(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from `key`'s bucket, returning whether one was available
+    fn take(&self, key: K) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("poisoned");
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.capacity);
+        }
+        let bucket = buckets.entry(key).or_insert((self.capacity, now));
+        let tokens = self.refill(bucket, now);
+        if tokens < 1.0 {
+            return false;
+        }
+        *bucket = (tokens - 1.0, now);
+        true
+    }
+
+    fn refill(&self, (tokens, updated_at): &(f64, Instant), now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(*updated_at).as_secs_f64();
+        (tokens + elapsed * self.refill_per_sec).min(self.capacity)
+    }
+}
+
+/// Counts concurrent users of each key, refusing more than `max` at once
+struct ConcurrencyLimit {
+    max: usize,
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+impl ConcurrencyLimit {
+    fn new(max: usize) -> Self { Self { max, counts: Mutex::new(HashMap::new()) } }
+
+    fn acquire(self: Arc<Self>, key: &str) -> Option<LongPollGuard> {
+        let mut counts = self.counts.lock().expect("poisoned");
+        let count = counts.entry(key.to_owned()).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        drop(counts);
+        Some(LongPollGuard { limit: self, key: key.to_owned() })
+    }
+}
+
+pub(crate) struct LongPollGuard {
+    limit: Arc<ConcurrencyLimit>,
+    key: String,
+}
+
+impl Drop for LongPollGuard {
+    fn drop(&mut self) {
+        let mut counts = self.limit.counts.lock().expect("poisoned");
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http_body_util::Full;
+
+    use super::*;
+
+    #[test]
+    fn token_buckets_refuse_bursts_beyond_capacity() {
+        let buckets = TokenBuckets::new(2, Duration::from_secs(60));
+        assert!(buckets.take("a"));
+        assert!(buckets.take("a"));
+        assert!(!buckets.take("a"));
+        assert!(buckets.take("b"), "buckets are per key");
+    }
+
+    #[test]
+    fn long_polls_are_capped_per_mailbox() {
+        let limits = RateLimits { max_long_polls_per_mailbox: 1, ..Default::default() };
+        let limiter = RateLimiter::new(&limits, Metrics::new());
+        let guard = limiter.start_long_poll("a").unwrap();
+        assert_eq!(limiter.start_long_poll("a").err(), Some(Limit::LongPolls));
+        assert!(limiter.start_long_poll("b").is_ok());
+        drop(guard);
+        assert!(limiter.start_long_poll("a").is_ok());
+    }
+
+    #[tokio::test]
+    async fn buffered_bytes_are_bounded_until_released() {
+        let limits = RateLimits { max_buffered_bytes: 8, ..Default::default() };
+        let limiter = RateLimiter::new(&limits, Metrics::new());
+        let (bytes, permit) = limiter.buffer(Full::from(vec![0; 6])).await.unwrap();
+        assert_eq!(bytes.len(), 6);
+        assert!(matches!(limiter.buffer(Full::from(vec![0; 6])).await, Err(BufferError::Limited)));
+        drop(permit);
+        assert!(limiter.buffer(Full::from(vec![0; 6])).await.is_ok());
+    }
+}
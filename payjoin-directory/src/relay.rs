@@ -0,0 +1,379 @@
+//! A minimal OHTTP relay that forwards to a single gateway.
+//!
+//! The relay sees client addresses but not request contents, the gateway sees contents but
+//! only the relay's address. To keep that split the relay never logs or forwards anything
+//! identifying its clients: forwarded requests carry only the encapsulated body and its
+//! content headers.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chunked_ohttp::CHUNKED_REQ_CONTENT_TYPE;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Body, Bytes};
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_LENGTH, CONTENT_TYPE,
+};
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::service::TowerToHyperService;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, warn};
+
+use crate::ShutdownHandle;
+
+const OHTTP_REQ_CONTENT_TYPE: &str = "message/ohttp-req";
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Settings for an OHTTP relay
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    /// The address to accept connections on
+    pub bind_addr: SocketAddr,
+    /// The scheme and authority of the OHTTP gateway requests are forwarded to
+    pub gateway_origin: Uri,
+    /// DER encoded certificates to trust for the gateway in addition to the system roots
+    pub gateway_roots: Vec<Vec<u8>>,
+}
+
+/// Relay requests to the configured gateway until `shutdown` is triggered and in-flight
+/// requests are answered
+pub async fn listen_relay(
+    config: RelayConfig,
+    shutdown: ShutdownHandle,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let relay = Relay::new(&config)?;
+    // The peer address is deliberately dropped
+    let new_service = |_| {
+        let relay = relay.clone();
+        let handler = move |req| handle_relay(req, relay.clone());
+        TowerToHyperService::new(tower::service_fn(handler))
+    };
+
+    let listener = TcpListener::bind(config.bind_addr).await?;
+    info!(
+        "Payjoin Relay awaiting HTTP connection at {}, forwarding to {}",
+        config.bind_addr, config.gateway_origin
+    );
+    crate::server::serve(listener, |stream| async { Ok(stream) }, new_service, shutdown).await;
+    info!("Payjoin Relay stopped");
+    Ok(())
+}
+
+/// State shared by every connection to a relay
+#[derive(Clone)]
+struct Relay {
+    gateway: Arc<Gateway>,
+    client: Client<HttpsConnector<HttpConnector>, BoxBody<Bytes, BoxError>>,
+}
+
+impl Relay {
+    fn new(config: &RelayConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let gateway = Gateway::new(&config.gateway_origin)?;
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(client_tls_config(&config.gateway_roots)?)
+            .https_or_http()
+            .enable_all_versions()
+            .build();
+        let client = Client::builder(TokioExecutor::new()).build(connector);
+        Ok(Self { gateway: Arc::new(gateway), client })
+    }
+}
+
+/// The one origin a relay forwards to
+#[derive(Debug, PartialEq, Eq)]
+struct Gateway {
+    scheme: String,
+    host: String,
+    port: u16,
+}
+
+impl Gateway {
+    fn new(origin: &Uri) -> Result<Self, Box<dyn std::error::Error>> {
+        let scheme = origin.scheme_str().unwrap_or("https").to_ascii_lowercase();
+        let default_port = match scheme.as_str() {
+            "https" => 443,
+            "http" => 80,
+            _ => return Err(format!("Unsupported gateway scheme: {}", scheme).into()),
+        };
+        let authority = origin.authority().ok_or("Gateway origin must have an authority")?;
+        Ok(Self {
+            scheme,
+            host: authority.host().to_ascii_lowercase(),
+            port: authority.port_u16().unwrap_or(default_port),
+        })
+    }
+
+    /// Whether a CONNECT target names this gateway. Tunnels to anywhere else are refused
+    /// so the relay can't be used as an open proxy.
+    fn is_connect_target(&self, target: &Uri) -> bool {
+        match target.authority() {
+            Some(authority) =>
+                authority.host().eq_ignore_ascii_case(&self.host)
+                    && authority.port_u16() == Some(self.port),
+            None => false,
+        }
+    }
+
+    fn forward_uri(&self, req_uri: &Uri) -> Result<Uri, hyper::http::Error> {
+        let path_and_query = req_uri.path_and_query().map_or("/", |pq| pq.as_str());
+        Uri::builder()
+            .scheme(self.scheme.as_str())
+            .authority(format!("{}:{}", self.host, self.port))
+            .path_and_query(path_and_query)
+            .build()
+    }
+}
+
+async fn handle_relay<B>(
+    req: Request<B>,
+    relay: Relay,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error>
+where
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    let mut response = match (req.method(), req.uri().path()) {
+        (&Method::OPTIONS, _) => handle_preflight(),
+        (&Method::CONNECT, _) => handle_connect(req, &relay.gateway),
+        (&Method::GET, "/health") => status(StatusCode::OK),
+        (&Method::POST, _) => handle_forward(req, &relay).await,
+        _ => status(StatusCode::NOT_FOUND),
+    };
+    response.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    Ok(response)
+}
+
+fn handle_preflight() -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = status(StatusCode::NO_CONTENT);
+    let headers = response.headers_mut();
+    headers
+        .insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("CONNECT, OPTIONS, POST"));
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static("Content-Type, Content-Length"),
+    );
+    response
+}
+
+/// Forward an encapsulated request, chunked or not, with nothing but its content headers
+async fn handle_forward<B>(req: Request<B>, relay: &Relay) -> Response<BoxBody<Bytes, hyper::Error>>
+where
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    let (parts, body) = req.into_parts();
+    let content_type = match parts.headers.get(CONTENT_TYPE) {
+        Some(ct) if ct == OHTTP_REQ_CONTENT_TYPE || ct == CHUNKED_REQ_CONTENT_TYPE => ct.clone(),
+        _ => return status(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+    };
+    let uri = match relay.gateway.forward_uri(&parts.uri) {
+        Ok(uri) => uri,
+        Err(_) => return status(StatusCode::BAD_REQUEST),
+    };
+    let mut forward = Request::post(uri)
+        .header(CONTENT_TYPE, content_type)
+        .body(body.map_err(Into::into).boxed())
+        .expect("uri and headers are valid");
+    if let Some(content_length) = parts.headers.get(CONTENT_LENGTH) {
+        forward.headers_mut().insert(CONTENT_LENGTH, content_length.clone());
+    }
+
+    match relay.client.request(forward).await {
+        Ok(gateway_res) => {
+            let (parts, body) = gateway_res.into_parts();
+            let mut response = Response::new(body.boxed());
+            *response.status_mut() = parts.status;
+            for header in [CONTENT_TYPE, CONTENT_LENGTH] {
+                if let Some(value) = parts.headers.get(&header) {
+                    response.headers_mut().insert(header, value.clone());
+                }
+            }
+            response
+        }
+        Err(e) => {
+            warn!("Failed to reach the gateway: {}", e);
+            status(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+/// Open a TCP tunnel to the gateway, as used to fetch its keys over TLS end to end
+fn handle_connect<B: Send + 'static>(
+    req: Request<B>,
+    gateway: &Gateway,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    if !gateway.is_connect_target(req.uri()) {
+        debug!("Refused CONNECT to a host other than the gateway");
+        return status(StatusCode::FORBIDDEN);
+    }
+    // IPv6 hosts keep their brackets in a URI but can't be resolved with them
+    let host = gateway.host.trim_start_matches('[').trim_end_matches(']');
+    let target = (host.to_owned(), gateway.port);
+    tokio::spawn(async move {
+        let mut upgraded = match hyper::upgrade::on(req).await {
+            Ok(upgraded) => TokioIo::new(upgraded),
+            Err(e) => {
+                error!("CONNECT upgrade failed: {}", e);
+                return;
+            }
+        };
+        match TcpStream::connect(target).await {
+            Ok(mut gateway) =>
+                if let Err(e) = tokio::io::copy_bidirectional(&mut upgraded, &mut gateway).await {
+                    debug!("CONNECT tunnel closed: {}", e);
+                },
+            Err(e) => warn!("Failed to reach the gateway: {}", e),
+        }
+    });
+    status(StatusCode::OK)
+}
+
+fn client_tls_config(
+    extra_roots: &[Vec<u8>],
+) -> Result<rustls::ClientConfig, Box<dyn std::error::Error>> {
+    let mut roots = rustls::RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            let (added, ignored) = roots.add_parsable_certificates(certs);
+            debug!("Loaded {} system root certificates, ignored {}", added, ignored);
+        }
+        Err(e) => warn!("Failed to load system root certificates: {}", e),
+    }
+    for cert in extra_roots {
+        roots.add(cert.clone().into())?;
+    }
+    Ok(rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth())
+}
+
+fn status(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = Response::new(Empty::new().map_err(|never| match never {}).boxed());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use http_body_util::Full;
+
+    use super::*;
+
+    #[test]
+    fn connect_is_only_allowed_to_the_gateway() {
+        let gateway = Gateway::new(&Uri::from_static("https://Gateway.example")).unwrap();
+        assert!(gateway.is_connect_target(&Uri::from_static("gateway.example:443")));
+        assert!(!gateway.is_connect_target(&Uri::from_static("gateway.example:80")));
+        assert!(!gateway.is_connect_target(&Uri::from_static("other.example:443")));
+        assert!(!gateway.is_connect_target(&Uri::from_static("/health")));
+
+        let gateway = Gateway::new(&Uri::from_static("http://127.0.0.1:8080")).unwrap();
+        assert!(gateway.is_connect_target(&Uri::from_static("127.0.0.1:8080")));
+        assert!(Gateway::new(&Uri::from_static("ftp://gateway.example")).is_err());
+    }
+
+    #[test]
+    fn forwarding_keeps_only_the_path() {
+        let gateway = Gateway::new(&Uri::from_static("https://gateway.example")).unwrap();
+        let uri = gateway.forward_uri(&Uri::from_static("http://relay.example/a?b")).unwrap();
+        assert_eq!(uri, Uri::from_static("https://gateway.example:443/a?b"));
+    }
+
+    #[tokio::test]
+    async fn requests_must_be_encapsulated() {
+        let config = RelayConfig {
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            gateway_origin: Uri::from_static("http://127.0.0.1:1"),
+            gateway_roots: vec![],
+        };
+        let relay = Relay::new(&config).unwrap();
+        let req =
+            Request::post("/").header(CONTENT_TYPE, "text/plain").body(Full::<Bytes>::default());
+        let res = handle_relay(req.unwrap(), relay.clone()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let req = Request::post("/").header(CONTENT_TYPE, OHTTP_REQ_CONTENT_TYPE);
+        let res = handle_relay(req.body(Full::<Bytes>::default()).unwrap(), relay).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    /// Poll `/health` until the server at `addr` answers, rather than guessing how long
+    /// startup takes.
+    async fn wait_until_healthy(addr: SocketAddr) {
+        let client = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+        let uri: Uri = format!("http://{}/health", addr).parse().unwrap();
+        for _ in 0..500 {
+            match client.get(uri.clone()).await {
+                Ok(res) if res.status() == StatusCode::OK => return,
+                _ => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        }
+        panic!("{} never became healthy", addr);
+    }
+
+    #[tokio::test]
+    async fn relays_to_a_directory() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{Config, DbBackend, GatewayKeys};
+
+        let free_port = || std::net::TcpListener::bind("[::1]:0").unwrap().local_addr().unwrap();
+        let (directory_addr, relay_addr) = (free_port(), free_port());
+        let shutdown = ShutdownHandle::new();
+        let directory_config = Config {
+            bind_addr: directory_addr,
+            db_backend: DbBackend::Memory,
+            ..Config::default()
+        };
+        let relay_config = RelayConfig {
+            bind_addr: relay_addr,
+            gateway_origin: format!("http://[::1]:{}", directory_addr.port()).parse().unwrap(),
+            gateway_roots: vec![],
+        };
+        tokio::spawn({
+            let (ohttp_keys, shutdown) = (GatewayKeys::ephemeral().unwrap(), shutdown.clone());
+            async move {
+                crate::listen_tcp(directory_config, ohttp_keys, shutdown)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        });
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { listen_relay(relay_config, shutdown).await.map_err(|e| e.to_string()) }
+        });
+        wait_until_healthy(directory_addr).await;
+        wait_until_healthy(relay_addr).await;
+
+        // A malformed encapsulated request reaches the gateway and its rejection comes back
+        let req = Request::post(format!("http://{}/", relay_addr))
+            .header(CONTENT_TYPE, OHTTP_REQ_CONTENT_TYPE)
+            .body(Full::<Bytes>::from("not ohttp"))
+            .unwrap();
+        let res = Client::builder(TokioExecutor::new()).build_http().request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/problem+json");
+
+        // CONNECT tunnels straight through to the gateway
+        let mut tunnel = TcpStream::connect(relay_addr).await.unwrap();
+        let gateway = format!("[::1]:{}", directory_addr.port());
+        let connect = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", gateway);
+        tunnel.write_all(connect.as_bytes()).await.unwrap();
+        let mut buf = [0; 1024];
+        let n = tunnel.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200"));
+        let health =
+            format!("GET /health HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", gateway);
+        tunnel.write_all(health.as_bytes()).await.unwrap();
+        let mut res = vec![];
+        tunnel.read_to_end(&mut res).await.unwrap();
+        assert!(res.starts_with(b"HTTP/1.1 200"));
+
+        shutdown.shutdown();
+    }
+}
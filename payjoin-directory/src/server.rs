@@ -0,0 +1,96 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::body::{Body, Incoming};
+use hyper::service::Service;
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+
+use crate::ShutdownHandle;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Accept connections until `shutdown` is triggered, then wait for the open ones to answer
+/// their in-flight requests.
+///
+/// `handshake` turns each accepted stream into the transport to serve, e.g. by completing a
+/// TLS handshake, and `new_service` makes the service for a connection from its peer's
+/// address. Each connection speaks HTTP/1.1 or HTTP/2, as the client chooses, so a relay can
+/// multiplex any number of long-polls over a single HTTP/2 connection.
+pub(crate) async fn serve<H, F, I, M, S, B>(
+    listener: TcpListener,
+    handshake: H,
+    new_service: M,
+    shutdown: ShutdownHandle,
+) where
+    H: Fn(TcpStream) -> F,
+    F: Future<Output = std::io::Result<I>> + Send + 'static,
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    M: Fn(SocketAddr) -> S,
+    S: Service<Request<Incoming>, Response = Response<B>> + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    // Every connection holds a sender, so the channel closes once the last one is done
+    let (open, mut closed) = mpsc::channel::<()>(1);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of file descriptors, which only closing connections fixes
+                    error!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.wait() => break,
+        };
+        let (transport, service) = (handshake(stream), new_service(peer));
+        let (open, shutdown) = (open.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            match transport.await {
+                Ok(io) => serve_connection(io, service, shutdown).await,
+                Err(e) => debug!("Handshake failed: {}", e),
+            }
+            drop(open);
+        });
+    }
+    drop(open);
+    let _ = closed.recv().await;
+}
+
+async fn serve_connection<I, S, B>(io: I, service: S, shutdown: ShutdownHandle)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response = Response<B>> + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+    tokio::pin!(conn);
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        _ = shutdown.wait() => {
+            // Finish the requests already received but take no more
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(e) = result {
+        debug!("Connection closed with an error: {}", e);
+    }
+}
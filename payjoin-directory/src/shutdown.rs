@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Stops a running directory.
+///
+/// Once triggered the directory stops accepting connections and answers pending long-polls
+/// as if they had timed out, so clients see a clean `202 Accepted` and poll again elsewhere
+/// or later. The `listen_tcp` functions return when the remaining requests are done.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    pub fn new() -> Self { Self(Arc::new(watch::channel(false).0)) }
+
+    /// Begin shutting down every directory this handle was given to
+    pub fn shutdown(&self) { self.0.send_replace(true); }
+
+    pub fn is_shutdown(&self) -> bool { *self.0.borrow() }
+
+    /// Resolve once shutdown has been triggered
+    pub(crate) async fn wait(&self) {
+        let mut triggered = self.0.subscribe();
+        // The sender lives as long as self, so this can't fail
+        let _ = triggered.wait_for(|triggered| *triggered).await;
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self { Self::new() }
+}
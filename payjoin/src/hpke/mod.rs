@@ -0,0 +1,300 @@
+//! [RFC 9180] Hybrid Public Key Encryption in its base and auth modes, with
+//! DHKEM(secp256k1, HKDF-SHA256), HKDF-SHA256 and ChaCha20Poly1305.
+//!
+//! The KEM follows [draft-wahby-cfrg-hpke-kem-secp256k1] so that the secp256k1 keys which
+//! already identify v2 sessions can be used as HPKE keys. `contrib/hpke_test_vectors.py`
+//! generates the test vectors independently.
+//!
+//! [RFC 9180]: https://www.rfc-editor.org/rfc/rfc9180
+//! [draft-wahby-cfrg-hpke-kem-secp256k1]: https://datatracker.ietf.org/doc/draft-wahby-cfrg-hpke-kem-secp256k1/
+
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{ecdh, PublicKey, Secp256k1, SecretKey};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+use crate::v2::HpkeError;
+
+const KEM_ID: u16 = 0x0016;
+const KDF_ID: u16 = 0x0001;
+const AEAD_ID: u16 = 0x0003;
+
+const MODE_BASE: u8 = 0x00;
+const MODE_AUTH: u8 = 0x02;
+
+/// Length of a serialized public key, which is also the length of an encapsulated key
+pub(crate) const N_PK: usize = 65;
+const N_SECRET: usize = 32;
+const N_K: usize = 32;
+const N_N: usize = 12;
+const N_H: usize = 32;
+
+/// The sender's serialized ephemeral public key, which the receiver needs to decapsulate
+pub(crate) type EncapsulatedKey = [u8; N_PK];
+
+/// Set up a context to encrypt to `pk_r`, in auth mode if the sender's key `sk_s` is given
+/// and in base mode otherwise.
+///
+/// The ephemeral key is derived from `ikm_e`, which must be secret and never reused for a
+/// different plaintext.
+pub(crate) fn setup_sender(
+    pk_r: &PublicKey,
+    info: &[u8],
+    sk_s: Option<&SecretKey>,
+    ikm_e: &[u8],
+) -> Result<(EncapsulatedKey, Context), HpkeError> {
+    let secp = Secp256k1::signing_only();
+    let sk_e = derive_key_pair(ikm_e)?;
+    let enc = sk_e.public_key(&secp).serialize_uncompressed();
+    let mut dh = dh(pk_r, &sk_e).to_vec();
+    let mut kem_context = [&enc[..], &pk_r.serialize_uncompressed()].concat();
+    let mode = match sk_s {
+        Some(sk_s) => {
+            dh.extend(self::dh(pk_r, sk_s));
+            kem_context.extend(sk_s.public_key(&secp).serialize_uncompressed());
+            MODE_AUTH
+        }
+        None => MODE_BASE,
+    };
+    let shared_secret = extract_and_expand(&dh, &kem_context);
+    Ok((enc, Context::new(KEM_ID, mode, &shared_secret, info)))
+}
+
+/// Set up a context to decrypt what was encrypted to `sk_r`, in auth mode if the sender's
+/// key `pk_s` is given and in base mode otherwise.
+pub(crate) fn setup_receiver(
+    enc: &EncapsulatedKey,
+    sk_r: &SecretKey,
+    info: &[u8],
+    pk_s: Option<&PublicKey>,
+) -> Result<Context, HpkeError> {
+    let pk_e = PublicKey::from_slice(enc)?;
+    let pk_r = sk_r.public_key(&Secp256k1::signing_only());
+    let mut dh = dh(&pk_e, sk_r).to_vec();
+    let mut kem_context = [&enc[..], &pk_r.serialize_uncompressed()].concat();
+    let mode = match pk_s {
+        Some(pk_s) => {
+            dh.extend(self::dh(pk_s, sk_r));
+            kem_context.extend(pk_s.serialize_uncompressed());
+            MODE_AUTH
+        }
+        None => MODE_BASE,
+    };
+    let shared_secret = extract_and_expand(&dh, &kem_context);
+    Ok(Context::new(KEM_ID, mode, &shared_secret, info))
+}
+
+/// Keys and nonces for the messages of one HPKE session, in order
+pub(crate) struct Context {
+    key: [u8; N_K],
+    base_nonce: [u8; N_N],
+    seq: u64,
+}
+
+impl Context {
+    /// The key schedule, with the KEM as a parameter to check it against other suites' vectors
+    fn new(kem_id: u16, mode: u8, shared_secret: &[u8; N_SECRET], info: &[u8]) -> Self {
+        let suite_id = hpke_suite_id(kem_id);
+        let psk_id_hash = labeled_extract(&suite_id, b"", b"psk_id_hash", b"");
+        let info_hash = labeled_extract(&suite_id, b"", b"info_hash", info);
+        let key_schedule_context = [&[mode][..], &psk_id_hash, &info_hash].concat();
+        let secret = labeled_extract(&suite_id, shared_secret, b"secret", b"");
+        Self {
+            key: labeled_expand(&suite_id, &secret, b"key", &key_schedule_context),
+            base_nonce: labeled_expand(&suite_id, &secret, b"base_nonce", &key_schedule_context),
+            seq: 0,
+        }
+    }
+
+    pub(crate) fn seal(&mut self, aad: &[u8], pt: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let cipher =
+            ChaCha20Poly1305::new_from_slice(&self.key).map_err(|_| HpkeError::InvalidKeyLength)?;
+        let ct = cipher.encrypt(Nonce::from_slice(&self.nonce()), Payload { msg: pt, aad })?;
+        self.seq += 1;
+        Ok(ct)
+    }
+
+    pub(crate) fn open(&mut self, aad: &[u8], ct: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let cipher =
+            ChaCha20Poly1305::new_from_slice(&self.key).map_err(|_| HpkeError::InvalidKeyLength)?;
+        let pt = cipher.decrypt(Nonce::from_slice(&self.nonce()), Payload { msg: ct, aad })?;
+        self.seq += 1;
+        Ok(pt)
+    }
+
+    fn nonce(&self) -> [u8; N_N] {
+        let mut nonce = self.base_nonce;
+        for (n, s) in nonce[N_N - 8..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        nonce
+    }
+}
+
+fn derive_key_pair(ikm: &[u8]) -> Result<SecretKey, HpkeError> {
+    let suite_id = kem_suite_id();
+    let dkp_prk = labeled_extract(&suite_id, b"", b"dkp_prk", ikm);
+    for counter in 0..=u8::MAX {
+        let candidate: [u8; 32] = labeled_expand(&suite_id, &dkp_prk, b"candidate", &[counter]);
+        // Rejects zero and anything not below the curve order
+        if let Ok(sk) = SecretKey::from_slice(&candidate) {
+            return Ok(sk);
+        }
+    }
+    Err(HpkeError::DeriveKeyPair)
+}
+
+/// The x-coordinate of the shared point
+fn dh(pk: &PublicKey, sk: &SecretKey) -> [u8; 32] {
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&ecdh::shared_secret_point(pk, sk)[..32]);
+    x
+}
+
+fn extract_and_expand(dh: &[u8], kem_context: &[u8]) -> [u8; N_SECRET] {
+    let suite_id = kem_suite_id();
+    let eae_prk = labeled_extract(&suite_id, b"", b"eae_prk", dh);
+    labeled_expand(&suite_id, &eae_prk, b"shared_secret", kem_context)
+}
+
+fn kem_suite_id() -> [u8; 5] {
+    let [a, b] = KEM_ID.to_be_bytes();
+    [b'K', b'E', b'M', a, b]
+}
+
+fn hpke_suite_id(kem_id: u16) -> Vec<u8> {
+    [&b"HPKE"[..], &kem_id.to_be_bytes(), &KDF_ID.to_be_bytes(), &AEAD_ID.to_be_bytes()].concat()
+}
+
+fn labeled_extract(suite_id: &[u8], salt: &[u8], label: &[u8], ikm: &[u8]) -> [u8; N_H] {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(salt);
+    for part in [&b"HPKE-v1"[..], suite_id, label, ikm] {
+        engine.input(part);
+    }
+    hmac::Hmac::from_engine(engine).to_byte_array()
+}
+
+fn labeled_expand<const L: usize>(
+    suite_id: &[u8],
+    prk: &[u8; N_H],
+    label: &[u8],
+    info: &[u8],
+) -> [u8; L] {
+    let length = (L as u16).to_be_bytes();
+    let mut okm = [0u8; L];
+    let mut previous: Option<[u8; N_H]> = None;
+    for (i, chunk) in okm.chunks_mut(N_H).enumerate() {
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(prk);
+        if let Some(previous) = previous {
+            engine.input(&previous);
+        }
+        for part in [&length[..], b"HPKE-v1", suite_id, label, info, &[i as u8 + 1]] {
+            engine.input(part);
+        }
+        let block = hmac::Hmac::from_engine(engine).to_byte_array();
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        previous = Some(block);
+    }
+    okm
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hex::FromHex;
+    use chacha20poly1305::aead::OsRng;
+    use serde_json::Value;
+
+    use super::*;
+
+    const X25519_KEM_ID: u16 = 0x0020;
+
+    fn hex(vector: &Value, field: &str) -> Vec<u8> {
+        Vec::from_hex(vector[field].as_str().unwrap()).unwrap()
+    }
+
+    fn secret_key(vector: &Value, field: &str) -> SecretKey {
+        SecretKey::from_slice(&hex(vector, field)).unwrap()
+    }
+
+    fn public_key(vector: &Value, field: &str) -> PublicKey {
+        PublicKey::from_slice(&hex(vector, field)).unwrap()
+    }
+
+    fn check_encryptions(vector: &Value, sender: &mut Context, receiver: &mut Context) {
+        for encryption in vector["encryptions"].as_array().unwrap() {
+            let seq = encryption["seq"].as_u64().unwrap();
+            sender.seq = seq;
+            receiver.seq = seq;
+            assert_eq!(sender.nonce().to_vec(), hex(encryption, "nonce"));
+            let (aad, pt) = (hex(encryption, "aad"), hex(encryption, "pt"));
+            let ct = sender.seal(&aad, &pt).unwrap();
+            assert_eq!(ct, hex(encryption, "ct"), "seq {}", seq);
+            assert_eq!(receiver.open(&aad, &ct).unwrap(), pt);
+            assert!(receiver.open(&aad, &ct).is_err(), "each nonce opens only its own message");
+        }
+    }
+
+    #[test]
+    fn test_vectors() {
+        let vectors: Vec<Value> = serde_json::from_str(include_str!("test_vectors.json")).unwrap();
+        for vector in &vectors {
+            let mode = vector["mode"].as_u64().unwrap() as u8;
+            let info = hex(vector, "info");
+            let (mut sender, mut receiver) = match vector["kem_id"].as_u64().unwrap() as u16 {
+                // RFC 9180 A.2 checks the key schedule and the AEAD on their own
+                X25519_KEM_ID => {
+                    let shared_secret = hex(vector, "shared_secret").try_into().unwrap();
+                    let new = || Context::new(X25519_KEM_ID, mode, &shared_secret, &info);
+                    (new(), new())
+                }
+                KEM_ID => {
+                    for (ikm, sk) in [("ikmE", "skEm"), ("ikmR", "skRm"), ("ikmS", "skSm")] {
+                        if vector.get(ikm).is_some() {
+                            let derived = derive_key_pair(&hex(vector, ikm)).unwrap();
+                            assert_eq!(derived, secret_key(vector, sk));
+                        }
+                    }
+                    let (sk_s, pk_s) = match mode {
+                        MODE_AUTH =>
+                            (Some(secret_key(vector, "skSm")), Some(public_key(vector, "pkSm"))),
+                        _ => (None, None),
+                    };
+                    let (enc, sender) = setup_sender(
+                        &public_key(vector, "pkRm"),
+                        &info,
+                        sk_s.as_ref(),
+                        &hex(vector, "ikmE"),
+                    )
+                    .unwrap();
+                    assert_eq!(enc.to_vec(), hex(vector, "enc"));
+                    let receiver =
+                        setup_receiver(&enc, &secret_key(vector, "skRm"), &info, pk_s.as_ref())
+                            .unwrap();
+                    (sender, receiver)
+                }
+                kem_id => panic!("unexpected KEM {}", kem_id),
+            };
+            assert_eq!(sender.key.to_vec(), hex(vector, "key"));
+            assert_eq!(sender.base_nonce.to_vec(), hex(vector, "base_nonce"));
+            check_encryptions(vector, &mut sender, &mut receiver);
+        }
+    }
+
+    #[test]
+    fn auth_mode_binds_the_sender() {
+        let secp = Secp256k1::new();
+        let (sk_r, pk_r) = secp.generate_keypair(&mut OsRng);
+        let (sk_s, pk_s) = secp.generate_keypair(&mut OsRng);
+        let (_, impostor) = secp.generate_keypair(&mut OsRng);
+
+        let (enc, mut sender) = setup_sender(&pk_r, b"info", Some(&sk_s), &[7; 32]).unwrap();
+        let ct = sender.seal(b"", b"hello").unwrap();
+        let mut receiver = setup_receiver(&enc, &sk_r, b"info", Some(&pk_s)).unwrap();
+        assert_eq!(receiver.open(b"", &ct).unwrap(), b"hello");
+
+        let mut receiver = setup_receiver(&enc, &sk_r, b"info", Some(&impostor)).unwrap();
+        assert!(receiver.open(b"", &ct).is_err());
+        let mut receiver = setup_receiver(&enc, &sk_r, b"info", None).unwrap();
+        assert!(receiver.open(b"", &ct).is_err());
+    }
+}
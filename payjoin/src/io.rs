@@ -20,7 +20,8 @@ pub async fn fetch_ohttp_keys(
 ) -> Result<OhttpKeys, Error> {
     use reqwest::{Client, Proxy};
 
-    let ohttp_keys_url = payjoin_directory.join("/ohttp-keys")?;
+    // Ask for every configuration, which older directories ignore and answer with just one
+    let ohttp_keys_url = payjoin_directory.join("/ohttp-keys?format=list")?;
     let proxy = Proxy::all(ohttp_relay.as_str())?;
     #[cfg(not(feature = "danger-local-https"))]
     let client = Client::builder().proxy(proxy).build()?;
@@ -32,8 +33,28 @@ pub async fn fetch_ohttp_keys(
         .proxy(proxy)
         .build()?;
     let res = client.get(ohttp_keys_url).send().await?;
+    if !res.status().is_success() {
+        return Err(Error(InternalError::UnexpectedStatusCode(res.status())));
+    }
+    let content_type = res.headers().get(reqwest::header::CONTENT_TYPE);
+    if !content_type.map_or(false, is_ohttp_keys_media_type) {
+        let content_type = content_type.map(|value| String::from_utf8_lossy(value.as_bytes()));
+        return Err(Error(InternalError::UnexpectedContentType(
+            content_type.unwrap_or_default().into_owned(),
+        )));
+    }
     let body = res.bytes().await?.to_vec();
-    OhttpKeys::decode(&body).map_err(|e| Error(InternalError::InvalidOhttpKeys(e.to_string())))
+    OhttpKeys::decode(&body).map_err(|e| Error(InternalError::InvalidOhttpKeys(e)))
+}
+
+/// Whether a `Content-Type` is `application/ohttp-keys`, whatever parameters it carries
+#[cfg(feature = "v2")]
+fn is_ohttp_keys_media_type(content_type: &reqwest::header::HeaderValue) -> bool {
+    content_type
+        .to_str()
+        .ok()
+        .and_then(|value| value.split(';').next())
+        .map_or(false, |essence| essence.trim().eq_ignore_ascii_case("application/ohttp-keys"))
 }
 
 #[derive(Debug)]
@@ -47,7 +68,11 @@ enum InternalError {
     #[cfg(feature = "danger-local-https")]
     Rustls(rustls::Error),
     #[cfg(feature = "v2")]
-    InvalidOhttpKeys(String),
+    UnexpectedStatusCode(reqwest::StatusCode),
+    #[cfg(feature = "v2")]
+    UnexpectedContentType(String),
+    #[cfg(feature = "v2")]
+    InvalidOhttpKeys(crate::v2::OhttpKeysError),
 }
 
 macro_rules! impl_from_error {
@@ -73,6 +98,15 @@ impl std::fmt::Display for Error {
             ParseUrl(e) => e.fmt(f),
             Io(e) => e.fmt(f),
             #[cfg(feature = "v2")]
+            UnexpectedStatusCode(status) =>
+                write!(f, "Payjoin directory responded to the ohttp keys request with {}", status),
+            #[cfg(feature = "v2")]
+            UnexpectedContentType(content_type) => write!(
+                f,
+                "Expected application/ohttp-keys from payjoin directory, got {:?}",
+                content_type
+            ),
+            #[cfg(feature = "v2")]
             InvalidOhttpKeys(e) => {
                 write!(f, "Invalid ohttp keys returned from payjoin directory: {}", e)
             }
@@ -91,7 +125,9 @@ impl std::error::Error for Error {
             ParseUrl(e) => Some(e),
             Io(e) => Some(e),
             #[cfg(feature = "v2")]
-            InvalidOhttpKeys(_) => None,
+            UnexpectedStatusCode(_) | UnexpectedContentType(_) => None,
+            #[cfg(feature = "v2")]
+            InvalidOhttpKeys(e) => Some(e),
             #[cfg(feature = "danger-local-https")]
             Rustls(e) => Some(e),
         }
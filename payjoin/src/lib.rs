@@ -27,14 +27,19 @@ pub use crate::receive::Error;
 #[cfg(feature = "send")]
 pub mod send;
 
+#[cfg(feature = "v2")]
+pub(crate) mod hpke;
 #[cfg(feature = "v2")]
 pub(crate) mod v2;
 #[cfg(feature = "v2")]
-pub use v2::OhttpKeys;
+pub use v2::{OhttpKeys, OhttpKeysError};
 
 #[cfg(feature = "io")]
 pub mod io;
 
+#[cfg(feature = "v2")]
+pub mod persist;
+
 #[cfg(any(feature = "send", feature = "receive"))]
 pub(crate) mod input_type;
 #[cfg(any(feature = "send", feature = "receive"))]
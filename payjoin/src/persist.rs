@@ -0,0 +1,374 @@
+//! Secret keys of v2 sessions, in memory and at rest.
+//!
+//! Sessions hold their secret keys in wrappers that erase them when dropped and never print
+//! them, and share rather than copy them between the requests they make. Sessions serialize their secret keys as-is with serde. Persist them with
+//! [`serialize_encrypted`] instead to have a [`KeyEncryption`] encrypt the keys first, e.g.
+//! with a key from the platform's keystore:
+//!
+//! ```ignore
+//! let json = serialize_encrypted(&session, &keystore)?;
+//! let session: ActiveSession = deserialize_encrypted(&json, &keystore)?;
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+
+use bitcoin::secp256k1::{self, Keypair, Secp256k1, SecretKey};
+use serde::de::{self, DeserializeOwned, Deserializer};
+use serde::ser::{self, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use zeroize::{Zeroize, Zeroizing};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Encrypts secret keys before they are persisted and decrypts them when they are loaded.
+pub trait KeyEncryption {
+    fn encrypt(&self, secret_key: &[u8]) -> Result<Vec<u8>, BoxError>;
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, BoxError>;
+}
+
+impl<K: KeyEncryption + ?Sized> KeyEncryption for Arc<K> {
+    fn encrypt(&self, secret_key: &[u8]) -> Result<Vec<u8>, BoxError> {
+        (**self).encrypt(secret_key)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, BoxError> {
+        (**self).decrypt(ciphertext)
+    }
+}
+
+/// Sessions whose secret key [`serialize_encrypted`] can encrypt
+pub trait EncryptableSession: Serialize + DeserializeOwned + Clone + sealed::Session {}
+
+impl<T: Serialize + DeserializeOwned + Clone + sealed::Session> EncryptableSession for T {}
+
+pub(crate) mod sealed {
+    use zeroize::Zeroizing;
+
+    pub trait Session {
+        /// The JSON pointer to the session's secret key
+        const SECRET_KEY: &'static str;
+
+        /// Replace the secret key with the one made of `secret_bytes`, returning the secret
+        /// bytes of the replaced key
+        fn replace_secret_key(
+            &mut self,
+            secret_bytes: &[u8],
+        ) -> Result<Zeroizing<[u8; 32]>, bitcoin::secp256k1::Error>;
+
+        /// A key of the session's kind as it's persisted without encryption, to stand in for
+        /// the encrypted one while the rest of the session is deserialized
+        fn placeholder_secret_key() -> serde_json::Value;
+    }
+}
+
+/// Stands in for secret keys that aren't persisted in the clear. Any valid key would do.
+const PLACEHOLDER_SECRET_KEY: [u8; 32] = [1; 32];
+
+/// Serialize `session` to JSON with its secret key encrypted by `encryption`.
+///
+/// The key is encrypted before the session is serialized, so it never appears unencrypted
+/// in the JSON or its intermediate values.
+pub fn serialize_encrypted<T: EncryptableSession>(
+    session: &T,
+    encryption: &impl KeyEncryption,
+) -> Result<String, serde_json::Error> {
+    let mut redacted = session.clone();
+    let secret_bytes = redacted
+        .replace_secret_key(&PLACEHOLDER_SECRET_KEY)
+        .expect("the placeholder is a valid secret key");
+    let encrypted = encryption.encrypt(&secret_bytes[..]).map_err(ser::Error::custom)?;
+    let mut json = serde_json::to_value(&redacted)?;
+    *secret_key_slot::<T>(&mut json)? =
+        serde_json::to_value(Persisted::<()>::Encrypted { encrypted })?;
+    serde_json::to_string(&json)
+}
+
+/// Deserialize a session serialized by [`serialize_encrypted`], decrypting its secret key with
+/// `encryption`.
+///
+/// Sessions persisted without encryption still deserialize, so existing sessions can be
+/// loaded and persisted again encrypted.
+pub fn deserialize_encrypted<T: EncryptableSession>(
+    json: &str,
+    encryption: &impl KeyEncryption,
+) -> Result<T, serde_json::Error> {
+    let mut json: Value = serde_json::from_str(json)?;
+    let slot = secret_key_slot::<T>(&mut json)?;
+    let encrypted = match Persisted::<Value>::deserialize(slot.take())? {
+        Persisted::Encrypted { encrypted } => {
+            *slot = T::placeholder_secret_key();
+            Some(encrypted)
+        }
+        Persisted::Plain(plain) => {
+            *slot = plain;
+            None
+        }
+    };
+    let mut session: T = serde_json::from_value(json)?;
+    if let Some(encrypted) = encrypted {
+        let secret_bytes =
+            Zeroizing::new(encryption.decrypt(&encrypted).map_err(de::Error::custom)?);
+        session.replace_secret_key(&secret_bytes).map_err(de::Error::custom)?;
+    }
+    Ok(session)
+}
+
+fn secret_key_slot<T: EncryptableSession>(
+    json: &mut Value,
+) -> Result<&mut Value, serde_json::Error> {
+    json.pointer_mut(T::SECRET_KEY).ok_or_else(|| de::Error::missing_field("secret key"))
+}
+
+/// Replace `key` with the one made of `secret_bytes`, for [`sealed::Session`] implementations
+pub(crate) fn replace_secret_key<K: SecretKeyMaterial>(
+    key: &mut Arc<Secret<K>>,
+    secret_bytes: &[u8],
+) -> Result<Zeroizing<[u8; 32]>, secp256k1::Error> {
+    let replaced =
+        std::mem::replace(key, Arc::new(Secret::new(K::from_secret_bytes(secret_bytes)?)));
+    Ok(replaced.0.secret_bytes())
+}
+
+/// A placeholder key as persisted, for [`sealed::Session`] implementations
+pub(crate) fn placeholder_secret_key<K: SecretKeyMaterial>() -> Value {
+    let key = Secret::new(
+        K::from_secret_bytes(&PLACEHOLDER_SECRET_KEY)
+            .expect("the placeholder is a valid secret key"),
+    );
+    serde_json::to_value(&key).expect("plain keys serialize")
+}
+
+/// A secret key that is erased from memory when dropped and redacted from `Debug` output.
+///
+/// It can't be cloned, so sessions share it in an `Arc` instead of copying it.
+#[derive(PartialEq, Eq)]
+pub(crate) struct Secret<K: SecretKeyMaterial>(K);
+
+impl<K: SecretKeyMaterial> Secret<K> {
+    pub(crate) fn new(key: K) -> Self { Self(key) }
+
+    pub(crate) fn expose(&self) -> &K { &self.0 }
+}
+
+impl<K: SecretKeyMaterial> Drop for Secret<K> {
+    fn drop(&mut self) {
+        // SAFETY: keys are `Copy` byte arrays, so any bytes are a valid key and there is no
+        // drop glue to run on them afterwards
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut self.0 as *mut K as *mut u8,
+                std::mem::size_of::<K>(),
+            )
+        };
+        bytes.zeroize();
+    }
+}
+
+impl<K: SecretKeyMaterial> fmt::Debug for Secret<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Secret(..)") }
+}
+
+/// Secp256k1 key types a [`Secret`] can hold
+pub(crate) trait SecretKeyMaterial: Copy + Sized {
+    /// Whether human-readable formats persist the key as a hex string, like secp256k1 does,
+    /// rather than as an array of bytes
+    const HEX: bool;
+
+    fn secret_bytes(&self) -> Zeroizing<[u8; 32]>;
+
+    fn from_secret_bytes(bytes: &[u8]) -> Result<Self, secp256k1::Error>;
+}
+
+impl SecretKeyMaterial for SecretKey {
+    const HEX: bool = false;
+
+    fn secret_bytes(&self) -> Zeroizing<[u8; 32]> { Zeroizing::new(SecretKey::secret_bytes(self)) }
+
+    fn from_secret_bytes(bytes: &[u8]) -> Result<Self, secp256k1::Error> {
+        SecretKey::from_slice(bytes)
+    }
+}
+
+impl SecretKeyMaterial for Keypair {
+    const HEX: bool = true;
+
+    fn secret_bytes(&self) -> Zeroizing<[u8; 32]> { Zeroizing::new(Keypair::secret_bytes(self)) }
+
+    fn from_secret_bytes(bytes: &[u8]) -> Result<Self, secp256k1::Error> {
+        Keypair::from_seckey_slice(&Secp256k1::signing_only(), bytes)
+    }
+}
+
+/// A key persisted in the clear, in either format a [`SecretKeyMaterial`] persists it in
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Plain {
+    Hex(String),
+    Bytes([u8; 32]),
+}
+
+impl Plain {
+    fn into_key<K: SecretKeyMaterial>(self) -> Result<K, secp256k1::Error> {
+        use bitcoin::hex::FromHex;
+
+        match self {
+            Plain::Hex(mut hex) => {
+                let bytes = <[u8; 32]>::from_hex(&hex).map(Zeroizing::new);
+                hex.zeroize();
+                K::from_secret_bytes(&bytes.map_err(|_| secp256k1::Error::InvalidSecretKey)?[..])
+            }
+            Plain::Bytes(mut bytes) => {
+                let key = K::from_secret_bytes(&bytes);
+                bytes.zeroize();
+                key
+            }
+        }
+    }
+}
+
+/// A key as persisted, encrypted by [`serialize_encrypted`] or in the clear
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Persisted<P> {
+    Encrypted { encrypted: Vec<u8> },
+    Plain(P),
+}
+
+impl<K: SecretKeyMaterial> Serialize for Secret<K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use bitcoin::hex::DisplayHex;
+
+        let bytes = self.0.secret_bytes();
+        if K::HEX && serializer.is_human_readable() {
+            serializer.serialize_str(&Zeroizing::new(bytes.to_lower_hex_string()))
+        } else {
+            bytes.serialize(serializer)
+        }
+    }
+}
+
+impl<'de, K: SecretKeyMaterial> Deserialize<'de> for Secret<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Persisted::<Plain>::deserialize(deserializer)? {
+            Persisted::Plain(plain) => Ok(Self(plain.into_key().map_err(de::Error::custom)?)),
+            Persisted::Encrypted { .. } => Err(de::Error::custom(
+                "secret key is encrypted, load the session with deserialize_encrypted",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// XORs keys with a fixed byte so tests can tell encrypted keys from plain ones
+    struct Xor(u8);
+
+    impl KeyEncryption for Xor {
+        fn encrypt(&self, secret_key: &[u8]) -> Result<Vec<u8>, BoxError> {
+            Ok(secret_key.iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, BoxError> {
+            self.encrypt(ciphertext)
+        }
+    }
+
+    fn keypair() -> Secret<Keypair> {
+        Secret::new(Keypair::from_seckey_slice(&Secp256k1::signing_only(), &[2; 32]).unwrap())
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    struct Session {
+        id: u8,
+        #[serde(with = "shared")]
+        key: Arc<Secret<Keypair>>,
+    }
+
+    /// Serde for a shared key, as sessions serialize theirs
+    mod shared {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            key: &Arc<Secret<Keypair>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            key.serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Arc<Secret<Keypair>>, D::Error> {
+            Secret::deserialize(deserializer).map(Arc::new)
+        }
+    }
+
+    impl sealed::Session for Session {
+        const SECRET_KEY: &'static str = "/key";
+
+        fn replace_secret_key(
+            &mut self,
+            secret_bytes: &[u8],
+        ) -> Result<Zeroizing<[u8; 32]>, secp256k1::Error> {
+            replace_secret_key(&mut self.key, secret_bytes)
+        }
+
+        fn placeholder_secret_key() -> Value { placeholder_secret_key::<Keypair>() }
+    }
+
+    fn session() -> Session { Session { id: 7, key: Arc::new(keypair()) } }
+
+    #[test]
+    fn debug_output_is_redacted() {
+        let key = keypair();
+        let debug = format!("{:?}", key);
+        assert_eq!(debug, "Secret(..)");
+        assert!(!debug.contains(&key.expose().display_secret().to_string()));
+    }
+
+    #[test]
+    fn plain_keys_keep_their_format() {
+        let key = Secret::new(SecretKey::from_slice(&[1; 32]).unwrap());
+        let json = serde_json::to_string(&key).unwrap();
+        assert_eq!(json, serde_json::to_string(&[1u8; 32]).unwrap());
+        assert_eq!(serde_json::from_str::<Secret<SecretKey>>(&json).unwrap(), key);
+    }
+
+    #[test]
+    fn encrypted_keys_roundtrip() {
+        let session = session();
+        let json = serialize_encrypted(&session, &Xor(0xff)).unwrap();
+        assert!(!json.contains(&session.key.expose().display_secret().to_string()));
+        assert!(json.contains("encrypted"));
+
+        let loaded: Session = deserialize_encrypted(&json, &Xor(0xff)).unwrap();
+        assert_eq!(loaded, session);
+        // the session itself still serializes its key as-is
+        assert!(serde_json::to_string(&session)
+            .unwrap()
+            .contains(&session.key.expose().display_secret().to_string()));
+    }
+
+    #[test]
+    fn plain_keys_load_with_encryption() {
+        let session = session();
+        let plain = serde_json::to_string(&session).unwrap();
+        let loaded: Session = deserialize_encrypted(&plain, &Xor(0xff)).unwrap();
+        assert_eq!(loaded, session);
+        let encrypted = serialize_encrypted(&loaded, &Xor(0xff)).unwrap();
+        assert_ne!(plain, encrypted);
+        assert_eq!(deserialize_encrypted::<Session>(&encrypted, &Xor(0xff)).unwrap(), session);
+    }
+
+    #[test]
+    fn encrypted_keys_need_their_encryption() {
+        let json = serialize_encrypted(&session(), &Xor(0xff)).unwrap();
+        assert!(serde_json::from_str::<Session>(&json).is_err());
+        let wrong_key = deserialize_encrypted::<Session>(&json, &Xor(0x0f));
+        assert!(wrong_key.map_or(true, |loaded| loaded != session()));
+    }
+}
@@ -84,6 +84,9 @@ pub(crate) enum InternalRequestError {
     ParsePsbt(bitcoin::psbt::PsbtParseError),
     #[cfg(feature = "v2")]
     Utf8(std::string::FromUtf8Error),
+    /// A reusable session can only reply to HPKE requests, each in the sender's own slot
+    #[cfg(feature = "v2")]
+    NotHpkeInReusableSession,
     /// Original PSBT fee rate is below minimum fee rate set by the receiver.
     ///
     /// First argument is the calculated fee rate of the original PSBT.
@@ -161,6 +164,12 @@ impl fmt::Display for RequestError {
             InternalRequestError::ParsePsbt(e) => write_error(f, "Error parsing PSBT:", e),
             #[cfg(feature = "v2")]
             InternalRequestError::Utf8(e) => write_error(f, "Error parsing PSBT:", e),
+            #[cfg(feature = "v2")]
+            InternalRequestError::NotHpkeInReusableSession => write_error(
+                f,
+                "version-unsupported",
+                "Reusable payjoin URIs only accept HPKE encrypted requests.",
+            ),
             InternalRequestError::PsbtBelowFeeRate(
                 original_psbt_fee_rate,
                 receiver_min_fee_rate,
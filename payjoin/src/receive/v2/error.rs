@@ -12,6 +12,8 @@ pub(crate) enum InternalSessionError {
     Expired(std::time::SystemTime),
     /// OHTTP Encapsulation failed
     OhttpEncapsulationError(OhttpEncapsulationError),
+    /// No request was received to drop
+    NoRequestToDrop,
 }
 
 impl fmt::Display for SessionError {
@@ -20,6 +22,7 @@ impl fmt::Display for SessionError {
             InternalSessionError::Expired(expiry) => write!(f, "Session expired at {:?}", expiry),
             InternalSessionError::OhttpEncapsulationError(e) =>
                 write!(f, "OHTTP Encapsulation Error: {}", e),
+            InternalSessionError::NoRequestToDrop => write!(f, "No request was received to drop"),
         }
     }
 }
@@ -29,6 +32,7 @@ impl error::Error for SessionError {
         match &self.0 {
             InternalSessionError::Expired(_) => None,
             InternalSessionError::OhttpEncapsulationError(e) => Some(e),
+            InternalSessionError::NoRequestToDrop => None,
         }
     }
 }
@@ -1,14 +1,16 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use bitcoin::address::NetworkUnchecked;
 use bitcoin::base64::prelude::BASE64_URL_SAFE_NO_PAD;
 use bitcoin::base64::Engine;
+use bitcoin::bip32::{self, ChildNumber, Xpriv};
 use bitcoin::psbt::Psbt;
 use bitcoin::secp256k1::{rand, PublicKey};
-use bitcoin::{Address, Amount, FeeRate, OutPoint, Script, TxOut};
+use bitcoin::{Address, Amount, FeeRate, OutPoint, Script, ScriptBuf, TxOut};
 use serde::de::{self, Deserializer, MapAccess, Visitor};
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Serialize, Serializer};
@@ -16,9 +18,11 @@ use url::Url;
 
 use super::v2::error::{InternalSessionError, SessionError};
 use super::{Error, InternalRequestError, RequestError, SelectionError};
+use crate::persist::Secret;
 use crate::psbt::PsbtExt;
 use crate::receive::optional_parameters::Params;
-use crate::v2::OhttpEncapsulationError;
+use crate::uri::UrlExt;
+use crate::v2::{MessageVersion, OhttpEncapsulationError};
 use crate::{OhttpKeys, PjUriBuilder, Request};
 
 pub(crate) mod error;
@@ -32,10 +36,32 @@ struct SessionContext {
     ohttp_keys: OhttpKeys,
     expiry: SystemTime,
     ohttp_relay: url::Url,
-    s: bitcoin::secp256k1::Keypair,
+    s: Arc<Secret<bitcoin::secp256k1::Keypair>>,
     e: Option<bitcoin::secp256k1::PublicKey>,
+    /// The version the sender's message A came in, which the proposal must answer in
+    message_version: MessageVersion,
+    /// Whether the session's URI may be paid by any number of senders
+    reusable: bool,
+    /// Where a reusable session gets a fresh address for each sender. It isn't persisted.
+    address_source: Option<AddressSource>,
+    /// The digest of the sender's message A, naming the directory slot the proposal goes in
+    reply_digest: Option<[u8; 32]>,
 }
 
+/// Hands out a fresh script for each payment to a reusable session
+#[derive(Clone)]
+struct AddressSource(Arc<dyn Fn() -> Result<ScriptBuf, Error> + Send + Sync>);
+
+impl fmt::Debug for AddressSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("AddressSource(..)") }
+}
+
+impl PartialEq for AddressSource {
+    fn eq(&self, other: &Self) -> bool { Arc::ptr_eq(&self.0, &other.0) }
+}
+
+impl Eq for AddressSource {}
+
 /// Initializes a new payjoin session, including necessary context
 /// information for communication and cryptographic operations.
 #[derive(Debug, Clone)]
@@ -68,6 +94,42 @@ impl SessionInitializer {
     ) -> Self {
         let secp = bitcoin::secp256k1::Secp256k1::new();
         let (sk, _) = secp.generate_keypair(&mut rand::rngs::OsRng);
+        let s = bitcoin::secp256k1::Keypair::from_secret_key(&secp, &sk);
+        Self::with_session_key(address, directory, ohttp_keys, ohttp_relay, expire_after, s)
+    }
+
+    /// Creates a new `SessionInitializer` whose session key is derived from a wallet's `xpriv`
+    /// instead of being random.
+    ///
+    /// The session key is the hardened child `index'` of `xpriv`, so pass an xpriv dedicated to
+    /// payjoin sessions and a fresh `index` for every session. A wallet that lost its sessions
+    /// can recover them from its seed: create a session for every index it has used, enroll it
+    /// again and poll its mailbox for proposals still waiting in the directory.
+    ///
+    /// Errors if `index` is too large to be a hardened child number.
+    pub fn from_xpriv(
+        address: Address,
+        directory: Url,
+        ohttp_keys: OhttpKeys,
+        ohttp_relay: Url,
+        expire_after: Option<Duration>,
+        xpriv: &Xpriv,
+        index: u32,
+    ) -> Result<Self, bip32::Error> {
+        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+        let child = xpriv.derive_priv(&secp, &[ChildNumber::from_hardened_idx(index)?])?;
+        let s = child.to_keypair(&secp);
+        Ok(Self::with_session_key(address, directory, ohttp_keys, ohttp_relay, expire_after, s))
+    }
+
+    fn with_session_key(
+        address: Address,
+        directory: Url,
+        ohttp_keys: OhttpKeys,
+        ohttp_relay: Url,
+        expire_after: Option<Duration>,
+        s: bitcoin::secp256k1::Keypair,
+    ) -> Self {
         Self {
             context: SessionContext {
                 address,
@@ -76,15 +138,44 @@ impl SessionInitializer {
                 ohttp_relay,
                 expiry: SystemTime::now()
                     + expire_after.unwrap_or(TWENTY_FOUR_HOURS_DEFAULT_EXPIRY),
-                s: bitcoin::secp256k1::Keypair::from_secret_key(&secp, &sk),
+                s: Arc::new(Secret::new(s)),
                 e: None,
+                message_version: MessageVersion::Legacy,
+                reusable: false,
+                address_source: None,
+                reply_digest: None,
             },
         }
     }
 
+    /// Let any number of senders pay the session's URI until it expires, e.g. to print it as a
+    /// donation QR code.
+    ///
+    /// The directory queues each sender's request and [`ActiveSession::process_res`] hands
+    /// them out one at a time, each as its own [`UncheckedProposal`]. Reply to every request,
+    /// or drop it with [`ActiveSession::extract_drop_req`], to be handed the next one. Only
+    /// HPKE requests, which senders that understand the URI's message version make, can be
+    /// replied to in their own slot, so other requests are rejected.
+    ///
+    /// Senders all pay the URI's address, so [`OutputsUnknown::identify_receiver_outputs`]
+    /// substitutes a fresh one from `next_address` whenever the sender allows it. Closures
+    /// aren't persisted, so hand a session loaded from storage its
+    /// [`ActiveSession::set_address_source`] again.
+    pub fn reusable(
+        mut self,
+        next_address: impl Fn() -> Result<ScriptBuf, Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.context.reusable = true;
+        self.context.address_source = Some(AddressSource(Arc::new(next_address)));
+        self
+    }
+
+    /// The per-session public key to use as an identifier
+    pub fn public_key(&self) -> PublicKey { self.context.s.expose().public_key() }
+
     pub fn extract_req(&mut self) -> Result<(Request, ohttp::ClientResponse), Error> {
         let url = self.context.ohttp_relay.clone();
-        let subdirectory = subdir_path_from_pubkey(&self.context.s.public_key());
+        let subdirectory = subdir_path_from_pubkey(&self.context.s.expose().public_key());
         let (body, ctx) = crate::v2::ohttp_encapsulate(
             &mut self.context.ohttp_keys,
             "POST",
@@ -107,7 +198,7 @@ impl SessionInitializer {
             return Err(Error::Server("Enrollment failed, expected success status".into()));
         }
 
-        Ok(ActiveSession { context: self.context.clone() })
+        Ok(ActiveSession { context: self.context.clone(), last_request: None })
     }
 }
 
@@ -120,9 +211,21 @@ fn subdir_path_from_pubkey(pubkey: &bitcoin::secp256k1::PublicKey) -> String {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ActiveSession {
     context: SessionContext,
+    /// The digest of the request last handed out, which the receiver may drop
+    #[serde(default)]
+    last_request: Option<[u8; 32]>,
 }
 
 impl ActiveSession {
+    /// Where a [reusable](SessionInitializer::reusable) session loaded from storage gets a
+    /// fresh address for each sender, which isn't persisted with it
+    pub fn set_address_source(
+        &mut self,
+        next_address: impl Fn() -> Result<ScriptBuf, Error> + Send + Sync + 'static,
+    ) {
+        self.context.address_source = Some(AddressSource(Arc::new(next_address)));
+    }
+
     pub fn extract_req(&mut self) -> Result<(Request, ohttp::ClientResponse), SessionError> {
         if SystemTime::now() > self.context.expiry {
             return Err(InternalSessionError::Expired(self.context.expiry).into());
@@ -149,8 +252,11 @@ impl ActiveSession {
             log::debug!("response is empty");
             return Ok(None);
         }
+        self.last_request = Some(crate::v2::reply_digest(response.body()));
         match String::from_utf8(response.body().to_vec()) {
             // V1 response bodies are utf8 plaintext
+            Ok(_) if self.context.reusable =>
+                Err(InternalRequestError::NotHpkeInReusableSession.into()),
             Ok(response) => Ok(Some(self.extract_proposal_from_v1(response)?)),
             // V2 response bodies are encrypted binary
             Err(_) => Ok(Some(self.extract_proposal_from_v2(response.body().to_vec())?)),
@@ -170,19 +276,29 @@ impl ActiveSession {
     }
 
     fn extract_proposal_from_v1(&mut self, response: String) -> Result<UncheckedProposal, Error> {
-        Ok(self.unchecked_from_payload(response)?)
+        Ok(Self::unchecked_from_payload(self.context.clone(), response)?)
     }
 
     fn extract_proposal_from_v2(&mut self, response: Vec<u8>) -> Result<UncheckedProposal, Error> {
-        let (payload_bytes, e) =
-            crate::v2::decrypt_message_a(&response, self.context.s.secret_key())?;
-        self.context.e = Some(e);
+        let (payload_bytes, e, message_version) =
+            crate::v2::decrypt_message_a(&response, &self.context.s.expose().secret_key())?;
+        // Each request gets a context of its own, so a reusable session can hand out many.
+        // They share the session key rather than copy it
+        let mut context = self.context.clone();
+        context.e = Some(e);
+        context.message_version = message_version;
+        match message_version {
+            MessageVersion::Hpke => context.reply_digest = Some(crate::v2::reply_digest(&response)),
+            MessageVersion::Legacy if self.context.reusable =>
+                return Err(InternalRequestError::NotHpkeInReusableSession.into()),
+            MessageVersion::Legacy => (),
+        }
         let payload = String::from_utf8(payload_bytes).map_err(InternalRequestError::Utf8)?;
-        Ok(self.unchecked_from_payload(payload)?)
+        Ok(Self::unchecked_from_payload(context, payload)?)
     }
 
     fn unchecked_from_payload(
-        &mut self,
+        context: SessionContext,
         payload: String,
     ) -> Result<UncheckedProposal, RequestError> {
         let (base64, padded_query) = payload.split_once('\n').unwrap_or_default();
@@ -208,13 +324,15 @@ impl ActiveSession {
 
         log::debug!("Received request with params: {:?}", params);
         let inner = super::UncheckedProposal { psbt, params };
-        Ok(UncheckedProposal { inner, context: self.context.clone() })
+        Ok(UncheckedProposal { inner, context })
     }
 
     pub fn pj_uri_builder(&self) -> PjUriBuilder {
+        let mut pj = self.pj_url();
+        pj.set_message_version(Some(MessageVersion::LATEST));
         PjUriBuilder::new(
             self.context.address.clone(),
-            self.pj_url(),
+            pj,
             Some(self.context.ohttp_keys.clone()),
             Some(self.context.expiry),
         )
@@ -223,7 +341,7 @@ impl ActiveSession {
     // The contents of the `&pj=` query parameter including the base64url-encoded public key receiver subdirectory.
     // This identifies a session at the payjoin directory server.
     pub fn pj_url(&self) -> Url {
-        let pubkey = &self.context.s.public_key().serialize();
+        let pubkey = &self.context.s.expose().public_key().serialize();
         let pubkey_base64 = BASE64_URL_SAFE_NO_PAD.encode(pubkey);
         let mut url = self.context.directory.clone();
         {
@@ -235,7 +353,95 @@ impl ActiveSession {
     }
 
     /// The per-session public key to use as an identifier
-    pub fn public_key(&self) -> PublicKey { self.context.s.public_key() }
+    pub fn public_key(&self) -> PublicKey { self.context.s.expose().public_key() }
+
+    /// Build a request asking the directory to delete this session's mailbox.
+    ///
+    /// The request is signed with the session key so only the receiver can cancel its
+    /// session, and carries the time it was made so the directory refuses it if replayed
+    /// later. Call this once a payjoin completes or is abandoned so the directory doesn't
+    /// keep the sender's Original PSBT ciphertext around until it expires.
+    pub fn extract_cancel_req(&mut self) -> Result<(Request, ohttp::ClientResponse), SessionError> {
+        let body =
+            mailbox_auth::sign(mailbox_auth::DELETE_MAILBOX_TAG, self.context.s.expose(), &[], &[]);
+        let target = self.pj_url();
+        let (body, ctx) = crate::v2::ohttp_encapsulate(
+            &mut self.context.ohttp_keys,
+            "DELETE",
+            target.as_str(),
+            Some(&body),
+        )?;
+        let url = self.context.ohttp_relay.clone();
+        let req = Request { url, body };
+        Ok((req, ctx))
+    }
+
+    /// Build a request asking the directory to drop the request last handed out by
+    /// [`ActiveSession::process_res`] without a reply, e.g. because it failed a check.
+    ///
+    /// The directory serves the requests to a [reusable](SessionInitializer::reusable)
+    /// session one at a time, so drop the ones you won't reply to to be handed the next.
+    pub fn extract_drop_req(&mut self) -> Result<(Request, ohttp::ClientResponse), SessionError> {
+        let digest = self.last_request.ok_or(InternalSessionError::NoRequestToDrop)?;
+        let body = mailbox_auth::sign(
+            mailbox_auth::DROP_REQUEST_TAG,
+            self.context.s.expose(),
+            &digest,
+            &[],
+        );
+        let target = crate::v2::reply_slot_url(&self.pj_url(), &digest);
+        let (body, ctx) = crate::v2::ohttp_encapsulate(
+            &mut self.context.ohttp_keys,
+            "DELETE",
+            target.as_str(),
+            Some(&body),
+        )?;
+        let url = self.context.ohttp_relay.clone();
+        let req = Request { url, body };
+        Ok((req, ctx))
+    }
+
+    /// Processes the directory's response to a request from [`ActiveSession::extract_drop_req`].
+    pub fn process_drop_res(
+        &mut self,
+        mut res: impl std::io::Read,
+        ctx: ohttp::ClientResponse,
+    ) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        let _ = res.read_to_end(&mut buf);
+        let response = crate::v2::ohttp_decapsulate(ctx, &buf)?;
+        if response.status().is_success() {
+            self.last_request = None;
+            Ok(())
+        } else {
+            Err(Error::Server(
+                format!("Drop request failed, expected success status, got {}", response.status())
+                    .into(),
+            ))
+        }
+    }
+
+    /// Processes the directory's response to a request from [`ActiveSession::extract_cancel_req`].
+    pub fn process_cancel_res(
+        &self,
+        mut res: impl std::io::Read,
+        ctx: ohttp::ClientResponse,
+    ) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        let _ = res.read_to_end(&mut buf);
+        let response = crate::v2::ohttp_decapsulate(ctx, &buf)?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::Server(
+                format!(
+                    "Cancel session failed, expected success status, got {}",
+                    response.status()
+                )
+                .into(),
+            ))
+        }
+    }
 }
 
 /// The sender's original PSBT and optional parameters
@@ -369,11 +575,19 @@ pub struct OutputsUnknown {
 
 impl OutputsUnknown {
     /// Find which outputs belong to the receiver
+    ///
+    /// A [reusable](SessionInitializer::reusable) session substitutes a fresh address for the
+    /// one in its URI here, unless the sender disabled output substitution.
     pub fn identify_receiver_outputs(
         self,
         is_receiver_output: impl Fn(&Script) -> Result<bool, Error>,
     ) -> Result<ProvisionalProposal, Error> {
-        let inner = self.inner.identify_receiver_outputs(is_receiver_output)?;
+        let mut inner = self.inner.identify_receiver_outputs(is_receiver_output)?;
+        if let Some(AddressSource(next_address)) = &self.context.address_source {
+            if !inner.is_output_substitution_disabled() {
+                inner.try_substitute_receiver_output(|| next_address())?;
+            }
+        }
         Ok(ProvisionalProposal { inner, context: self.context })
     }
 }
@@ -462,15 +676,41 @@ impl PayjoinProposal {
             Some(e) => {
                 let mut payjoin_bytes = self.inner.payjoin_psbt.serialize();
                 log::debug!("THERE IS AN e: {}", e);
-                crate::v2::encrypt_message_b(&mut payjoin_bytes, e)
+                crate::v2::encrypt_message_b(
+                    &mut payjoin_bytes,
+                    e,
+                    &self.context.s.expose().secret_key(),
+                    self.context.message_version,
+                )
             }
             None => Ok(self.extract_v1_req().as_bytes().to_vec()),
         }?;
-        let post_payjoin_target = format!(
+        let mailbox = format!(
             "{}{}/payjoin",
             self.context.directory.as_str(),
-            subdir_path_from_pubkey(&self.context.s.public_key())
+            subdir_path_from_pubkey(&self.context.s.expose().public_key())
         );
+        // Prove to the directory that this response comes from the mailbox owner
+        let (body, post_payjoin_target) = match self.context.reply_digest {
+            Some(digest) => (
+                mailbox_auth::sign(
+                    mailbox_auth::POST_REPLY_TAG,
+                    self.context.s.expose(),
+                    &digest,
+                    &body,
+                ),
+                format!("{}/{}", mailbox, BASE64_URL_SAFE_NO_PAD.encode(digest)),
+            ),
+            None => (
+                mailbox_auth::sign(
+                    mailbox_auth::POST_PAYJOIN_TAG,
+                    self.context.s.expose(),
+                    &[],
+                    &body,
+                ),
+                mailbox,
+            ),
+        };
         log::debug!("Payjoin post target: {}", post_payjoin_target.as_str());
         let (body, ctx) = crate::v2::ohttp_encapsulate(
             &mut self.context.ohttp_keys,
@@ -507,6 +747,21 @@ impl PayjoinProposal {
         }
     }
 }
+impl crate::persist::sealed::Session for ActiveSession {
+    const SECRET_KEY: &'static str = "/context/s";
+
+    fn replace_secret_key(
+        &mut self,
+        secret_bytes: &[u8],
+    ) -> Result<zeroize::Zeroizing<[u8; 32]>, bitcoin::secp256k1::Error> {
+        crate::persist::replace_secret_key(&mut self.context.s, secret_bytes)
+    }
+
+    fn placeholder_secret_key() -> serde_json::Value {
+        crate::persist::placeholder_secret_key::<bitcoin::secp256k1::Keypair>()
+    }
+}
+
 impl Serialize for SessionContext {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -518,8 +773,11 @@ impl Serialize for SessionContext {
         state.serialize_field("ohttp_keys", &self.ohttp_keys)?;
         state.serialize_field("ohttp_relay", &self.ohttp_relay)?;
         state.serialize_field("expiry", &self.expiry)?;
-        state.serialize_field("s", &self.s)?;
+        state.serialize_field("s", &*self.s)?;
         state.serialize_field("e", &self.e)?;
+        state.serialize_field("message_version", &u8::from(self.message_version))?;
+        state.serialize_field("reusable", &self.reusable)?;
+        // Only proposals carry a reply digest, and they aren't persisted
 
         state.end()
     }
@@ -540,6 +798,8 @@ impl<'de> Deserialize<'de> for SessionContext {
             Expiry,
             S,
             E,
+            MessageVersion,
+            Reusable,
         }
 
         struct SessionContextVisitor;
@@ -562,6 +822,8 @@ impl<'de> Deserialize<'de> for SessionContext {
                 let mut expiry = None;
                 let mut s = None;
                 let mut e = None;
+                let mut message_version: Option<u8> = None;
+                let mut reusable = None;
                 while let Some(key) = map.next_key()? {
                     match key {
                         Field::Address => {
@@ -598,7 +860,7 @@ impl<'de> Deserialize<'de> for SessionContext {
                             if s.is_some() {
                                 return Err(de::Error::duplicate_field("s"));
                             }
-                            s = Some(map.next_value()?);
+                            s = Some(Arc::new(map.next_value()?));
                         }
                         Field::E => {
                             if e.is_some() {
@@ -606,6 +868,18 @@ impl<'de> Deserialize<'de> for SessionContext {
                             }
                             e = Some(map.next_value()?);
                         }
+                        Field::MessageVersion => {
+                            if message_version.is_some() {
+                                return Err(de::Error::duplicate_field("message_version"));
+                            }
+                            message_version = Some(map.next_value()?);
+                        }
+                        Field::Reusable => {
+                            if reusable.is_some() {
+                                return Err(de::Error::duplicate_field("reusable"));
+                            }
+                            reusable = Some(map.next_value()?);
+                        }
                     }
                 }
                 let address = address
@@ -619,11 +893,36 @@ impl<'de> Deserialize<'de> for SessionContext {
                 let expiry = expiry.ok_or_else(|| de::Error::missing_field("expiry"))?;
                 let s = s.ok_or_else(|| de::Error::missing_field("s"))?;
                 let e = e.ok_or_else(|| de::Error::missing_field("e"))?;
-                Ok(SessionContext { address, directory, ohttp_keys, ohttp_relay, expiry, s, e })
+                // Sessions persisted before HPKE could only have received legacy messages
+                let message_version = message_version
+                    .map_or(Ok(MessageVersion::Legacy), MessageVersion::try_from)
+                    .map_err(de::Error::custom)?;
+                Ok(SessionContext {
+                    address,
+                    directory,
+                    ohttp_keys,
+                    ohttp_relay,
+                    expiry,
+                    s,
+                    e,
+                    message_version,
+                    reusable: reusable.unwrap_or(false),
+                    address_source: None,
+                    reply_digest: None,
+                })
             }
         }
 
-        const FIELDS: &[&str] = &["directory", "ohttp_keys", "ohttp_relay", "expiry", "s", "e"];
+        const FIELDS: &[&str] = &[
+            "directory",
+            "ohttp_keys",
+            "ohttp_relay",
+            "expiry",
+            "s",
+            "e",
+            "message_version",
+            "reusable",
+        ];
         deserializer.deserialize_struct("SessionContext", FIELDS, SessionContextVisitor)
     }
 }
@@ -632,9 +931,11 @@ impl<'de> Deserialize<'de> for SessionContext {
 mod test {
     use super::*;
 
-    #[test]
+    #[cfg(feature = "send")]
+    const ORIGINAL_PSBT: &str = "cHNidP8BAHMCAAAAAY8nutGgJdyYGXWiBEb45Hoe9lWGbkxh/6bNiOJdCDuDAAAAAAD+////AtyVuAUAAAAAF6kUHehJ8GnSdBUOOv6ujXLrWmsJRDCHgIQeAAAAAAAXqRR3QJbbz0hnQ8IvQ0fptGn+votneofTAAAAAAEBIKgb1wUAAAAAF6kU3k4ekGHKWRNbA1rV5tR5kEVDVNCHAQcXFgAUx4pFclNVgo1WWAdN1SYNX8tphTABCGsCRzBEAiB8Q+A6dep+Rz92vhy26lT0AjZn4PRLi8Bf9qoB/CMk0wIgP/Rj2PWZ3gEjUkTlhDRNAQ0gXwTO7t9n+V14pZ6oljUBIQMVmsAaoNWHVMS02LfTSe0e388LNitPa1UQZyOihY+FFgABABYAFEb2Giu6c4KO5YW0pfw3lGp9jMUUAAA=";
+
     #[cfg(feature = "v2")]
-    fn active_session_ser_de_roundtrip() {
+    fn active_session(reusable: bool) -> ActiveSession {
         use ohttp::hpke::{Aead, Kdf, Kem};
         use ohttp::{KeyId, SymmetricSuite};
         const KEY_ID: KeyId = 1;
@@ -642,26 +943,171 @@ mod test {
         const SYMMETRIC: &[SymmetricSuite] =
             &[ohttp::SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305)];
 
-        let session = ActiveSession {
+        ActiveSession {
             context: SessionContext {
                 address: Address::from_str("tb1q6d3a2w975yny0asuvd9a67ner4nks58ff0q8g4")
                     .unwrap()
                     .assume_checked(),
                 directory: url::Url::parse("https://directory.com").unwrap(),
-                ohttp_keys: OhttpKeys(
+                ohttp_keys: OhttpKeys::from(
                     ohttp::KeyConfig::new(KEY_ID, KEM, Vec::from(SYMMETRIC)).unwrap(),
                 ),
                 ohttp_relay: url::Url::parse("https://relay.com").unwrap(),
                 expiry: SystemTime::now() + Duration::from_secs(60),
-                s: bitcoin::secp256k1::Keypair::from_secret_key(
+                s: Arc::new(Secret::new(bitcoin::secp256k1::Keypair::from_secret_key(
                     &bitcoin::secp256k1::Secp256k1::new(),
                     &bitcoin::secp256k1::SecretKey::from_slice(&[1; 32]).unwrap(),
-                ),
+                ))),
                 e: None,
+                message_version: MessageVersion::Hpke,
+                reusable,
+                address_source: None,
+                reply_digest: None,
             },
-        };
+            last_request: None,
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn active_session_ser_de_roundtrip() {
+        let mut session = active_session(true);
+        session.last_request = Some([3; 32]);
         let serialized = serde_json::to_string(&session).unwrap();
         let deserialized: ActiveSession = serde_json::from_str(&serialized).unwrap();
         assert_eq!(session, deserialized);
+
+        /// XORs keys with a fixed byte
+        struct Xor(u8);
+
+        impl crate::persist::KeyEncryption for Xor {
+            fn encrypt(
+                &self,
+                secret_key: &[u8],
+            ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+                Ok(secret_key.iter().map(|b| b ^ self.0).collect())
+            }
+
+            fn decrypt(
+                &self,
+                ciphertext: &[u8],
+            ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+                self.encrypt(ciphertext)
+            }
+        }
+
+        session.context.s = Arc::new(Secret::new(
+            bitcoin::secp256k1::Keypair::from_seckey_slice(
+                &bitcoin::secp256k1::Secp256k1::signing_only(),
+                &[2; 32],
+            )
+            .unwrap(),
+        ));
+        let keystore = Xor(0xff);
+        let encrypted = crate::persist::serialize_encrypted(&session, &keystore).unwrap();
+        assert!(!encrypted.contains(&session.context.s.expose().display_secret().to_string()));
+        let decrypted: ActiveSession =
+            crate::persist::deserialize_encrypted(&encrypted, &keystore).unwrap();
+        assert_eq!(session, decrypted);
+    }
+
+    #[test]
+    #[cfg(all(feature = "v2", feature = "send"))]
+    fn reusable_sessions_pay_a_fresh_address_when_allowed() {
+        use bitcoin::hashes::Hash;
+
+        let fresh = ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::from_byte_array([7; 20]));
+        let mut session = active_session(true);
+        session.set_address_source({
+            let fresh = fresh.clone();
+            move || Ok(fresh.clone())
+        });
+        let s = session.public_key();
+        let payee = Psbt::from_str(ORIGINAL_PSBT).unwrap().unsigned_tx.output[0].clone();
+        let mut receiver_outputs = |e: u8, query: &str| {
+            let e = bitcoin::secp256k1::SecretKey::from_slice(&[e; 32]).unwrap();
+            let body = format!("{}\n{}", ORIGINAL_PSBT, query).into_bytes();
+            let message_a = crate::v2::encrypt_message_a(body, &e, s, MessageVersion::Hpke);
+            let proposal = session
+                .extract_proposal_from_v2(message_a.unwrap())
+                .unwrap()
+                .assume_interactive_receiver()
+                .check_inputs_not_owned(|_| Ok(false))
+                .unwrap()
+                .check_no_mixed_input_scripts()
+                .unwrap()
+                .check_no_inputs_seen_before(|_| Ok(false))
+                .unwrap()
+                .identify_receiver_outputs(|script| Ok(script == payee.script_pubkey.as_script()))
+                .unwrap();
+            let vout = proposal.inner.owned_vouts[0];
+            proposal.inner.payjoin_psbt.unsigned_tx.output[vout].script_pubkey.clone()
+        };
+
+        assert_eq!(receiver_outputs(2, "v=2"), fresh);
+        assert_eq!(receiver_outputs(3, "v=2&disableoutputsubstitution=true"), payee.script_pubkey);
+    }
+
+    #[test]
+    #[cfg(all(feature = "v2", feature = "send"))]
+    fn reusable_sessions_hand_out_a_proposal_per_sender() {
+        let mut session = active_session(true);
+        let s = session.public_key();
+        let message_a = |e: u8, version| {
+            let e = bitcoin::secp256k1::SecretKey::from_slice(&[e; 32]).unwrap();
+            let body = format!("{}\nv=2", ORIGINAL_PSBT).into_bytes();
+            crate::v2::encrypt_message_a(body, &e, s, version).unwrap()
+        };
+
+        let (first, second) =
+            (message_a(2, MessageVersion::Hpke), message_a(3, MessageVersion::Hpke));
+        let first_proposal = session.extract_proposal_from_v2(first.clone()).unwrap();
+        let second_proposal = session.extract_proposal_from_v2(second.clone()).unwrap();
+        assert_eq!(first_proposal.context.reply_digest, Some(crate::v2::reply_digest(&first)));
+        assert_eq!(second_proposal.context.reply_digest, Some(crate::v2::reply_digest(&second)));
+        assert_ne!(first_proposal.context.e, second_proposal.context.e);
+        assert_eq!(session.context.e, None, "the session itself isn't bound to a sender");
+
+        let legacy = message_a(4, MessageVersion::Legacy);
+        assert!(session.extract_proposal_from_v2(legacy.clone()).is_err());
+        let proposal = active_session(false).extract_proposal_from_v2(legacy).unwrap();
+        assert_eq!(proposal.context.reply_digest, None);
+    }
+
+    #[test]
+    #[cfg(feature = "v2")]
+    fn session_keys_derive_from_xpriv() {
+        use ohttp::hpke::{Aead, Kdf, Kem};
+        use ohttp::SymmetricSuite;
+
+        let xpriv = Xpriv::new_master(bitcoin::Network::Signet, &[0x42; 32]).unwrap();
+        let session = |index| {
+            SessionInitializer::from_xpriv(
+                Address::from_str("tb1q6d3a2w975yny0asuvd9a67ner4nks58ff0q8g4")
+                    .unwrap()
+                    .assume_checked(),
+                url::Url::parse("https://directory.com").unwrap(),
+                OhttpKeys::from(
+                    ohttp::KeyConfig::new(
+                        1,
+                        Kem::X25519Sha256,
+                        vec![SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305)],
+                    )
+                    .unwrap(),
+                ),
+                url::Url::parse("https://relay.com").unwrap(),
+                None,
+                &xpriv,
+                index,
+            )
+        };
+
+        let recovered = session(7).unwrap().public_key();
+        assert_eq!(recovered, session(7).unwrap().public_key());
+        assert_ne!(recovered, session(8).unwrap().public_key());
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let expected = xpriv.derive_priv(&secp, &[ChildNumber::from_hardened_idx(7).unwrap()]);
+        assert_eq!(recovered, expected.unwrap().private_key.public_key(&secp));
+        assert!(session(1 << 31).is_err());
     }
 }
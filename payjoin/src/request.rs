@@ -5,6 +5,10 @@ pub const V1_REQ_CONTENT_TYPE: &str = "text/plain";
 #[cfg(feature = "v2")]
 pub const V2_REQ_CONTENT_TYPE: &str = "message/ohttp-req";
 
+/// The content type of requests from [`crate::send::RequestContext::extract_v2_chunked`]
+#[cfg(feature = "v2")]
+pub const V2_CHUNKED_REQ_CONTENT_TYPE: &str = chunked_ohttp::CHUNKED_REQ_CONTENT_TYPE;
+
 /// Represents data that needs to be transmitted to the receiver or payjoin directory.
 #[non_exhaustive]
 #[derive(Debug, Clone)]
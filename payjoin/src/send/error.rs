@@ -198,6 +198,10 @@ pub(crate) enum InternalCreateRequestError {
     MissingOhttpConfig,
     #[cfg(feature = "v2")]
     Expired(std::time::SystemTime),
+    #[cfg(feature = "v2")]
+    InconsistentOhttpKeys,
+    #[cfg(feature = "v2")]
+    LegacyReceiver,
 }
 
 impl fmt::Display for CreateRequestError {
@@ -229,6 +233,10 @@ impl fmt::Display for CreateRequestError {
             MissingOhttpConfig => write!(f, "no ohttp configuration with which to make a v2 request available"),
             #[cfg(feature = "v2")]
             Expired(expiry) => write!(f, "session expired at {:?}", expiry),
+            #[cfg(feature = "v2")]
+            InconsistentOhttpKeys => write!(f, "the ohttp key in the payjoin uri doesn't match the keys obtained independently"),
+            #[cfg(feature = "v2")]
+            LegacyReceiver => write!(f, "the receiver predates authenticated proposals and unauthenticated receivers aren't allowed, so the original transaction was not sent"),
         }
     }
 }
@@ -262,6 +270,10 @@ impl std::error::Error for CreateRequestError {
             MissingOhttpConfig => None,
             #[cfg(feature = "v2")]
             Expired(_) => None,
+            #[cfg(feature = "v2")]
+            InconsistentOhttpKeys => None,
+            #[cfg(feature = "v2")]
+            LegacyReceiver => None,
         }
     }
 }
@@ -25,6 +25,8 @@
 //! wallet and http client.
 
 use std::str::FromStr;
+#[cfg(feature = "v2")]
+use std::sync::Arc;
 
 use bitcoin::psbt::Psbt;
 #[cfg(feature = "v2")]
@@ -43,6 +45,8 @@ use serde::{
 use url::Url;
 
 use crate::input_type::InputType;
+#[cfg(feature = "v2")]
+use crate::persist::Secret;
 use crate::psbt::PsbtExt;
 use crate::request::Request;
 use crate::weight::{varint_size, ComputeWeight};
@@ -69,6 +73,8 @@ pub struct RequestBuilder<'a> {
     /// be just lowered in the request to match the change amount.
     clamp_fee_contribution: bool,
     min_fee_rate: FeeRate,
+    #[cfg(feature = "v2")]
+    allow_unauthenticated_receiver: bool,
 }
 
 impl<'a> RequestBuilder<'a> {
@@ -86,6 +92,8 @@ impl<'a> RequestBuilder<'a> {
             fee_contribution: None,
             clamp_fee_contribution: false,
             min_fee_rate: FeeRate::ZERO,
+            #[cfg(feature = "v2")]
+            allow_unauthenticated_receiver: false,
         })
     }
 
@@ -100,6 +108,18 @@ impl<'a> RequestBuilder<'a> {
         self
     }
 
+    /// Send v2 requests to receivers that don't advertise a message version too.
+    ///
+    /// Such receivers predate HPKE and can't authenticate their proposal, so anyone who
+    /// learns the sender's ephemeral key through the directory could forge one. By default
+    /// they are refused before the Original is sent to them. Only allow them while
+    /// receivers are still upgrading.
+    #[cfg(feature = "v2")]
+    pub fn allow_unauthenticated_receiver(mut self, allow: bool) -> Self {
+        self.allow_unauthenticated_receiver = allow;
+        self
+    }
+
     // Calculate the recommended fee contribution for an Original PSBT.
     //
     // BIP 78 recommends contributing `originalPSBTFeeRate * vsize(sender_input_type)`.
@@ -242,7 +262,7 @@ impl<'a> RequestBuilder<'a> {
         let e = {
             let secp = bitcoin::secp256k1::Secp256k1::new();
             let (e_sec, _) = secp.generate_keypair(&mut rand::rngs::OsRng);
-            e_sec
+            Arc::new(Secret::new(e_sec))
         };
 
         Ok(RequestContext {
@@ -256,6 +276,10 @@ impl<'a> RequestBuilder<'a> {
             min_fee_rate: self.min_fee_rate,
             #[cfg(feature = "v2")]
             e,
+            #[cfg(feature = "v2")]
+            reply_digest: None,
+            #[cfg(feature = "v2")]
+            allow_unauthenticated_receiver: self.allow_unauthenticated_receiver,
         })
     }
 }
@@ -271,7 +295,13 @@ pub struct RequestContext {
     sequence: Sequence,
     payee: ScriptBuf,
     #[cfg(feature = "v2")]
-    e: bitcoin::secp256k1::SecretKey,
+    e: Arc<Secret<bitcoin::secp256k1::SecretKey>>,
+    /// The digest naming the directory slot the reply goes in, once the Original was delivered
+    #[cfg(feature = "v2")]
+    reply_digest: Option<[u8; 32]>,
+    /// Whether to send to receivers that can't authenticate their proposal
+    #[cfg(feature = "v2")]
+    allow_unauthenticated_receiver: bool,
 }
 
 #[cfg(feature = "v2")]
@@ -305,16 +335,44 @@ impl RequestContext {
 
     /// Extract serialized Request and Context from a Payjoin Proposal.
     ///
-    /// In order to support polling, this may need to be called many times to be encrypted with
-    /// new unique nonces to make independent OHTTP requests.
+    /// In order to support polling, this may need to be called many times to make independent
+    /// OHTTP requests. Requests post the Original until [`RequestContext::process_v2_response`]
+    /// learns the directory has it, and then merely poll for the receiver's reply.
+    ///
+    /// Receivers that don't advertise an HPKE message version can't authenticate their
+    /// proposal, so they are refused before the Original is encrypted to them, unless
+    /// [`RequestBuilder::allow_unauthenticated_receiver`] allowed them.
     ///
     /// The `ohttp_relay` merely passes the encrypted payload to the ohttp gateway of the receiver
     #[cfg(feature = "v2")]
     pub fn extract_v2(
         &mut self,
         ohttp_relay: Url,
+    ) -> Result<(Request, ContextV2), CreateRequestError> {
+        self.extract_v2_with(ohttp_relay, false)
+    }
+
+    /// Like [`RequestContext::extract_v2`], but as a chunked OHTTP request to be sent as
+    /// [`crate::V2_CHUNKED_REQ_CONTENT_TYPE`], for directories that support them.
+    ///
+    /// The directory answers with a chunked response, which [`ContextV2::process_response`]
+    /// opens chunk by chunk as it's read, refusing it once it outgrows the largest proposal.
+    #[cfg(feature = "v2")]
+    pub fn extract_v2_chunked(
+        &mut self,
+        ohttp_relay: Url,
+    ) -> Result<(Request, ContextV2), CreateRequestError> {
+        self.extract_v2_with(ohttp_relay, true)
+    }
+
+    #[cfg(feature = "v2")]
+    fn extract_v2_with(
+        &mut self,
+        ohttp_relay: Url,
+        chunked: bool,
     ) -> Result<(Request, ContextV2), CreateRequestError> {
         use crate::uri::UrlExt;
+        use crate::v2::MessageVersion;
 
         if let Some(expiry) = self.endpoint.exp() {
             if std::time::SystemTime::now() > expiry {
@@ -322,24 +380,59 @@ impl RequestContext {
             }
         }
         let rs = Self::rs_pubkey_from_dir_endpoint(&self.endpoint)?;
-        let url = self.endpoint.clone();
-        let body = serialize_v2_body(
-            &self.psbt,
-            self.disable_output_substitution,
-            self.fee_contribution,
-            self.min_fee_rate,
-        )?;
-        let body = crate::v2::encrypt_message_a(body, self.e, rs)
-            .map_err(InternalCreateRequestError::Hpke)?;
+        // Receivers that don't advertise a message version predate HPKE and can't
+        // authenticate a proposal, so the Original goes to them only if the sender said so
+        let message_version = self.endpoint.message_version().unwrap_or(MessageVersion::Legacy);
+        if message_version == MessageVersion::Legacy && !self.allow_unauthenticated_receiver {
+            return Err(InternalCreateRequestError::LegacyReceiver.into());
+        }
         let mut ohttp =
             self.endpoint.ohttp().ok_or(InternalCreateRequestError::MissingOhttpConfig)?;
-        let (body, ohttp_res) =
-            crate::v2::ohttp_encapsulate(&mut ohttp, "POST", url.as_str(), Some(&body))
-                .map_err(InternalCreateRequestError::OhttpEncapsulation)?;
+        let (body, ohttp_res, delivers) = match self.reply_digest {
+            // Only poll for the reply once the directory has the Original
+            Some(reply_digest) => {
+                let url = crate::v2::reply_slot_url(&self.endpoint, &reply_digest);
+                let (body, ohttp_res) = ohttp_encapsulate(&mut ohttp, chunked, "GET", &url, None)?;
+                (body, ohttp_res, None)
+            }
+            None => {
+                let body = serialize_v2_body(
+                    &self.psbt,
+                    self.disable_output_substitution,
+                    self.fee_contribution,
+                    self.min_fee_rate,
+                )?;
+                let body = crate::v2::encrypt_message_a(body, self.e.expose(), rs, message_version)
+                    .map_err(InternalCreateRequestError::Hpke)?;
+                match message_version {
+                    // The reply waits in a slot of its own, so a reusable receiver mailbox
+                    // can answer several senders
+                    MessageVersion::Hpke => {
+                        let reply_digest = crate::v2::reply_digest(&body);
+                        let url = crate::v2::reply_slot_url(&self.endpoint, &reply_digest);
+                        let (body, ohttp_res) =
+                            ohttp_encapsulate(&mut ohttp, chunked, "POST", &url, Some(&body))?;
+                        (body, ohttp_res, Some(reply_digest))
+                    }
+                    // Legacy receivers answer in the mailbox's one response slot, which is
+                    // polled by posting the same Original again
+                    MessageVersion::Legacy => {
+                        let (body, ohttp_res) = ohttp_encapsulate(
+                            &mut ohttp,
+                            chunked,
+                            "POST",
+                            &self.endpoint,
+                            Some(&body),
+                        )?;
+                        (body, ohttp_res, None)
+                    }
+                }
+            }
+        };
         log::debug!("ohttp_relay_url: {:?}", ohttp_relay);
         Ok((
             Request { url: ohttp_relay, body },
-            // this method may be called more than once to re-construct the ohttp, therefore we must clone (or TODO memoize)
+            // Each request's context validates the proposal on its own, so it owns a copy
             ContextV2 {
                 context_v1: ContextV1 {
                     original_psbt: self.psbt.clone(),
@@ -350,12 +443,62 @@ impl RequestContext {
                     sequence: self.sequence,
                     min_fee_rate: self.min_fee_rate,
                 },
-                e: self.e,
+                e: Arc::clone(&self.e),
+                rs,
+                message_version,
                 ohttp_res,
+                delivers,
             },
         ))
     }
 
+    /// Decodes and validates the response to a request from [`RequestContext::extract_v2`] like
+    /// [`ContextV2::process_response`], remembering once the directory has the Original so
+    /// later requests only poll for the reply. Persist the context again afterwards to resume
+    /// polling without re-sending the Original.
+    #[cfg(feature = "v2")]
+    pub fn process_v2_response(
+        &mut self,
+        context: ContextV2,
+        response: &mut impl std::io::Read,
+    ) -> Result<Option<Psbt>, ResponseError> {
+        let delivers = context.delivers;
+        let proposal = context.process_response(response)?;
+        // The directory answers a posted Original once it has queued it
+        if delivers.is_some() {
+            self.reply_digest = delivers;
+        }
+        Ok(proposal)
+    }
+
+    /// Whether the directory has the Original, so requests only poll for the reply
+    #[cfg(feature = "v2")]
+    pub fn is_original_delivered(&self) -> bool { self.reply_digest.is_some() }
+
+    /// Check the OHTTP key in the receiver's URI against keys obtained independently of it,
+    /// either pinned ahead of time or fetched with `io::fetch_ohttp_keys` through a
+    /// different relay than the one that carries the session.
+    ///
+    /// A directory colluding with a relay could otherwise hand each sender a key of its own and
+    /// link their requests. Don't start the session if this fails. Keys match by key id and
+    /// public key, whichever suites they're offered with. A URI made before the directory
+    /// rotated its key still matches while the directory lists the old key among the ones it
+    /// accepts.
+    #[cfg(feature = "v2")]
+    pub fn check_ohttp_keys(
+        &self,
+        expected: &[crate::OhttpKeys],
+    ) -> Result<(), CreateRequestError> {
+        use crate::uri::UrlExt;
+
+        let ohttp = self.endpoint.ohttp().ok_or(InternalCreateRequestError::MissingOhttpConfig)?;
+        if expected.iter().any(|keys| keys.contains_key(&ohttp)) {
+            Ok(())
+        } else {
+            Err(InternalCreateRequestError::InconsistentOhttpKeys.into())
+        }
+    }
+
     #[cfg(feature = "v2")]
     fn rs_pubkey_from_dir_endpoint(endpoint: &Url) -> Result<PublicKey, CreateRequestError> {
         use bitcoin::base64::prelude::BASE64_URL_SAFE_NO_PAD;
@@ -399,7 +542,12 @@ impl Serialize for RequestContext {
         state.serialize_field("input_type", &self.input_type)?;
         state.serialize_field("sequence", &self.sequence)?;
         state.serialize_field("payee", &self.payee)?;
-        state.serialize_field("e", &self.e.secret_bytes())?;
+        state.serialize_field("e", &*self.e)?;
+        state.serialize_field("reply_digest", &self.reply_digest)?;
+        state.serialize_field(
+            "allow_unauthenticated_receiver",
+            &self.allow_unauthenticated_receiver,
+        )?;
         state.end()
     }
 }
@@ -423,6 +571,8 @@ impl<'de> Deserialize<'de> for RequestContext {
             "sequence",
             "payee",
             "e",
+            "reply_digest",
+            "allow_unauthenticated_receiver",
         ];
 
         impl<'de> Visitor<'de> for RequestContextVisitor {
@@ -445,6 +595,8 @@ impl<'de> Deserialize<'de> for RequestContext {
                 let mut sequence = None;
                 let mut payee = None;
                 let mut e = None;
+                let mut reply_digest = None;
+                let mut allow_unauthenticated_receiver = None;
 
                 while let Some(key) = map.next_key::<String>()? {
                     match key.as_str() {
@@ -468,13 +620,10 @@ impl<'de> Deserialize<'de> for RequestContext {
                         "input_type" => input_type = Some(map.next_value()?),
                         "sequence" => sequence = Some(map.next_value()?),
                         "payee" => payee = Some(map.next_value()?),
-                        "e" => {
-                            let secret_bytes: Vec<u8> = map.next_value()?;
-                            e = Some(
-                                bitcoin::secp256k1::SecretKey::from_slice(&secret_bytes)
-                                    .map_err(de::Error::custom)?,
-                            );
-                        }
+                        "e" => e = Some(Arc::new(map.next_value()?)),
+                        "reply_digest" => reply_digest = map.next_value()?,
+                        "allow_unauthenticated_receiver" =>
+                            allow_unauthenticated_receiver = Some(map.next_value()?),
                         _ => return Err(de::Error::unknown_field(key.as_str(), FIELDS)),
                     }
                 }
@@ -491,6 +640,10 @@ impl<'de> Deserialize<'de> for RequestContext {
                     sequence: sequence.ok_or_else(|| de::Error::missing_field("sequence"))?,
                     payee: payee.ok_or_else(|| de::Error::missing_field("payee"))?,
                     e: e.ok_or_else(|| de::Error::missing_field("e"))?,
+                    // Contexts persisted before it was recorded post the Original again
+                    reply_digest,
+                    allow_unauthenticated_receiver: allow_unauthenticated_receiver
+                        .unwrap_or_default(),
                 })
             }
         }
@@ -499,6 +652,22 @@ impl<'de> Deserialize<'de> for RequestContext {
     }
 }
 
+#[cfg(feature = "v2")]
+impl crate::persist::sealed::Session for RequestContext {
+    const SECRET_KEY: &'static str = "/e";
+
+    fn replace_secret_key(
+        &mut self,
+        secret_bytes: &[u8],
+    ) -> Result<zeroize::Zeroizing<[u8; 32]>, bitcoin::secp256k1::Error> {
+        crate::persist::replace_secret_key(&mut self.e, secret_bytes)
+    }
+
+    fn placeholder_secret_key() -> serde_json::Value {
+        crate::persist::placeholder_secret_key::<bitcoin::secp256k1::SecretKey>()
+    }
+}
+
 /// Data required for validation of response.
 ///
 /// This type is used to process the response. Get it from [`RequestBuilder`](crate::send::RequestBuilder)'s build methods.
@@ -517,8 +686,43 @@ pub struct ContextV1 {
 #[cfg(feature = "v2")]
 pub struct ContextV2 {
     context_v1: ContextV1,
-    e: bitcoin::secp256k1::SecretKey,
-    ohttp_res: ohttp::ClientResponse,
+    e: Arc<Secret<bitcoin::secp256k1::SecretKey>>,
+    /// The receiver's session key, which must have authenticated its proposal
+    rs: bitcoin::secp256k1::PublicKey,
+    /// Legacy only if the sender allowed receivers that can't authenticate their proposal
+    message_version: crate::v2::MessageVersion,
+    ohttp_res: OhttpResponse,
+    /// The digest naming the reply slot, if this request delivers the Original
+    delivers: Option<[u8; 32]>,
+}
+
+/// Opens the directory's response, chunked if the request was
+#[cfg(feature = "v2")]
+enum OhttpResponse {
+    KnownLength(ohttp::ClientResponse),
+    Chunked(chunked_ohttp::ClientResponse),
+}
+
+/// Bounds a chunked response to the largest message B and its bhttp framing
+#[cfg(feature = "v2")]
+const MAX_CHUNKED_RESPONSE_LEN: usize = crate::v2::MAX_PADDED_MESSAGE_BYTES + 4096;
+
+#[cfg(feature = "v2")]
+fn ohttp_encapsulate(
+    ohttp_keys: &mut ohttp::KeyConfig,
+    chunked: bool,
+    method: &str,
+    url: &Url,
+    body: Option<&[u8]>,
+) -> Result<(Vec<u8>, OhttpResponse), InternalCreateRequestError> {
+    let encapsulated = if chunked {
+        crate::v2::ohttp_encapsulate_chunked(ohttp_keys, method, url.as_str(), body)
+            .map(|(body, ohttp_res)| (body, OhttpResponse::Chunked(ohttp_res)))
+    } else {
+        crate::v2::ohttp_encapsulate(ohttp_keys, method, url.as_str(), body)
+            .map(|(body, ohttp_res)| (body, OhttpResponse::KnownLength(ohttp_res)))
+    };
+    encapsulated.map_err(InternalCreateRequestError::OhttpEncapsulation)
 }
 
 macro_rules! check_eq {
@@ -547,22 +751,37 @@ impl ContextV2 {
     /// A successful response can either be None if the directory has not response yet or Some(Psbt).
     ///
     /// If the response is some valid PSBT you should sign and broadcast.
+    ///
+    /// Proposals must be authenticated by the receiver's session key from the `pj=` URL,
+    /// unless [`RequestBuilder::allow_unauthenticated_receiver`] let the request go to a
+    /// receiver that predates authentication.
     #[inline]
     pub fn process_response(
         self,
         response: &mut impl std::io::Read,
     ) -> Result<Option<Psbt>, ResponseError> {
-        let mut res_buf = Vec::new();
-        response.read_to_end(&mut res_buf).map_err(InternalValidationError::Io)?;
-        let response = crate::v2::ohttp_decapsulate(self.ohttp_res, &res_buf)
-            .map_err(InternalValidationError::OhttpEncapsulation)?;
+        let response = match self.ohttp_res {
+            OhttpResponse::KnownLength(ohttp_res) => {
+                let mut res_buf = Vec::new();
+                response.read_to_end(&mut res_buf).map_err(InternalValidationError::Io)?;
+                crate::v2::ohttp_decapsulate(ohttp_res, &res_buf)
+            }
+            OhttpResponse::Chunked(ohttp_res) =>
+                crate::v2::ohttp_decapsulate_chunked(ohttp_res, response, MAX_CHUNKED_RESPONSE_LEN),
+        }
+        .map_err(InternalValidationError::OhttpEncapsulation)?;
         let mut body = match response.status() {
             http::StatusCode::OK => response.body().to_vec(),
             http::StatusCode::ACCEPTED => return Ok(None),
             _ => return Err(InternalValidationError::UnexpectedStatusCode)?,
         };
-        let psbt = crate::v2::decrypt_message_b(&mut body, self.e)
-            .map_err(InternalValidationError::HpkeError)?;
+        let psbt = match self.message_version {
+            crate::v2::MessageVersion::Hpke =>
+                crate::v2::decrypt_message_b(&mut body, self.e.expose(), self.rs),
+            crate::v2::MessageVersion::Legacy =>
+                crate::v2::decrypt_legacy_message_b(&mut body, self.e.expose()),
+        }
+        .map_err(InternalValidationError::HpkeError)?;
 
         let proposal = Psbt::deserialize(&psbt).map_err(InternalValidationError::Psbt)?;
         let processed_proposal = self.context_v1.process_proposal(proposal)?;
@@ -1078,13 +1297,290 @@ mod test {
             },
             sequence: Sequence::MAX,
             payee: ScriptBuf::from(vec![0x00]),
-            e: bitcoin::secp256k1::SecretKey::from_slice(&[0x01; 32]).unwrap(),
+            e: Arc::new(Secret::new(
+                bitcoin::secp256k1::SecretKey::from_slice(&[0x01; 32]).unwrap(),
+            )),
+            reply_digest: Some([0x02; 32]),
+            allow_unauthenticated_receiver: true,
         };
         let serialized = serde_json::to_string(&req_ctx).unwrap();
         let deserialized = serde_json::from_str(&serialized).unwrap();
         assert!(req_ctx == deserialized);
     }
 
+    #[test]
+    #[cfg(feature = "v2")]
+    fn ohttp_keys_are_checked_against_independent_ones() {
+        use ohttp::hpke::{Aead, Kdf, Kem};
+        use ohttp::SymmetricSuite;
+
+        use super::*;
+        use crate::uri::UrlExt;
+
+        let chacha = SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305);
+        let aes = SymmetricSuite::new(Kdf::HkdfSha256, Aead::Aes128Gcm);
+        let keys = |ikm: &[u8], suites: &[SymmetricSuite]| {
+            let config = ohttp::KeyConfig::derive(1, Kem::X25519Sha256, suites.to_vec(), ikm);
+            crate::OhttpKeys::from(config.unwrap())
+        };
+        let mut req_ctx = RequestContext {
+            psbt: Psbt::from_str(ORIGINAL_PSBT).unwrap(),
+            endpoint: Url::parse("https://directory.example/subdirectory").unwrap(),
+            disable_output_substitution: false,
+            fee_contribution: None,
+            min_fee_rate: FeeRate::ZERO,
+            input_type: InputType::SegWitV0 {
+                ty: crate::input_type::SegWitV0Type::Pubkey,
+                nested: true,
+            },
+            sequence: Sequence::MAX,
+            payee: ScriptBuf::from(vec![0x00]),
+            e: Arc::new(Secret::new(
+                bitcoin::secp256k1::SecretKey::from_slice(&[0x01; 32]).unwrap(),
+            )),
+            reply_digest: None,
+            allow_unauthenticated_receiver: false,
+        };
+        assert!(req_ctx.check_ohttp_keys(&[keys(&[1; 32], &[chacha])]).is_err());
+
+        req_ctx.endpoint.set_ohttp(Some(keys(&[1; 32], &[chacha])));
+        // the same key fetched later, offered with more suites, still matches
+        let fetched = keys(&[1; 32], &[chacha, aes]);
+        let other = keys(&[2; 32], &[chacha]);
+        assert!(req_ctx.check_ohttp_keys(std::slice::from_ref(&fetched)).is_ok());
+        assert!(req_ctx.check_ohttp_keys(&[other.clone(), fetched]).is_ok());
+        assert!(req_ctx.check_ohttp_keys(&[other]).is_err());
+        assert!(req_ctx.check_ohttp_keys(&[]).is_err());
+
+        // a URI made before the directory rotated its key matches while the old key is listed
+        let path = std::env::temp_dir()
+            .join(format!("payjoin-send-key-rotation-{}.keys", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let grace = std::time::Duration::from_secs(3600);
+        let mut gateway = payjoin_directory::GatewayKeys::load_or_create(&path, grace).unwrap();
+        let old = crate::OhttpKeys::decode(&gateway.encode().unwrap()).unwrap();
+        gateway.rotate().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let fetched = crate::OhttpKeys::decode(&gateway.encode_list().unwrap()).unwrap();
+        assert_ne!(fetched, old, "requests go to the new key");
+        req_ctx.endpoint.set_ohttp(Some(old));
+        assert!(req_ctx.check_ohttp_keys(std::slice::from_ref(&fetched)).is_ok());
+        // and no longer once it expired
+        let mut gateway = payjoin_directory::GatewayKeys::ephemeral().unwrap();
+        req_ctx
+            .endpoint
+            .set_ohttp(Some(crate::OhttpKeys::decode(&gateway.encode().unwrap()).unwrap()));
+        gateway.rotate().unwrap();
+        let fetched = crate::OhttpKeys::decode(&gateway.encode_list().unwrap()).unwrap();
+        assert!(req_ctx.check_ohttp_keys(&[fetched]).is_err());
+    }
+
+    /// Post an OHTTP request to an in-memory directory and return the encapsulated response
+    #[cfg(all(feature = "v2", feature = "receive"))]
+    async fn post_to_directory(
+        directory: &payjoin_directory::Directory,
+        path: &str,
+        body: Vec<u8>,
+    ) -> Vec<u8> {
+        use http_body_util::{BodyExt, Full};
+        use tower::ServiceExt;
+
+        let method = if body.is_empty() { http::Method::GET } else { http::Method::POST };
+        let req = http::Request::builder().method(method).uri(path).body(Full::from(body)).unwrap();
+        let res = directory.clone().oneshot(req).await.unwrap();
+        res.into_body().collect().await.unwrap().to_bytes().to_vec()
+    }
+
+    /// An in-memory directory, a receiver session on it, and a sender ready to pay that receiver
+    #[cfg(all(feature = "v2", feature = "receive"))]
+    async fn directory_with_session(
+    ) -> (payjoin_directory::Directory, crate::receive::v2::ActiveSession, super::RequestContext)
+    {
+        use std::time::Duration;
+
+        use payjoin_directory::{Config, DbBackend, Directory, GatewayKeys, ShutdownHandle};
+
+        use super::*;
+        use crate::receive::v2::SessionInitializer;
+
+        let config = Config {
+            timeout: Duration::from_millis(50),
+            db_backend: DbBackend::Memory,
+            ..Config::default()
+        };
+        let directory =
+            Directory::new(config, GatewayKeys::ephemeral().unwrap(), ShutdownHandle::new())
+                .await
+                .unwrap();
+        let ohttp_keys =
+            crate::OhttpKeys::decode(&post_to_directory(&directory, "/ohttp-keys", vec![]).await)
+                .unwrap();
+
+        let psbt = Psbt::from_str(ORIGINAL_PSBT).unwrap();
+        let payee = &psbt.unsigned_tx.output[0];
+        let address =
+            bitcoin::Address::from_script(&payee.script_pubkey, bitcoin::Network::Bitcoin).unwrap();
+        let mut initializer = SessionInitializer::new(
+            address,
+            Url::parse("https://directory.example").unwrap(),
+            ohttp_keys,
+            Url::parse("https://relay.example").unwrap(),
+            None,
+        );
+        let (req, ctx) = initializer.extract_req().unwrap();
+        let res = post_to_directory(&directory, "/", req.body).await;
+        let session = initializer.process_res(res.as_slice(), ctx).unwrap();
+        let uri = session.pj_uri_builder().amount(payee.value).build();
+
+        let req_ctx = RequestBuilder::from_psbt_and_uri(psbt, uri)
+            .unwrap()
+            .build_non_incentivizing(FeeRate::MIN)
+            .unwrap();
+        (directory, session, req_ctx)
+    }
+
+    #[tokio::test]
+    #[cfg(all(feature = "v2", feature = "receive"))]
+    async fn the_original_is_posted_once_then_polled_for() {
+        use super::*;
+
+        let (directory, mut session, mut req_ctx) = directory_with_session().await;
+        let relay = Url::parse("https://relay.example").unwrap();
+        let (post, ctx) = req_ctx.extract_v2(relay.clone()).unwrap();
+        let res = post_to_directory(&directory, "/", post.body.clone()).await;
+        assert_eq!(req_ctx.process_v2_response(ctx, &mut res.as_slice()).unwrap(), None);
+        assert!(req_ctx.is_original_delivered());
+        let persisted: RequestContext =
+            serde_json::from_str(&serde_json::to_string(&req_ctx).unwrap()).unwrap();
+        assert!(persisted.is_original_delivered());
+
+        // The receiver is handed the Original and drops it without a reply
+        let (req, ctx) = session.extract_req().unwrap();
+        let res = post_to_directory(&directory, "/", req.body).await;
+        assert!(session.process_res(res.as_slice(), ctx).unwrap().is_some());
+        let (req, ctx) = session.extract_drop_req().unwrap();
+        let res = post_to_directory(&directory, "/", req.body).await;
+        session.process_drop_res(res.as_slice(), ctx).unwrap();
+
+        // Polls don't carry the Original, so it isn't queued again
+        let (poll, ctx) = req_ctx.extract_v2(relay).unwrap();
+        assert!(poll.body.len() < post.body.len());
+        let res = post_to_directory(&directory, "/", poll.body).await;
+        assert_eq!(req_ctx.process_v2_response(ctx, &mut res.as_slice()).unwrap(), None);
+        let (req, ctx) = session.extract_req().unwrap();
+        let res = post_to_directory(&directory, "/", req.body).await;
+        assert!(session.process_res(res.as_slice(), ctx).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[cfg(all(feature = "v2", feature = "receive"))]
+    async fn legacy_receivers_are_sent_the_original_only_when_allowed() {
+        use bitcoin::base64::prelude::BASE64_URL_SAFE_NO_PAD;
+        use bitcoin::base64::Engine;
+
+        use super::*;
+        use crate::uri::UrlExt;
+
+        let (directory, mut session, mut req_ctx) = directory_with_session().await;
+        req_ctx.endpoint.set_message_version(None);
+        let relay = Url::parse("https://relay.example").unwrap();
+        let err = req_ctx.extract_v2(relay.clone()).err().expect("legacy receivers are refused");
+        assert!(err.to_string().contains("original transaction was not sent"));
+        assert!(!req_ctx.is_original_delivered());
+
+        let psbt = Psbt::from_str(ORIGINAL_PSBT).unwrap();
+        let payee = psbt.unsigned_tx.output[0].clone();
+        let mut uri = session.pj_uri_builder().amount(payee.value).build();
+        uri.extras.endpoint.set_message_version(None);
+        let mut req_ctx = RequestBuilder::from_psbt_and_uri(psbt, uri)
+            .unwrap()
+            .allow_unauthenticated_receiver(true)
+            .build_non_incentivizing(FeeRate::MIN)
+            .unwrap();
+        let (post, ctx) = req_ctx.extract_v2(relay.clone()).unwrap();
+        let res = post_to_directory(&directory, "/", post.body.clone()).await;
+        assert_eq!(req_ctx.process_v2_response(ctx, &mut res.as_slice()).unwrap(), None);
+        assert!(!req_ctx.is_original_delivered(), "legacy receivers are polled with the Original");
+
+        // The receiver is handed the Original, and answers as a receiver without `mv=` would
+        let (req, ctx) = session.extract_req().unwrap();
+        let res = post_to_directory(&directory, "/", req.body).await;
+        assert!(session.process_res(res.as_slice(), ctx).unwrap().is_some());
+        let persisted = serde_json::to_value(&session).unwrap();
+        let s = bitcoin::secp256k1::Keypair::from_str(
+            persisted.pointer("/context/s").unwrap().as_str().unwrap(),
+        )
+        .unwrap();
+        let mut proposal = Psbt::from_str(PAYJOIN_PROPOSAL).unwrap();
+        for output in proposal.outputs_mut() {
+            output.bip32_derivation.clear();
+        }
+        for input in proposal.inputs_mut() {
+            input.bip32_derivation.clear();
+        }
+        proposal.inputs_mut()[0].witness_utxo = None;
+        let body = crate::v2::encrypt_message_b(
+            &mut proposal.serialize(),
+            req_ctx.e.expose().public_key(&bitcoin::secp256k1::Secp256k1::signing_only()),
+            &s.secret_key(),
+            crate::v2::MessageVersion::Legacy,
+        )
+        .unwrap();
+        let body = mailbox_auth::sign(mailbox_auth::POST_PAYJOIN_TAG, &s, &[], &body);
+        let mailbox = format!(
+            "https://directory.example/{}/payjoin",
+            BASE64_URL_SAFE_NO_PAD.encode(s.public_key().serialize())
+        );
+        let mut ohttp_keys =
+            crate::OhttpKeys::decode(&post_to_directory(&directory, "/ohttp-keys", vec![]).await)
+                .unwrap();
+        let (req, _) =
+            crate::v2::ohttp_encapsulate(&mut ohttp_keys, "POST", &mailbox, Some(&body)).unwrap();
+        post_to_directory(&directory, "/", req).await;
+
+        let (poll, ctx) = req_ctx.extract_v2(relay).unwrap();
+        assert_eq!(poll.body.len(), post.body.len(), "the same Original is posted again");
+        let res = post_to_directory(&directory, "/", poll.body).await;
+        let payjoin = req_ctx.process_v2_response(ctx, &mut res.as_slice()).unwrap();
+        assert_eq!(payjoin.unwrap().unsigned_tx, proposal.unsigned_tx);
+    }
+
+    #[tokio::test]
+    #[cfg(all(feature = "v2", feature = "receive"))]
+    async fn chunked_requests_round_trip_through_the_directory() {
+        use http_body_util::{BodyExt, Full};
+        use tower::ServiceExt;
+
+        use super::*;
+
+        let (directory, mut session, mut req_ctx) = directory_with_session().await;
+        let relay = Url::parse("https://relay.example").unwrap();
+        let post_chunked = |body: Vec<u8>| {
+            let req = http::Request::post("/")
+                .header(http::header::CONTENT_TYPE, crate::V2_CHUNKED_REQ_CONTENT_TYPE)
+                .body(Full::from(body))
+                .unwrap();
+            directory.clone().oneshot(req)
+        };
+
+        let (post, ctx) = req_ctx.extract_v2_chunked(relay.clone()).unwrap();
+        let res = post_chunked(post.body).await.unwrap();
+        assert_eq!(res.headers()[http::header::CONTENT_TYPE], "message/ohttp-chunked-res");
+        let res = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(req_ctx.process_v2_response(ctx, &mut res.as_ref()).unwrap(), None);
+        assert!(req_ctx.is_original_delivered());
+
+        // The receiver, which doesn't speak chunked OHTTP, is handed the same Original
+        let (req, ctx) = session.extract_req().unwrap();
+        let res = post_to_directory(&directory, "/", req.body).await;
+        assert!(session.process_res(res.as_slice(), ctx).unwrap().is_some());
+
+        let (poll, ctx) = req_ctx.extract_v2_chunked(relay).unwrap();
+        let res = post_chunked(poll.body).await.unwrap();
+        let res = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(req_ctx.process_v2_response(ctx, &mut res.as_ref()).unwrap(), None);
+    }
+
     #[test]
     fn handle_json_errors() {
         let ctx = create_v1_context();
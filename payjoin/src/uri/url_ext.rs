@@ -2,6 +2,7 @@ use std::str::FromStr;
 
 use url::Url;
 
+use crate::v2::MessageVersion;
 use crate::OhttpKeys;
 
 /// Parse and set fragment parameters from `&pj=` URI parameter URLs
@@ -10,6 +11,9 @@ pub(crate) trait UrlExt {
     fn set_ohttp(&mut self, ohttp: Option<OhttpKeys>);
     fn exp(&self) -> Option<std::time::SystemTime>;
     fn set_exp(&mut self, exp: Option<std::time::SystemTime>);
+    fn message_version(&self) -> Option<MessageVersion>;
+    #[cfg(feature = "receive")]
+    fn set_message_version(&mut self, version: Option<MessageVersion>);
 }
 
 impl UrlExt for Url {
@@ -43,6 +47,18 @@ impl UrlExt for Url {
         });
         set_param(self, "exp=", exp_str)
     }
+
+    /// Retrieve the newest message version supported both here and by the receiver from the
+    /// mv parameter in the URL fragment
+    fn message_version(&self) -> Option<MessageVersion> {
+        get_param(self, "mv=", |value| value.parse::<u8>().ok().map(MessageVersion::negotiate))
+    }
+
+    /// Set the mv parameter in the URL fragment
+    #[cfg(feature = "receive")]
+    fn set_message_version(&mut self, version: Option<MessageVersion>) {
+        set_param(self, "mv=", version.map(|v| u8::from(v).to_string()))
+    }
 }
 
 fn get_param<F, T>(url: &Url, prefix: &str, parse: F) -> Option<T>
@@ -119,6 +135,23 @@ mod tests {
         assert_eq!(url.fragment(), None);
     }
 
+    #[test]
+    #[cfg(feature = "receive")]
+    fn test_message_version_get_set() {
+        let mut url = Url::parse("https://example.com#exp=1720547781").unwrap();
+        assert_eq!(url.message_version(), None);
+
+        url.set_message_version(Some(MessageVersion::Hpke));
+        assert_eq!(url.fragment(), Some("exp=1720547781&mv=1"));
+        assert_eq!(url.message_version(), Some(MessageVersion::Hpke));
+
+        url.set_fragment(Some("mv=7"));
+        assert_eq!(url.message_version(), Some(MessageVersion::Hpke), "newer receivers");
+
+        url.set_message_version(None);
+        assert_eq!(url.fragment(), None);
+    }
+
     #[test]
     fn test_invalid_v2_url_fragment_on_bip21() {
         // fragment is not percent encoded so `&ohttp=` is parsed as a query parameter, not a fragment parameter
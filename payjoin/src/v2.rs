@@ -5,47 +5,171 @@ use bitcoin::base64::prelude::BASE64_URL_SAFE_NO_PAD;
 use bitcoin::base64::Engine;
 use bitcoin::secp256k1::ecdh::SharedSecret;
 use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
-use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
-use chacha20poly1305::{AeadCore, ChaCha20Poly1305, Nonce};
+#[cfg(feature = "receive")]
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+/// Plaintexts are padded to the smallest of these sizes that fits them, so the size of a
+/// message reveals only its bucket. Most PSBTs fit the first, 7KiB, and each bucket is four
+/// times the last to leave room for consolidations and `non_witness_utxo`s.
+pub const PADDING_BUCKETS: [usize; 4] = [7168, 28672, 114688, 458752];
+
+/// The largest plaintext a v2 message can carry
+pub const MAX_PADDED_MESSAGE_BYTES: usize = PADDING_BUCKETS[PADDING_BUCKETS.len() - 1];
+
+/// HPKE info binding each context to the message it encrypts
+const MESSAGE_A_INFO: &[u8] = b"payjoin/v2 message a";
+const MESSAGE_B_INFO: &[u8] = b"payjoin/v2 message b";
+
+/// The encryption of v2 messages A and B.
+///
+/// Receivers advertise the newest version they support in the `mv=` fragment parameter of
+/// their `pj=` URL and answer in the version the sender chose. Receivers that advertise none
+/// predate HPKE, and senders fall back to [`MessageVersion::Legacy`] for them only if they
+/// allow receivers that can't authenticate their proposal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MessageVersion {
+    /// Raw secp256k1 ECDH shared secrets used directly as ChaCha20Poly1305 keys
+    Legacy = 0,
+    /// [RFC 9180](https://www.rfc-editor.org/rfc/rfc9180) HPKE, see [`crate::hpke`]
+    Hpke = 1,
+}
+
+impl MessageVersion {
+    #[cfg(feature = "receive")]
+    pub(crate) const LATEST: Self = Self::Hpke;
+
+    /// The newest version supported both here and by a receiver advertising `advertised`
+    pub(crate) fn negotiate(advertised: u8) -> Self {
+        if advertised >= Self::Hpke as u8 {
+            Self::Hpke
+        } else {
+            Self::Legacy
+        }
+    }
+}
+
+impl From<MessageVersion> for u8 {
+    fn from(version: MessageVersion) -> u8 { version as u8 }
+}
+
+impl TryFrom<u8> for MessageVersion {
+    type Error = HpkeError;
 
-pub const PADDED_MESSAGE_BYTES: usize = 7168; // 7KB
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            0 => Ok(Self::Legacy),
+            1 => Ok(Self::Hpke),
+            _ => Err(HpkeError::UnsupportedVersion(version)),
+        }
+    }
+}
 
 /// crypto context
 ///
 /// <- Receiver S
 /// -> Sender E, ES(payload), payload protected by knowledge of receiver key
 /// <- Receiver E, EE(payload), payload protected by knowledge of sender & receiver key
+///
+/// An HPKE message A is `version || enc || E || ciphertext`, encrypted to S in auth mode
+/// with E as the sender's key, so the receiver knows whom to answer.
 #[cfg(feature = "send")]
 pub fn encrypt_message_a(
     mut raw_msg: Vec<u8>,
-    e_sec: SecretKey,
+    e_sec: &SecretKey,
     s: PublicKey,
+    version: MessageVersion,
 ) -> Result<Vec<u8>, HpkeError> {
+    use bitcoin::hashes::{sha256, Hash, HashEngine};
+
+    if version == MessageVersion::Legacy {
+        return encrypt_legacy_message_a(raw_msg, e_sec, s);
+    }
+    let e_pub = e_sec.public_key(&Secp256k1::signing_only());
+    let msg = pad(&mut raw_msg)?;
+    // Resending the message must yield the same ciphertext so the directory can recognize it
+    // as a duplicate, so the HPKE ephemeral key is derived from E and the message
+    let mut engine = sha256::Hash::engine();
+    engine.input(MESSAGE_A_INFO);
+    engine.input(&e_sec.secret_bytes());
+    engine.input(&s.serialize());
+    engine.input(msg);
+    let ikm_e = sha256::Hash::from_engine(engine).to_byte_array();
+    let (enc, mut ctx) = crate::hpke::setup_sender(&s, MESSAGE_A_INFO, Some(e_sec), &ikm_e)?;
+    let header = [&[version.into()][..], &enc, &e_pub.serialize_uncompressed()].concat();
+    let c_t = ctx.seal(&header, msg)?;
+    Ok([header, c_t].concat())
+}
+
+/// A legacy message A is `E || nonce || ciphertext`, encrypted with the raw ES shared secret.
+///
+/// Legacy receivers are polled by posting the same message A again, so the nonce is derived
+/// from the key and the message for the directory to recognize it as a duplicate.
+#[cfg(feature = "send")]
+fn encrypt_legacy_message_a(
+    mut raw_msg: Vec<u8>,
+    e_sec: &SecretKey,
+    s: PublicKey,
+) -> Result<Vec<u8>, HpkeError> {
+    use bitcoin::hashes::{sha256, Hash, HashEngine};
+
     let secp = Secp256k1::new();
     let e_pub = e_sec.public_key(&secp);
-    let es = SharedSecret::new(&s, &e_sec);
+    let es = SharedSecret::new(&s, e_sec);
     let cipher = ChaCha20Poly1305::new_from_slice(&es.secret_bytes())
         .map_err(|_| HpkeError::InvalidKeyLength)?;
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng); // key es encrypts only 1 message so 0 is unique
     let aad = &e_pub.serialize();
     let msg = pad(&mut raw_msg)?;
+    let mut engine = sha256::Hash::engine();
+    engine.input(&es.secret_bytes());
+    engine.input(msg);
+    let nonce = sha256::Hash::from_engine(engine).to_byte_array();
+    let nonce = Nonce::from_slice(&nonce[..12]);
     let payload = Payload { msg, aad };
-    let c_t: Vec<u8> = cipher.encrypt(&nonce, payload)?;
+    let c_t: Vec<u8> = cipher.encrypt(nonce, payload)?;
     let mut message_a = e_pub.serialize().to_vec();
     message_a.extend(&nonce[..]);
     message_a.extend(&c_t[..]);
     Ok(message_a)
 }
 
+/// Decrypt message A in whichever version the sender chose, which the reply must use too.
 #[cfg(feature = "receive")]
 pub fn decrypt_message_a(
     message_a: &[u8],
-    s: SecretKey,
+    s: &SecretKey,
+) -> Result<(Vec<u8>, PublicKey, MessageVersion), HpkeError> {
+    use crate::hpke::N_PK;
+
+    // A legacy message A starts with a compressed public key, whose prefix is 0x02 or 0x03
+    match message_a.first().copied().ok_or(HpkeError::PayloadTooShort)? {
+        0x02 | 0x03 => {
+            let (buffer, e) = decrypt_legacy_message_a(message_a, s)?;
+            Ok((buffer, e, MessageVersion::Legacy))
+        }
+        version => {
+            let version = MessageVersion::try_from(version)?;
+            let header = message_a.get(..1 + 2 * N_PK).ok_or(HpkeError::PayloadTooShort)?;
+            let (enc, e) = header[1..].split_at(N_PK);
+            let enc = enc.try_into().expect("split at the encapsulated key length");
+            let e = PublicKey::from_slice(e)?;
+            let mut ctx = crate::hpke::setup_receiver(enc, s, MESSAGE_A_INFO, Some(&e))?;
+            let buffer = ctx.open(header, &message_a[header.len()..])?;
+            Ok((buffer, e, version))
+        }
+    }
+}
+
+#[cfg(feature = "receive")]
+fn decrypt_legacy_message_a(
+    message_a: &[u8],
+    s: &SecretKey,
 ) -> Result<(Vec<u8>, PublicKey), HpkeError> {
     // let message a = [pubkey/AD][nonce][authentication tag][ciphertext]
     let e = PublicKey::from_slice(message_a.get(..33).ok_or(HpkeError::PayloadTooShort)?)?;
     let nonce = Nonce::from_slice(message_a.get(33..45).ok_or(HpkeError::PayloadTooShort)?);
-    let es = SharedSecret::new(&e, &s);
+    let es = SharedSecret::new(&e, s);
     let cipher = ChaCha20Poly1305::new_from_slice(&es.secret_bytes())
         .map_err(|_| HpkeError::InvalidKeyLength)?;
     let c_t = message_a.get(45..).ok_or(HpkeError::PayloadTooShort)?;
@@ -55,8 +179,34 @@ pub fn decrypt_message_a(
     Ok((buffer, e))
 }
 
+/// An HPKE message B is `version || enc || ciphertext`, encrypted to E in auth mode with S
+/// as the sender's key, so that only the receiver whose session key the sender paid to can
+/// have made the proposal.
+#[cfg(feature = "receive")]
+pub fn encrypt_message_b(
+    raw_msg: &mut Vec<u8>,
+    re_pub: PublicKey,
+    s: &SecretKey,
+    version: MessageVersion,
+) -> Result<Vec<u8>, HpkeError> {
+    use chacha20poly1305::aead::rand_core::RngCore;
+
+    if version == MessageVersion::Legacy {
+        return encrypt_legacy_message_b(raw_msg, re_pub);
+    }
+    let mut ikm_e = [0u8; 32];
+    OsRng.fill_bytes(&mut ikm_e);
+    let (enc, mut ctx) = crate::hpke::setup_sender(&re_pub, MESSAGE_B_INFO, Some(s), &ikm_e)?;
+    let header = [&[version.into()][..], &enc].concat();
+    let c_t = ctx.seal(&header, pad(raw_msg)?)?;
+    Ok([header, c_t].concat())
+}
+
 #[cfg(feature = "receive")]
-pub fn encrypt_message_b(raw_msg: &mut Vec<u8>, re_pub: PublicKey) -> Result<Vec<u8>, HpkeError> {
+fn encrypt_legacy_message_b(
+    raw_msg: &mut Vec<u8>,
+    re_pub: PublicKey,
+) -> Result<Vec<u8>, HpkeError> {
     // let message b = [pubkey/AD][nonce][authentication tag][ciphertext]
     let secp = Secp256k1::new();
     let (e_sec, e_pub) = secp.generate_keypair(&mut OsRng);
@@ -74,29 +224,65 @@ pub fn encrypt_message_b(raw_msg: &mut Vec<u8>, re_pub: PublicKey) -> Result<Vec
     Ok(message_b)
 }
 
+/// Decrypt an HPKE message B, which only the receiver holding the session key `rs` can have
+/// encrypted. Legacy messages B are never authenticated, see [`decrypt_legacy_message_b`].
 #[cfg(feature = "send")]
-pub fn decrypt_message_b(message_b: &mut [u8], e: SecretKey) -> Result<Vec<u8>, HpkeError> {
-    // let message b = [pubkey/AD][nonce][authentication tag][ciphertext]
+pub fn decrypt_message_b(
+    message_b: &mut [u8],
+    e: &SecretKey,
+    rs: PublicKey,
+) -> Result<Vec<u8>, HpkeError> {
+    use crate::hpke::N_PK;
+
+    let header = message_b.get(..1 + N_PK).ok_or(HpkeError::PayloadTooShort)?;
+    if header[0] != u8::from(MessageVersion::Hpke) {
+        return Err(HpkeError::UnsupportedVersion(header[0]));
+    }
+    let enc = header[1..].try_into().expect("sliced to the encapsulated key length");
+    let mut ctx = crate::hpke::setup_receiver(enc, e, MESSAGE_B_INFO, Some(&rs))?;
+    ctx.open(header, &message_b[header.len()..])
+}
+
+/// Decrypt a legacy message B, `RE || nonce || ciphertext` encrypted with the raw EE shared
+/// secret. Anyone who learned E could have encrypted it, so only senders that allowed
+/// receivers which can't authenticate their proposal may accept it.
+#[cfg(feature = "send")]
+pub fn decrypt_legacy_message_b(message_b: &mut [u8], e: &SecretKey) -> Result<Vec<u8>, HpkeError> {
     let re = PublicKey::from_slice(message_b.get(..33).ok_or(HpkeError::PayloadTooShort)?)?;
     let nonce = Nonce::from_slice(message_b.get(33..45).ok_or(HpkeError::PayloadTooShort)?);
-    let ee = SharedSecret::new(&re, &e);
+    let ee = SharedSecret::new(&re, e);
     let cipher = ChaCha20Poly1305::new_from_slice(&ee.secret_bytes())
         .map_err(|_| HpkeError::InvalidKeyLength)?;
     let payload = Payload {
         msg: message_b.get(45..).ok_or(HpkeError::PayloadTooShort)?,
         aad: &re.serialize(),
     };
-    let buffer = cipher.decrypt(nonce, payload)?;
-    Ok(buffer)
+    Ok(cipher.decrypt(nonce, payload)?)
+}
+
+/// The digest of a sender's message A, which names the directory slot the reply to it goes in
+pub(crate) fn reply_digest(message_a: &[u8]) -> [u8; 32] {
+    use bitcoin::hashes::{sha256, Hash};
+
+    sha256::Hash::hash(message_a).to_byte_array()
+}
+
+/// The mailbox path of the reply slot named by `reply_digest`
+pub(crate) fn reply_slot_url(mailbox: &url::Url, reply_digest: &[u8; 32]) -> url::Url {
+    let mut url = mailbox.clone();
+    url.set_fragment(None);
+    url.path_segments_mut()
+        .expect("Payjoin Directory URL cannot be a base")
+        .push(&BASE64_URL_SAFE_NO_PAD.encode(reply_digest));
+    url
 }
 
 fn pad(msg: &mut Vec<u8>) -> Result<&[u8], HpkeError> {
-    if msg.len() > PADDED_MESSAGE_BYTES {
-        return Err(HpkeError::PayloadTooLarge);
-    }
-    while msg.len() < PADDED_MESSAGE_BYTES {
-        msg.push(0);
-    }
+    let bucket = PADDING_BUCKETS
+        .iter()
+        .find(|bucket| msg.len() <= **bucket)
+        .ok_or(HpkeError::PayloadTooLarge)?;
+    msg.resize(*bucket, 0);
     Ok(msg)
 }
 
@@ -108,6 +294,8 @@ pub enum HpkeError {
     InvalidKeyLength,
     PayloadTooLarge,
     PayloadTooShort,
+    DeriveKeyPair,
+    UnsupportedVersion(u8),
 }
 
 impl From<bitcoin::secp256k1::Error> for HpkeError {
@@ -127,8 +315,10 @@ impl fmt::Display for HpkeError {
             ChaCha20Poly1305(e) => e.fmt(f),
             InvalidKeyLength => write!(f, "Invalid Length"),
             PayloadTooLarge =>
-                write!(f, "Payload too large, max size is {} bytes", PADDED_MESSAGE_BYTES),
+                write!(f, "Payload too large, max size is {} bytes", MAX_PADDED_MESSAGE_BYTES),
             PayloadTooShort => write!(f, "Payload too small"),
+            DeriveKeyPair => write!(f, "Failed to derive a key pair"),
+            UnsupportedVersion(v) => write!(f, "Unsupported message version {}", v),
         }
     }
 }
@@ -139,7 +329,12 @@ impl error::Error for HpkeError {
 
         match &self {
             Secp256k1(e) => Some(e),
-            ChaCha20Poly1305(_) | InvalidKeyLength | PayloadTooLarge | PayloadTooShort => None,
+            ChaCha20Poly1305(_)
+            | InvalidKeyLength
+            | PayloadTooLarge
+            | PayloadTooShort
+            | DeriveKeyPair
+            | UnsupportedVersion(_) => None,
         }
     }
 }
@@ -150,9 +345,33 @@ pub fn ohttp_encapsulate(
     target_resource: &str,
     body: Option<&[u8]>,
 ) -> Result<(Vec<u8>, ohttp::ClientResponse), OhttpEncapsulationError> {
+    let ctx = ohttp::ClientRequest::from_config(ohttp_keys)?;
+    let bhttp_req = bhttp_request(method, target_resource, body, bhttp::Mode::KnownLength)?;
+    let encapsulated = ctx.encapsulate(&bhttp_req)?;
+    Ok(encapsulated)
+}
+
+/// Like [`ohttp_encapsulate`], but as a chunked OHTTP request of an indeterminate-length
+/// bhttp message, to be sent as [`crate::V2_CHUNKED_REQ_CONTENT_TYPE`]
+#[cfg(feature = "send")]
+pub fn ohttp_encapsulate_chunked(
+    ohttp_keys: &ohttp::KeyConfig,
+    method: &str,
+    target_resource: &str,
+    body: Option<&[u8]>,
+) -> Result<(Vec<u8>, chunked_ohttp::ClientResponse), OhttpEncapsulationError> {
+    let bhttp_req = bhttp_request(method, target_resource, body, bhttp::Mode::IndeterminateLength)?;
+    Ok(chunked_ohttp::encapsulate(ohttp_keys, &bhttp_req)?)
+}
+
+fn bhttp_request(
+    method: &str,
+    target_resource: &str,
+    body: Option<&[u8]>,
+    mode: bhttp::Mode,
+) -> Result<Vec<u8>, OhttpEncapsulationError> {
     use std::fmt::Write;
 
-    let ctx = ohttp::ClientRequest::from_config(ohttp_keys)?;
     let url = url::Url::parse(target_resource)?;
     let authority_bytes = url.host().map_or_else(Vec::new, |host| {
         let mut authority = host.to_string();
@@ -171,9 +390,8 @@ pub fn ohttp_encapsulate(
         bhttp_message.write_content(body);
     }
     let mut bhttp_req = Vec::new();
-    let _ = bhttp_message.write_bhttp(bhttp::Mode::KnownLength, &mut bhttp_req);
-    let encapsulated = ctx.encapsulate(&bhttp_req)?;
-    Ok(encapsulated)
+    let _ = bhttp_message.write_bhttp(mode, &mut bhttp_req);
+    Ok(bhttp_req)
 }
 
 /// decapsulate ohttp, bhttp response and return http response body and status code
@@ -182,6 +400,23 @@ pub fn ohttp_decapsulate(
     ohttp_body: &[u8],
 ) -> Result<http::Response<Vec<u8>>, OhttpEncapsulationError> {
     let bhttp_body = res_ctx.decapsulate(ohttp_body)?;
+    bhttp_response(bhttp_body)
+}
+
+/// Like [`ohttp_decapsulate`], but opening a chunked response as it's read, and refusing it
+/// once its bhttp message grows past `max_len` bytes
+#[cfg(feature = "send")]
+pub fn ohttp_decapsulate_chunked(
+    res_ctx: chunked_ohttp::ClientResponse,
+    ohttp_body: &mut impl std::io::Read,
+    max_len: usize,
+) -> Result<http::Response<Vec<u8>>, OhttpEncapsulationError> {
+    let bhttp_body = res_ctx.decapsulate(ohttp_body, max_len)?;
+    bhttp_response(bhttp_body)
+}
+
+/// Parse a bhttp response of either known or indeterminate length
+fn bhttp_response(bhttp_body: Vec<u8>) -> Result<http::Response<Vec<u8>>, OhttpEncapsulationError> {
     let mut r = std::io::Cursor::new(bhttp_body);
     let m: bhttp::Message = bhttp::Message::read_bhttp(&mut r)?;
     http::Response::builder()
@@ -197,6 +432,8 @@ pub enum OhttpEncapsulationError {
     Ohttp(ohttp::Error),
     Bhttp(bhttp::Error),
     ParseUrl(url::ParseError),
+    #[cfg(feature = "send")]
+    Chunked(chunked_ohttp::Error),
 }
 
 impl From<http::Error> for OhttpEncapsulationError {
@@ -215,6 +452,11 @@ impl From<url::ParseError> for OhttpEncapsulationError {
     fn from(value: url::ParseError) -> Self { Self::ParseUrl(value) }
 }
 
+#[cfg(feature = "send")]
+impl From<chunked_ohttp::Error> for OhttpEncapsulationError {
+    fn from(value: chunked_ohttp::Error) -> Self { Self::Chunked(value) }
+}
+
 impl fmt::Display for OhttpEncapsulationError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use OhttpEncapsulationError::*;
@@ -224,6 +466,8 @@ impl fmt::Display for OhttpEncapsulationError {
             Ohttp(e) => e.fmt(f),
             Bhttp(e) => e.fmt(f),
             ParseUrl(e) => e.fmt(f),
+            #[cfg(feature = "send")]
+            Chunked(e) => e.fmt(f),
         }
     }
 }
@@ -237,17 +481,123 @@ impl error::Error for OhttpEncapsulationError {
             Ohttp(e) => Some(e),
             Bhttp(e) => Some(e),
             ParseUrl(e) => Some(e),
+            #[cfg(feature = "send")]
+            Chunked(e) => Some(e),
         }
     }
 }
 
+/// A directory's OHTTP key configurations.
+///
+/// Requests are encapsulated to the first. Any others came from the same
+/// `application/ohttp-keys` list, such as keys the gateway still accepts after a rotation, and
+/// only serve to recognize `pj=` URIs made with them. Only the first is persisted.
 #[derive(Debug, Clone)]
-pub struct OhttpKeys(pub ohttp::KeyConfig);
+pub struct OhttpKeys(
+    pub ohttp::KeyConfig,
+    // receivers only ever encapsulate to the first
+    #[cfg_attr(not(feature = "send"), allow(dead_code))] Vec<ohttp::KeyConfig>,
+);
+
+/// The KDF and AEAD combinations this client can encapsulate to, all with the X25519 KEM
+const SUPPORTED_SUITES: [ohttp::SymmetricSuite; 2] = [
+    ohttp::SymmetricSuite::new(ohttp::hpke::Kdf::HkdfSha256, ohttp::hpke::Aead::ChaCha20Poly1305),
+    ohttp::SymmetricSuite::new(ohttp::hpke::Kdf::HkdfSha256, ohttp::hpke::Aead::Aes128Gcm),
+];
+
+impl OhttpKeys {
+    /// Decode either a single OHTTP key configuration, as embedded in `ohttp=` URI parameters,
+    /// or a [RFC 9458](https://www.rfc-editor.org/rfc/rfc9458#section-3.2) `application/ohttp-keys`
+    /// list of them.
+    ///
+    /// From a list, the first configuration with a KEM and symmetric suite this client supports
+    /// is selected, so the gateway's order of preference is respected, and the other supported
+    /// ones are kept to recognize. Configurations this client can't use are skipped, but
+    /// malformed ones and reused key ids fail the whole list.
+    pub fn decode(bytes: &[u8]) -> Result<Self, OhttpKeysError> {
+        match split_key_config_list(bytes) {
+            Some(encoded_configs) => select_key_config(encoded_configs),
+            None => {
+                let config = decode_key_config(bytes)?.ok_or(OhttpKeysError::NoSupportedSuite)?;
+                Ok(Self::from(config))
+            }
+        }
+    }
+}
 
 impl OhttpKeys {
-    /// Decode an OHTTP KeyConfig
-    pub fn decode(bytes: &[u8]) -> Result<Self, ohttp::Error> {
-        ohttp::KeyConfig::decode(bytes).map(Self)
+    /// Whether the key `other` is encapsulated to is one of these, whichever symmetric suites
+    /// each offers it with
+    #[cfg(feature = "send")]
+    pub(crate) fn contains_key(&self, other: &Self) -> bool {
+        match key_identity(other) {
+            Some(other_key) => std::iter::once(&self.0)
+                .chain(&self.1)
+                .any(|config| key_identity(config).as_ref() == Some(&other_key)),
+            None => false,
+        }
+    }
+}
+
+impl From<ohttp::KeyConfig> for OhttpKeys {
+    fn from(config: ohttp::KeyConfig) -> Self { Self(config, vec![]) }
+}
+
+/// The key id, KEM id and public key that lead the encoded configuration
+#[cfg(feature = "send")]
+fn key_identity(config: &ohttp::KeyConfig) -> Option<Vec<u8>> {
+    let encoded = config.encode().ok()?;
+    let kem_id = u16::from_be_bytes([*encoded.get(1)?, *encoded.get(2)?]);
+    let kem = ohttp::hpke::Kem::try_from(kem_id).ok()?;
+    encoded.get(..3 + kem.n_pk()).map(<[u8]>::to_vec)
+}
+
+/// Split an `application/ohttp-keys` list into its length-prefixed configurations.
+///
+/// Returns `None` unless the prefixes cover `bytes` exactly. A lone configuration never does:
+/// its first two bytes, a key id and the high byte of a KEM id, read either as a zero length
+/// or as one longer than any single X25519 configuration.
+fn split_key_config_list(bytes: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut encoded_configs = vec![];
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            return None;
+        }
+        let (len, tail) = rest.split_at(2);
+        let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+        if len == 0 || len > tail.len() {
+            return None;
+        }
+        let (encoded_config, tail) = tail.split_at(len);
+        encoded_configs.push(encoded_config);
+        rest = tail;
+    }
+    (!encoded_configs.is_empty()).then_some(encoded_configs)
+}
+
+fn select_key_config(encoded_configs: Vec<&[u8]>) -> Result<OhttpKeys, OhttpKeysError> {
+    let mut key_ids = std::collections::BTreeSet::new();
+    let mut supported = vec![];
+    for encoded_config in encoded_configs {
+        // the key id leads every configuration, supported or not
+        if !key_ids.insert(encoded_config[0]) {
+            return Err(OhttpKeysError::DuplicateKeyId(encoded_config[0]));
+        }
+        supported.extend(decode_key_config(encoded_config)?);
+    }
+    let mut supported = supported.into_iter();
+    let selected = supported.next().ok_or(OhttpKeysError::NoSupportedSuite)?;
+    Ok(OhttpKeys(selected, supported.collect()))
+}
+
+/// Decode one configuration, or `None` if it offers nothing this client supports
+fn decode_key_config(encoded_config: &[u8]) -> Result<Option<ohttp::KeyConfig>, OhttpKeysError> {
+    match ohttp::KeyConfig::decode(encoded_config) {
+        Ok(config) if SUPPORTED_SUITES.iter().any(|suite| config.select(*suite).is_ok()) =>
+            Ok(Some(config)),
+        Ok(_) | Err(ohttp::Error::Unsupported) => Ok(None),
+        Err(e) => Err(OhttpKeysError::Decode(e)),
     }
 }
 
@@ -309,10 +659,48 @@ impl serde::Serialize for OhttpKeys {
     }
 }
 
+/// Error from decoding OHTTP key configurations.
+#[derive(Debug)]
+pub enum OhttpKeysError {
+    /// A key configuration is malformed
+    Decode(ohttp::Error),
+    /// Two configurations in a list share a key id, so a gateway couldn't tell them apart
+    DuplicateKeyId(ohttp::KeyId),
+    /// No configuration offers a KEM, KDF and AEAD this client supports
+    NoSupportedSuite,
+}
+
+impl fmt::Display for OhttpKeysError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use OhttpKeysError::*;
+
+        match &self {
+            Decode(e) => write!(f, "Malformed OHTTP key configuration: {}", e),
+            DuplicateKeyId(key_id) =>
+                write!(f, "OHTTP key id {} is used by more than one configuration", key_id),
+            NoSupportedSuite => write!(
+                f,
+                "No OHTTP key configuration offers X25519 with HKDF-SHA256 and ChaCha20Poly1305 or AES-128-GCM"
+            ),
+        }
+    }
+}
+
+impl error::Error for OhttpKeysError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use OhttpKeysError::*;
+
+        match &self {
+            Decode(e) => Some(e),
+            DuplicateKeyId(_) | NoSupportedSuite => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ParseOhttpKeysError {
     DecodeBase64(bitcoin::base64::DecodeError),
-    DecodeKeyConfig(ohttp::Error),
+    DecodeKeyConfig(OhttpKeysError),
 }
 
 impl std::fmt::Display for ParseOhttpKeysError {
@@ -348,9 +736,152 @@ mod test {
         const KEM: Kem = Kem::X25519Sha256;
         const SYMMETRIC: &[SymmetricSuite] =
             &[ohttp::SymmetricSuite::new(Kdf::HkdfSha256, Aead::ChaCha20Poly1305)];
-        let keys = OhttpKeys(ohttp::KeyConfig::new(KEY_ID, KEM, Vec::from(SYMMETRIC)).unwrap());
+        let keys =
+            OhttpKeys::from(ohttp::KeyConfig::new(KEY_ID, KEM, Vec::from(SYMMETRIC)).unwrap());
         let serialized = &keys.to_string();
         let deserialized = OhttpKeys::from_str(serialized).unwrap();
         assert_eq!(keys.encode().unwrap(), deserialized.encode().unwrap());
     }
+
+    fn encoded_key_config(key_id: ohttp::KeyId, aead: ohttp::hpke::Aead) -> Vec<u8> {
+        use ohttp::hpke::{Kdf, Kem};
+
+        let suite = ohttp::SymmetricSuite::new(Kdf::HkdfSha256, aead);
+        ohttp::KeyConfig::new(key_id, Kem::X25519Sha256, vec![suite]).unwrap().encode().unwrap()
+    }
+
+    /// Prefix each configuration with its length, as in an `application/ohttp-keys` body
+    fn key_config_list(encoded_configs: &[Vec<u8>]) -> Vec<u8> {
+        encoded_configs
+            .iter()
+            .flat_map(|config| [&(config.len() as u16).to_be_bytes()[..], config].concat())
+            .collect()
+    }
+
+    #[test]
+    fn ohttp_keys_select_the_first_supported_config_from_a_list() {
+        use ohttp::hpke::Aead;
+
+        let p256 = [&[1, 0x00, 0x10][..], &[4; 65], &[0, 4, 0, 1, 0, 1]].concat();
+        let mut aes_256_gcm = encoded_key_config(2, Aead::ChaCha20Poly1305);
+        let len = aes_256_gcm.len();
+        aes_256_gcm[len - 1] = 2;
+        let aes_128_gcm = encoded_key_config(3, Aead::Aes128Gcm);
+        let chacha = encoded_key_config(4, Aead::ChaCha20Poly1305);
+
+        let list = key_config_list(&[p256, aes_256_gcm, aes_128_gcm.clone(), chacha.clone()]);
+        let mut keys = OhttpKeys::decode(&list).unwrap();
+        assert_eq!(keys.encode().unwrap(), aes_128_gcm);
+        // the rest of the supported ones are kept
+        assert_eq!(keys.1.len(), 1);
+        assert_eq!(keys.1[0].encode().unwrap(), chacha);
+        assert!(ohttp_encapsulate(&mut keys, "GET", "https://example.com/", None).is_ok());
+
+        // a lone configuration, as in `ohttp=`, still decodes as itself
+        assert_eq!(OhttpKeys::decode(&aes_128_gcm).unwrap().encode().unwrap(), aes_128_gcm);
+    }
+
+    #[test]
+    fn ohttp_keys_reject_unusable_configs() {
+        use ohttp::hpke::Aead;
+
+        let chacha = encoded_key_config(1, Aead::ChaCha20Poly1305);
+        let same_key_id = encoded_key_config(1, Aead::Aes128Gcm);
+        let list = key_config_list(&[chacha.clone(), same_key_id]);
+        assert!(matches!(OhttpKeys::decode(&list), Err(OhttpKeysError::DuplicateKeyId(1))));
+
+        let mut aes_256_gcm = chacha.clone();
+        let len = aes_256_gcm.len();
+        aes_256_gcm[len - 1] = 2;
+        assert!(matches!(OhttpKeys::decode(&aes_256_gcm), Err(OhttpKeysError::NoSupportedSuite)));
+        let list = key_config_list(&[aes_256_gcm]);
+        assert!(matches!(OhttpKeys::decode(&list), Err(OhttpKeysError::NoSupportedSuite)));
+
+        let truncated = encoded_key_config(2, Aead::ChaCha20Poly1305)[..20].to_vec();
+        assert!(matches!(OhttpKeys::decode(&truncated), Err(OhttpKeysError::Decode(_))));
+        let list = key_config_list(&[chacha, truncated]);
+        assert!(matches!(OhttpKeys::decode(&list), Err(OhttpKeysError::Decode(_))));
+    }
+
+    #[test]
+    #[cfg(all(feature = "send", feature = "receive"))]
+    fn resent_message_a_is_identical() {
+        let secp = Secp256k1::new();
+        let (s_sec, s_pub) = secp.generate_keypair(&mut OsRng);
+        let (e_sec, e_pub) = secp.generate_keypair(&mut OsRng);
+        for version in [MessageVersion::Legacy, MessageVersion::Hpke] {
+            let encrypt = || encrypt_message_a(b"original psbt".to_vec(), &e_sec, s_pub, version);
+            let message_a = encrypt().unwrap();
+            assert_eq!(message_a, encrypt().unwrap());
+
+            let (decrypted, sender, received) = decrypt_message_a(&message_a, &s_sec).unwrap();
+            assert_eq!(&decrypted[..13], b"original psbt");
+            assert_eq!(sender, e_pub);
+            assert_eq!(received, version);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "send", feature = "receive"))]
+    fn only_the_receiver_can_make_message_b() {
+        let secp = Secp256k1::new();
+        let (s_sec, s_pub) = secp.generate_keypair(&mut OsRng);
+        let (e_sec, e_pub) = secp.generate_keypair(&mut OsRng);
+        let (forger, _) = secp.generate_keypair(&mut OsRng);
+        let encrypt = |s, version| encrypt_message_b(&mut b"proposal".to_vec(), e_pub, &s, version);
+
+        let mut message_b = encrypt(s_sec, MessageVersion::Hpke).unwrap();
+        let decrypted = decrypt_message_b(&mut message_b, &e_sec, s_pub).unwrap();
+        assert_eq!(&decrypted[..8], b"proposal");
+
+        let mut forged = encrypt(forger, MessageVersion::Hpke).unwrap();
+        assert!(decrypt_message_b(&mut forged, &e_sec, s_pub).is_err());
+        let mut legacy = encrypt(s_sec, MessageVersion::Legacy).unwrap();
+        assert!(decrypt_message_b(&mut legacy, &e_sec, s_pub).is_err());
+    }
+
+    #[test]
+    fn pads_to_the_smallest_bucket_that_fits() {
+        for (i, bucket) in PADDING_BUCKETS.iter().enumerate() {
+            assert_eq!(pad(&mut vec![1; *bucket]).unwrap().len(), *bucket);
+            let next = PADDING_BUCKETS.get(i + 1);
+            match pad(&mut vec![1; bucket + 1]) {
+                Ok(padded) => assert_eq!(Some(&padded.len()), next),
+                Err(HpkeError::PayloadTooLarge) => assert_eq!(next, None),
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+        assert_eq!(pad(&mut vec![]).unwrap().len(), PADDING_BUCKETS[0]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "send", feature = "receive"))]
+    fn the_largest_messages_fit_in_a_directory_mailbox() {
+        use payjoin_directory::DEFAULT_MAX_PAYLOAD_SIZE;
+
+        let secp = Secp256k1::new();
+        let (s_sec, s_pub) = secp.generate_keypair(&mut OsRng);
+        let (e_sec, e_pub) = secp.generate_keypair(&mut OsRng);
+        let largest = vec![1; MAX_PADDED_MESSAGE_BYTES];
+        for version in [MessageVersion::Legacy, MessageVersion::Hpke] {
+            let message_a = encrypt_message_a(largest.clone(), &e_sec, s_pub, version).unwrap();
+            assert!(message_a.len() <= DEFAULT_MAX_PAYLOAD_SIZE);
+            let (decrypted, _, _) = decrypt_message_a(&message_a, &s_sec).unwrap();
+            assert_eq!(decrypted, largest);
+
+            let message_b =
+                encrypt_message_b(&mut largest.clone(), e_pub, &s_sec, version).unwrap();
+            assert!(message_b.len() <= DEFAULT_MAX_PAYLOAD_SIZE);
+        }
+        let too_large = vec![1; MAX_PADDED_MESSAGE_BYTES + 1];
+        let result = encrypt_message_a(too_large, &e_sec, s_pub, MessageVersion::Hpke);
+        assert!(matches!(result, Err(HpkeError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn negotiates_the_newest_common_version() {
+        assert_eq!(MessageVersion::negotiate(0), MessageVersion::Legacy);
+        assert_eq!(MessageVersion::negotiate(1), MessageVersion::Hpke);
+        assert_eq!(MessageVersion::negotiate(u8::MAX), MessageVersion::Hpke);
+    }
 }
@@ -283,6 +283,7 @@ mod integration {
     #[cfg(feature = "danger-local-https")]
     #[cfg(feature = "v2")]
     mod v2 {
+        use std::net::{Ipv6Addr, SocketAddr};
         use std::sync::Arc;
         use std::time::Duration;
 
@@ -293,8 +294,6 @@ mod integration {
         };
         use payjoin::{OhttpKeys, PjUri, UriExt};
         use reqwest::{Client, ClientBuilder, Error, Response};
-        use testcontainers_modules::redis::Redis;
-        use testcontainers_modules::testcontainers::clients::Cli;
 
         use super::*;
 
@@ -352,9 +351,8 @@ mod integration {
                 Url::parse(&format!("http://localhost:{}", ohttp_relay_port)).unwrap();
             let directory_port = find_free_port();
             let directory = Url::parse(&format!("https://localhost:{}", directory_port)).unwrap();
-            let gateway_origin = http::Uri::from_str(directory.as_str()).unwrap();
             tokio::select!(
-            _ = ohttp_relay::listen_tcp(ohttp_relay_port, gateway_origin) => assert!(false, "Ohttp relay is long running"),
+            _ = init_relay(ohttp_relay_port, directory.clone(), cert.clone()) => assert!(false, "Ohttp relay is long running"),
             _ = init_directory(directory_port, (cert.clone(), key)) => assert!(false, "Directory server is long running"),
             res = do_expiration_tests(ohttp_relay, directory, cert) => assert!(res.is_ok(), "v2 send receive failed: {:#?}", res)
             );
@@ -413,6 +411,54 @@ mod integration {
             }
         }
 
+        #[tokio::test]
+        async fn test_session_cancellation() {
+            init_tracing();
+            let (cert, key) = local_cert_key();
+            let ohttp_relay_port = find_free_port();
+            let ohttp_relay =
+                Url::parse(&format!("http://localhost:{}", ohttp_relay_port)).unwrap();
+            let directory_port = find_free_port();
+            let directory = Url::parse(&format!("https://localhost:{}", directory_port)).unwrap();
+            tokio::select!(
+            _ = init_relay(ohttp_relay_port, directory.clone(), cert.clone()) => assert!(false, "Ohttp relay is long running"),
+            _ = init_directory(directory_port, (cert.clone(), key)) => assert!(false, "Directory server is long running"),
+            res = do_cancellation_tests(ohttp_relay, directory, cert) => assert!(res.is_ok(), "v2 cancellation failed: {:#?}", res)
+            );
+
+            async fn do_cancellation_tests(
+                ohttp_relay: Url,
+                directory: Url,
+                cert_der: Vec<u8>,
+            ) -> Result<(), BoxError> {
+                let agent = Arc::new(http_agent(cert_der.clone())?);
+                wait_for_service_ready(ohttp_relay.clone(), agent.clone()).await.unwrap();
+                wait_for_service_ready(directory.clone(), agent.clone()).await.unwrap();
+                let ohttp_keys =
+                    payjoin::io::fetch_ohttp_keys(ohttp_relay, directory.clone(), cert_der.clone())
+                        .await?;
+                let mock_address = Address::from_str("tb1q6d3a2w975yny0asuvd9a67ner4nks58ff0q8g4")
+                    .unwrap()
+                    .assume_checked();
+                let mut session =
+                    initialize_session(mock_address, directory, ohttp_keys, cert_der, None).await?;
+
+                // The receiver deletes its own mailbox
+                let (req, ctx) = session.extract_cancel_req()?;
+                let response = agent.post(req.url).body(req.body).send().await?;
+                assert!(response.status().is_success());
+                session.process_cancel_res(response.bytes().await?.to_vec().as_slice(), ctx)?;
+
+                // Nothing is left to poll afterwards
+                let (req, ctx) = session.extract_req()?;
+                let response = agent.post(req.url).body(req.body).send().await?;
+                let proposal =
+                    session.process_res(response.bytes().await?.to_vec().as_slice(), ctx)?;
+                assert!(proposal.is_none());
+                Ok(())
+            }
+        }
+
         #[tokio::test]
         async fn v2_to_v2() {
             init_tracing();
@@ -422,9 +468,8 @@ mod integration {
                 Url::parse(&format!("http://localhost:{}", ohttp_relay_port)).unwrap();
             let directory_port = find_free_port();
             let directory = Url::parse(&format!("https://localhost:{}", directory_port)).unwrap();
-            let gateway_origin = http::Uri::from_str(directory.as_str()).unwrap();
             tokio::select!(
-            _ = ohttp_relay::listen_tcp(ohttp_relay_port, gateway_origin) => assert!(false, "Ohttp relay is long running"),
+            _ = init_relay(ohttp_relay_port, directory.clone(), cert.clone()) => assert!(false, "Ohttp relay is long running"),
             _ = init_directory(directory_port, (cert.clone(), key)) => assert!(false, "Directory server is long running"),
             res = do_v2_send_receive(ohttp_relay, directory, cert) => assert!(res.is_ok(), "v2 send receive failed: {:#?}", res)
             );
@@ -535,9 +580,8 @@ mod integration {
                 Url::parse(&format!("http://localhost:{}", ohttp_relay_port)).unwrap();
             let directory_port = find_free_port();
             let directory = Url::parse(&format!("https://localhost:{}", directory_port)).unwrap();
-            let gateway_origin = http::Uri::from_str(directory.as_str()).unwrap();
             tokio::select!(
-            _ = ohttp_relay::listen_tcp(ohttp_relay_port, gateway_origin) => assert!(false, "Ohttp relay is long running"),
+            _ = init_relay(ohttp_relay_port, directory.clone(), cert.clone()) => assert!(false, "Ohttp relay is long running"),
             _ = init_directory(directory_port, (cert.clone(), key)) => assert!(false, "Directory server is long running"),
             res = do_v1_to_v2(ohttp_relay, directory, cert) => assert!(res.is_ok()),
             );
@@ -658,12 +702,33 @@ mod integration {
             port: u16,
             local_cert_key: (Vec<u8>, Vec<u8>),
         ) -> Result<(), BoxError> {
-            let docker: Cli = Cli::default();
-            let timeout = Duration::from_secs(2);
-            let db = docker.run(Redis::default());
-            let db_host = format!("127.0.0.1:{}", db.get_host_port_ipv4(6379));
-            println!("Database running on {}", db.get_host_port_ipv4(6379));
-            payjoin_directory::listen_tcp_with_tls(port, db_host, timeout, local_cert_key).await
+            let (cert, key) = local_cert_key;
+            let config = payjoin_directory::Config {
+                bind_addr: SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+                timeout: Duration::from_secs(2),
+                db_backend: payjoin_directory::DbBackend::Memory,
+                ..Default::default()
+            };
+            payjoin_directory::listen_tcp_with_tls(
+                config,
+                payjoin_directory::GatewayKeys::ephemeral()?,
+                (vec![cert], key),
+                payjoin_directory::ShutdownHandle::new(),
+            )
+            .await
+        }
+
+        async fn init_relay(
+            port: u16,
+            gateway: Url,
+            gateway_cert: Vec<u8>,
+        ) -> Result<(), BoxError> {
+            let config = payjoin_directory::RelayConfig {
+                bind_addr: SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+                gateway_origin: gateway.as_str().parse()?,
+                gateway_roots: vec![gateway_cert],
+            };
+            payjoin_directory::listen_relay(config, payjoin_directory::ShutdownHandle::new()).await
         }
 
         // generates or gets a DER encoded localhost cert and key.